@@ -0,0 +1,121 @@
+#[cfg(feature = "async")]
+use std::future::Future;
+
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use thiserror::Error;
+
+use crate::cookie::CookieError;
+use crate::oauth::consts::OauthScope;
+use crate::oauth::error::OauthError;
+use crate::oauth::request::HatenaRequest;
+use crate::wsse::WsseError;
+
+#[derive(Debug, Error)]
+pub enum AuthError {
+  #[error(transparent)]
+  Oauth(#[from] OauthError),
+
+  #[error(transparent)]
+  Wsse(#[from] WsseError),
+
+  #[error(transparent)]
+  Cookie(#[from] CookieError),
+}
+
+/// Attaches credentials to requests of API clients (eg. `Fotolife`)
+///
+/// Implemented by `HatenaOauth`, `WsseCredentials`, `HatenaCookieSession` and `NoAuth`.
+pub trait Authenticator {
+  /// Attach credentials to `request`, which is called again before a retry
+  fn authenticate(&mut self, request: &mut HatenaRequest) -> Result<(), AuthError>;
+
+  /// Hatena ID of the authenticated user, if known
+  fn username(&mut self) -> Result<Option<String>, AuthError> {
+    Ok(None)
+  }
+
+  /// Send requests of the authenticator itself (eg. validating a session) with `client` of the API client
+  fn use_http_client(&mut self, _client: &reqwest::blocking::Client) {}
+
+  /// Check that the credentials have any of `required` scopes before calling an API
+  fn require_scopes(&mut self, _required: &[OauthScope]) -> Result<(), AuthError> {
+    Ok(())
+  }
+
+  /// Inspect a failed response, and return true if the request should be retried once.
+  ///
+  /// Returning an error aborts the request with it.
+  fn retry(
+    &mut self,
+    _status: StatusCode,
+    _headers: &HeaderMap,
+    _body: &[u8],
+  ) -> Result<bool, AuthError> {
+    Ok(false)
+  }
+}
+
+/// Asynchronous version of `Authenticator`, used by asynchronous API clients (eg. `AsyncFotolife`)
+#[cfg(feature = "async")]
+pub trait AsyncAuthenticator: Send {
+  /// See `Authenticator::authenticate`
+  fn authenticate(
+    &mut self,
+    request: &mut HatenaRequest,
+  ) -> impl Future<Output = Result<(), AuthError>> + Send;
+
+  /// See `Authenticator::username`
+  fn username(&mut self) -> impl Future<Output = Result<Option<String>, AuthError>> + Send {
+    async { Ok(None) }
+  }
+
+  /// See `Authenticator::use_http_client`
+  fn use_http_client(&mut self, _client: &reqwest::Client) {}
+
+  /// See `Authenticator::require_scopes`
+  fn require_scopes(
+    &mut self,
+    _required: &[OauthScope],
+  ) -> impl Future<Output = Result<(), AuthError>> + Send {
+    async { Ok(()) }
+  }
+
+  /// See `Authenticator::retry`
+  fn retry(
+    &mut self,
+    _status: StatusCode,
+    _headers: &HeaderMap,
+    _body: &[u8],
+  ) -> impl Future<Output = Result<bool, AuthError>> + Send {
+    async { Ok(false) }
+  }
+}
+
+/// Authenticator which attaches nothing, used for public pages and tests
+#[derive(Debug, Clone, Default)]
+pub struct NoAuth {
+  /// Hatena ID returned by `username`
+  pub username: Option<String>,
+}
+
+impl Authenticator for NoAuth {
+  fn authenticate(&mut self, _request: &mut HatenaRequest) -> Result<(), AuthError> {
+    Ok(())
+  }
+
+  fn username(&mut self) -> Result<Option<String>, AuthError> {
+    Ok(self.username.clone())
+  }
+}
+
+#[cfg(feature = "async")]
+impl AsyncAuthenticator for NoAuth {
+  async fn authenticate(&mut self, _request: &mut HatenaRequest) -> Result<(), AuthError> {
+    Ok(())
+  }
+
+  async fn username(&mut self) -> Result<Option<String>, AuthError> {
+    Ok(self.username.clone())
+  }
+}
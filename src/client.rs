@@ -0,0 +1,154 @@
+use std::time::Duration;
+
+use reqwest::{Certificate, Proxy};
+
+/// Default value of `User-Agent`
+pub const DEFAULT_USER_AGENT: &str = concat!("hatena-rs/", env!("CARGO_PKG_VERSION"));
+
+/// Options of the HTTP client, which an API client builds once and reuses for all requests
+///
+/// Pass it to `with_http_config` of `HatenaOauth` and `Fotolife`.
+/// To configure more, build your own `reqwest` client and pass it to `with_http_client` instead.
+#[derive(Debug, Clone)]
+pub struct HttpClientConfig {
+  /// Value of `User-Agent`
+  pub user_agent: String,
+  /// URL of the proxy for all requests. If `None`, proxies in environment variables (eg. `HTTPS_PROXY`) are used.
+  pub proxy: Option<String>,
+  /// Ignore proxies in environment variables
+  pub no_proxy: bool,
+  /// Timeout of each request, which the timeout of a single request overrides
+  pub timeout: Option<Duration>,
+  /// Timeout to connect to a server
+  pub connect_timeout: Option<Duration>,
+  /// PEM-encoded certificates trusted in addition to the system ones
+  pub root_certificates: Vec<Vec<u8>>,
+}
+
+impl Default for HttpClientConfig {
+  fn default() -> Self {
+    Self {
+      user_agent: DEFAULT_USER_AGENT.into(),
+      proxy: None,
+      no_proxy: false,
+      timeout: None,
+      connect_timeout: None,
+      root_certificates: vec![],
+    }
+  }
+}
+
+/// Apply options of `config` to a blocking or async `ClientBuilder`, which have the same methods
+macro_rules! configure_builder {
+  ($builder:expr, $config:expr) => {{
+    let config = $config;
+    let mut builder = $builder.user_agent(config.user_agent.as_str());
+    if let Some(proxy) = &config.proxy {
+      builder = builder.proxy(Proxy::all(proxy.as_str())?);
+    } else if config.no_proxy {
+      builder = builder.no_proxy();
+    }
+    if let Some(timeout) = config.timeout {
+      builder = builder.timeout(timeout);
+    }
+    if let Some(timeout) = config.connect_timeout {
+      builder = builder.connect_timeout(timeout);
+    }
+    for pem in &config.root_certificates {
+      builder = builder.add_root_certificate(Certificate::from_pem(pem)?);
+    }
+    builder.build()
+  }};
+}
+
+impl HttpClientConfig {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn with_user_agent(mut self, user_agent: &str) -> Self {
+    self.user_agent = user_agent.into();
+    self
+  }
+
+  /// Send all requests via the proxy at `url` (eg. `http://proxy.example.com:8080`)
+  pub fn with_proxy(mut self, url: &str) -> Self {
+    self.proxy = Some(url.into());
+    self
+  }
+
+  /// Ignore proxies in environment variables
+  pub fn without_proxy(mut self) -> Self {
+    self.no_proxy = true;
+    self
+  }
+
+  pub fn with_timeout(mut self, timeout: Duration) -> Self {
+    self.timeout = Some(timeout);
+    self
+  }
+
+  pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+    self.connect_timeout = Some(timeout);
+    self
+  }
+
+  /// Trust a PEM-encoded certificate (eg. of a corporate proxy or a local server)
+  pub fn with_root_certificate(mut self, pem: &[u8]) -> Self {
+    self.root_certificates.push(pem.to_vec());
+    self
+  }
+
+  /// Build a blocking client
+  ///
+  /// It fails if the proxy URL or a certificate is invalid.
+  pub fn build_blocking(&self) -> Result<reqwest::blocking::Client, reqwest::Error> {
+    configure_builder!(reqwest::blocking::Client::builder(), self)
+  }
+
+  /// Build an async client
+  ///
+  /// It fails if the proxy URL or a certificate is invalid.
+  #[cfg(feature = "async")]
+  pub fn build_async(&self) -> Result<reqwest::Client, reqwest::Error> {
+    configure_builder!(reqwest::Client::builder(), self)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::*;
+
+  #[test]
+  fn test_build_blocking() {
+    let (proxy, server) = spawn_stub_server(vec![response("200 OK", &[], "proxied")]);
+    let client = HttpClientConfig::new()
+      .with_user_agent("hatena-rs-test")
+      .with_proxy(&proxy)
+      .with_timeout(Duration::from_secs(10))
+      .build_blocking()
+      .unwrap();
+
+    let res = client
+      .get("http://f.hatena.invalid/atom/feed")
+      .send()
+      .unwrap();
+    assert_eq!(res.text().unwrap(), "proxied");
+    let requests = server.join().unwrap();
+    assert_eq!(
+      requests[0].request_line,
+      "GET http://f.hatena.invalid/atom/feed HTTP/1.1"
+    );
+    assert_eq!(requests[0].header("user-agent"), Some("hatena-rs-test"));
+
+    assert!(HttpClientConfig::new()
+      .with_proxy("not a url")
+      .build_blocking()
+      .is_err());
+    assert!(HttpClientConfig::new()
+      .with_root_certificate(b"not a certificate")
+      .build_blocking()
+      .is_err());
+  }
+}
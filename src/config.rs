@@ -0,0 +1,374 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::endpoints::HatenaEndpoints;
+use crate::oauth::callback::GrantPermissionCallback;
+use crate::oauth::consts::OauthScope;
+use crate::oauth::error::OauthError;
+use crate::oauth::store::*;
+use crate::oauth::token::AccessTokenResponse;
+use crate::oauth::{HatenaConsumerInfo, HatenaOauth};
+use crate::secret::SecretString;
+
+#[cfg(feature = "async")]
+use crate::oauth::AsyncHatenaOauth;
+
+pub const ENV_HATENA_PROFILE: &str = "HATENA_PROFILE";
+
+/// Name of the configuration file under `default_config_dir()`
+pub const CONFIG_FILE_NAME: &str = "config.toml";
+
+/// Name of the profile used if neither specified nor `default_profile` is set
+pub const DEFAULT_PROFILE_NAME: &str = "default";
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+  #[error("neither XDG_CONFIG_HOME nor HOME is set")]
+  ConfigDirUnknown,
+
+  #[error("failed to read config file: {path:?}")]
+  ReadFailure {
+    path: PathBuf,
+    #[source]
+    source: io::Error,
+  },
+
+  #[error("failed to parse config file")]
+  ParseFailure(#[from] toml::de::Error),
+
+  #[error("profile not found: {name}")]
+  ProfileNotFound { name: String },
+
+  #[error("both token and token_file are set in the profile")]
+  TokenConflict,
+
+  #[error("failed to create OAuth client")]
+  OauthFailure(#[from] OauthError),
+}
+
+/// Configuration file, which is `~/.config/hatena-rs/config.toml` by default
+///
+/// ```toml
+/// default_profile = "personal"
+///
+/// [profiles.personal]
+/// consumer_key = "..."
+/// consumer_secret = "..."
+/// scopes = ["read_public", "write_public"]
+/// blog = "smallkirby.hatenablog.com"
+/// token_file = "/home/smallkirby/.config/hatena-rs/personal_token"
+///
+/// [profiles.team]
+/// consumer_key = "..."
+/// consumer_secret = "..."
+/// token = { oauth_token = "...", oauth_token_secret = "...", url_name = "team" }
+/// endpoints = { base_url = "http://localhost:49494" }
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct HatenaConfig {
+  /// Profile used if `HATENA_PROFILE` is not set
+  pub default_profile: Option<String>,
+  /// Profiles keyed by their names
+  #[serde(default)]
+  pub profiles: HashMap<String, HatenaProfile>,
+}
+
+/// Named set of credentials and settings
+#[derive(Debug, Clone, Deserialize)]
+pub struct HatenaProfile {
+  /// Consumer key
+  pub consumer_key: String,
+  /// Consumer secret
+  pub consumer_secret: SecretString,
+  /// Scopes requested for the access token
+  #[serde(default)]
+  pub scopes: Vec<OauthScope>,
+  /// Default blog ID (eg. `smallkirby.hatenablog.com`)
+  pub blog: Option<String>,
+  /// Stored access token. It cannot be set with `token_file`.
+  pub token: Option<ProfileToken>,
+  /// File to load and save the access token, in the format of `FileTokenStore`. It cannot be set with `token`.
+  pub token_file: Option<PathBuf>,
+  /// Overrides of endpoints
+  #[serde(default)]
+  pub endpoints: EndpointOverrides,
+}
+
+/// Access token written in a profile
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProfileToken {
+  pub oauth_token: String,
+  pub oauth_token_secret: SecretString,
+  pub url_name: String,
+  /// Same as `url_name` if omitted
+  pub display_name: Option<String>,
+}
+
+/// Base URLs replacing the default endpoints, applied in the order of fields
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct EndpointOverrides {
+  /// See `HatenaEndpoints::from_base_url`
+  pub base_url: Option<String>,
+  /// See `HatenaEndpoints::with_oauth_base`
+  pub oauth_base: Option<String>,
+  /// See `HatenaEndpoints::with_fotolife_base`
+  pub fotolife_base: Option<String>,
+}
+
+impl HatenaConfig {
+  /// Load `config.toml` under `default_config_dir()`
+  ///
+  /// It returns an empty config if the file does not exist.
+  pub fn load() -> Result<Self, ConfigError> {
+    let dir = default_config_dir().ok_or(ConfigError::ConfigDirUnknown)?;
+    Self::from_file(&dir.join(CONFIG_FILE_NAME))
+  }
+
+  /// Load a config file at `path`
+  ///
+  /// It returns an empty config if the file does not exist.
+  pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+    match fs::read_to_string(path) {
+      Ok(text) => Self::parse(&text),
+      Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+      Err(source) => Err(ConfigError::ReadFailure {
+        path: path.to_path_buf(),
+        source,
+      }),
+    }
+  }
+
+  /// Parse a config written in TOML
+  pub fn parse(text: &str) -> Result<Self, ConfigError> {
+    Ok(toml::from_str(text)?)
+  }
+
+  /// Get a profile
+  ///
+  /// # Arguments
+  ///
+  /// * `name` - Name of the profile. If `None`, it uses `HATENA_PROFILE`, `default_profile` or `default` in this order.
+  pub fn profile(&self, name: Option<&str>) -> Result<&HatenaProfile, ConfigError> {
+    let env_value = env::var(ENV_HATENA_PROFILE).ok();
+    let name = self.resolve_profile(name, env_value.as_deref()).to_string();
+
+    self
+      .profiles
+      .get(&name)
+      .ok_or(ConfigError::ProfileNotFound { name })
+  }
+
+  /// Name of the profile which `profile` uses
+  ///
+  /// # Arguments
+  ///
+  /// * `explicit` - Name given by a caller, which is used if `Some`
+  /// * `env_value` - Value of `HATENA_PROFILE`. It is ignored if empty.
+  pub fn resolve_profile<'a>(
+    &'a self,
+    explicit: Option<&'a str>,
+    env_value: Option<&'a str>,
+  ) -> &'a str {
+    explicit
+      .or(env_value.filter(|name| !name.is_empty()))
+      .or(self.default_profile.as_deref())
+      .unwrap_or(DEFAULT_PROFILE_NAME)
+  }
+}
+
+impl HatenaProfile {
+  pub fn consumer_info(&self) -> Result<HatenaConsumerInfo, OauthError> {
+    HatenaConsumerInfo::new(&self.consumer_key, self.consumer_secret.expose_secret())
+  }
+
+  /// Access token written in the profile, if any
+  pub fn access_token(&self) -> Option<AccessTokenResponse> {
+    self.token.as_ref().map(|token| AccessTokenResponse {
+      oauth_token: token.oauth_token.clone(),
+      oauth_token_secret: token.oauth_token_secret.clone(),
+      url_name: token.url_name.clone(),
+      display_name: token
+        .display_name
+        .clone()
+        .unwrap_or_else(|| token.url_name.clone()),
+      scopes: None,
+      extra: Default::default(),
+    })
+  }
+
+  /// Default endpoints with the overrides applied
+  pub fn endpoints(&self) -> HatenaEndpoints {
+    let overrides = &self.endpoints;
+    let mut endpoints = match &overrides.base_url {
+      Some(base) => HatenaEndpoints::from_base_url(base),
+      None => HatenaEndpoints::default(),
+    };
+    if let Some(base) = &overrides.oauth_base {
+      endpoints = endpoints.with_oauth_base(base);
+    }
+    if let Some(base) = &overrides.fotolife_base {
+      endpoints = endpoints.with_fotolife_base(base);
+    }
+
+    endpoints
+  }
+
+  /// Token store of the profile
+  ///
+  /// It is `FileTokenStore` at `token_file` if set, otherwise `MemoryTokenStore` holding `token`.
+  /// It fails with `TokenConflict` if both are set.
+  pub fn token_store(&self) -> Result<Box<dyn TokenStore>, ConfigError> {
+    Ok(match (&self.token_file, self.access_token()) {
+      (Some(_), Some(_)) => return Err(ConfigError::TokenConflict),
+      (Some(path), None) => Box::new(FileTokenStore::new(path)),
+      (None, Some(token)) => Box::new(MemoryTokenStore::with_token(token)),
+      (None, None) => Box::new(MemoryTokenStore::new()),
+    })
+  }
+
+  /// Create an OAuth client with the credentials, token and endpoints of the profile
+  ///
+  /// The token of the profile takes precedence over `HATENA_OAUTH_ACCESS_TOKEN` and its family,
+  /// which are used only if the token store of the profile is empty.
+  ///
+  /// # Arguments
+  ///
+  /// * `grant_permission_callback` - See `HatenaOauth::new`
+  pub fn oauth(
+    &self,
+    grant_permission_callback: Option<GrantPermissionCallback>,
+  ) -> Result<HatenaOauth, ConfigError> {
+    let store = self.token_store()?;
+    let mut oauth = HatenaOauth::new(
+      self.scopes.clone(),
+      grant_permission_callback,
+      self.consumer_info()?,
+    )?
+    .with_endpoints(self.endpoints());
+    let env_token = oauth.core.access_token.take();
+
+    let mut oauth = oauth.with_token_store(store)?;
+    oauth.core.access_token = oauth.core.access_token.take().or(env_token);
+    Ok(oauth)
+  }
+
+  /// Asynchronous version of `oauth`
+  #[cfg(feature = "async")]
+  pub async fn async_oauth(
+    &self,
+    grant_permission_callback: Option<GrantPermissionCallback>,
+  ) -> Result<AsyncHatenaOauth, ConfigError> {
+    let store = self.token_store()?;
+    let mut oauth = AsyncHatenaOauth::new(
+      self.scopes.clone(),
+      grant_permission_callback,
+      self.consumer_info()?,
+    )?
+    .with_endpoints(self.endpoints());
+    let env_token = oauth.core.access_token.take();
+
+    let mut oauth = oauth.with_token_store(store).await?;
+    oauth.core.access_token = oauth.core.access_token.take().or(env_token);
+    Ok(oauth)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const CONFIG: &str = r#"
+default_profile = "personal"
+
+[profiles.personal]
+consumer_key = "personal_key"
+consumer_secret = "personal_secret"
+scopes = ["read_public", "write_public"]
+blog = "smallkirby.hatenablog.com"
+
+[profiles.team]
+consumer_key = "team_key"
+consumer_secret = "team_secret"
+token = { oauth_token = "token", oauth_token_secret = "token_secret", url_name = "team" }
+endpoints = { base_url = "http://localhost:49494", oauth_base = "http://localhost:50505" }
+"#;
+
+  #[test]
+  fn test_parse_config() {
+    let config = HatenaConfig::parse(CONFIG).unwrap();
+
+    let personal = config.profile(Some("personal")).unwrap();
+    assert_eq!(personal.consumer_key, "personal_key");
+    assert_eq!(
+      personal.scopes,
+      vec![OauthScope::ReadPublic, OauthScope::WritePublic]
+    );
+    assert_eq!(personal.blog.as_deref(), Some("smallkirby.hatenablog.com"));
+    assert!(personal.access_token().is_none());
+    assert_eq!(personal.endpoints(), HatenaEndpoints::default());
+
+    let team = config.profile(Some("team")).unwrap();
+    let token = team.access_token().unwrap();
+    assert_eq!(token.oauth_token_secret.expose_secret(), "token_secret");
+    assert_eq!(token.display_name, "team");
+    let endpoints = team.endpoints();
+    assert_eq!(endpoints.fotolife_post, "http://localhost:49494/atom/post");
+    assert_eq!(
+      endpoints.oauth_access_token,
+      "http://localhost:50505/oauth/token"
+    );
+
+    assert!(matches!(
+      config.profile(Some("unknown")),
+      Err(ConfigError::ProfileNotFound { name }) if name == "unknown"
+    ));
+    assert!(HatenaConfig::parse("[profiles.broken]\nconsumer_key = 1").is_err());
+  }
+
+  #[test]
+  fn test_profile_resolution() {
+    let config = HatenaConfig::parse(CONFIG).unwrap();
+    assert_eq!(config.resolve_profile(None, None), "personal");
+    assert_eq!(config.resolve_profile(None, Some("team")), "team");
+    assert_eq!(
+      config.resolve_profile(Some("personal"), Some("team")),
+      "personal"
+    );
+    assert_eq!(config.resolve_profile(None, Some("")), "personal");
+
+    let config = HatenaConfig {
+      default_profile: None,
+      ..config
+    };
+    assert_eq!(config.resolve_profile(None, Some("")), "default");
+    assert_eq!(
+      config.profile(Some("team")).unwrap().consumer_key,
+      "team_key"
+    );
+  }
+
+  #[test]
+  fn test_profile_token() {
+    let config = HatenaConfig::parse(CONFIG).unwrap();
+    let oauth = config.profile(Some("team")).unwrap().oauth(None).unwrap();
+    let token = oauth.core.access_token.as_ref().unwrap();
+    assert_eq!(token.oauth_token, "token");
+    assert_eq!(token.url_name, "team");
+
+    let conflict = HatenaConfig::parse(&CONFIG.replace(
+      "[profiles.team]",
+      "[profiles.team]\ntoken_file = \"/nonexistent/token\"",
+    ))
+    .unwrap();
+    assert!(matches!(
+      conflict.profile(Some("team")).unwrap().oauth(None),
+      Err(ConfigError::TokenConflict)
+    ));
+  }
+}
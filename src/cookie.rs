@@ -0,0 +1,444 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+use reqwest::header::{COOKIE, LOCATION};
+use reqwest::redirect::Policy;
+use scraper::{Html, Selector};
+use thiserror::Error;
+
+use crate::auth::*;
+use crate::client::DEFAULT_USER_AGENT;
+use crate::oauth::request::HatenaRequest;
+use crate::secret::SecretString;
+
+pub const ENV_HATENA_COOKIE: &str = "HATENA_COOKIE";
+pub const ENV_HATENA_COOKIE_FILE: &str = "HATENA_COOKIE_FILE";
+
+/// Page which shows the logged-in user, used to validate a session
+pub const HATENA_URL_SESSION_CHECK: &str = "https://www.hatena.ne.jp/my/";
+
+/// Domains whose cookies are sent to Hatena
+const HATENA_DOMAINS: [&str; 2] = ["hatena.ne.jp", "hatena.com"];
+
+#[derive(Debug, Error)]
+pub enum CookieError {
+  #[error("neither HATENA_COOKIE nor HATENA_COOKIE_FILE is set")]
+  InsufficientSecret,
+
+  #[error("failed to read cookie file: {path:?}")]
+  CookieFile {
+    path: PathBuf,
+    #[source]
+    source: std::io::Error,
+  },
+
+  #[error("no cookie of Hatena is found")]
+  NoHatenaCookie,
+
+  #[error("cookie session is expired (redirected to {location})")]
+  Expired { location: String },
+
+  #[error("no user is logged in with the cookie")]
+  NotLoggedIn,
+
+  #[error("request failed")]
+  HttpFailure(#[from] reqwest::Error),
+}
+
+/// Logged-in Cookie of Hatena, used by helpers which scrape Web pages instead of calling APIs
+#[derive(Debug, Clone)]
+pub struct HatenaCookieSession {
+  /// Pairs of cookie name and value
+  cookies: Vec<(String, SecretString)>,
+  /// Hatena ID of the logged-in user, if known
+  username: Option<String>,
+  /// Page which shows the logged-in user
+  session_check: String,
+  /// HTTP client of `get`, which is built for each request if `None`
+  client: Option<reqwest::blocking::Client>,
+  /// HTTP client of `get_async`, which is built for each request if `None`
+  #[cfg(feature = "async")]
+  async_client: Option<reqwest::Client>,
+}
+
+impl HatenaCookieSession {
+  /// # Arguments
+  ///
+  /// * `cookie` - Value of `Cookie` header (eg. `rk=...; b=...`)
+  pub fn new(cookie: &str) -> Result<Self, CookieError> {
+    let cookies = cookie
+      .split(';')
+      .filter_map(|pair| pair.split_once('='))
+      .map(|(name, value)| (name.trim().to_string(), value.trim().into()))
+      .filter(|(name, _)| !name.is_empty())
+      .collect();
+
+    Self::from_cookies(cookies)
+  }
+
+  /// Session of a single `rk` cookie
+  pub fn from_rk(rk: &str) -> Self {
+    Self::from_cookies(vec![("rk".into(), rk.into())]).unwrap()
+  }
+
+  /// Load a session from `HATENA_COOKIE` (value of `Cookie` header), or `HATENA_COOKIE_FILE` (path of cookies.txt)
+  pub fn from_env() -> Result<Self, CookieError> {
+    if let Ok(cookie) = env::var(ENV_HATENA_COOKIE) {
+      Self::new(&cookie)
+    } else if let Ok(path) = env::var(ENV_HATENA_COOKIE_FILE) {
+      Self::from_cookie_file(Path::new(&path))
+    } else {
+      Err(CookieError::InsufficientSecret)
+    }
+  }
+
+  /// Load cookies of Hatena from a Netscape cookies.txt file
+  ///
+  /// Cookies of other domains and expired ones are ignored.
+  ///
+  /// # Arguments
+  ///
+  /// * `path` - Path to cookies.txt exported from a browser
+  pub fn from_cookie_file(path: &Path) -> Result<Self, CookieError> {
+    let content = fs::read_to_string(path).map_err(|source| CookieError::CookieFile {
+      path: path.to_path_buf(),
+      source,
+    })?;
+
+    Self::from_cookies(parse_cookie_file(&content, Utc::now().timestamp()))
+  }
+
+  fn from_cookies(cookies: Vec<(String, SecretString)>) -> Result<Self, CookieError> {
+    if cookies.is_empty() {
+      return Err(CookieError::NoHatenaCookie);
+    }
+
+    Ok(Self {
+      cookies,
+      username: None,
+      session_check: HATENA_URL_SESSION_CHECK.into(),
+      client: None,
+      #[cfg(feature = "async")]
+      async_client: None,
+    })
+  }
+
+  /// Set Hatena ID of the logged-in user to skip validation
+  pub fn with_username(mut self, username: &str) -> Self {
+    self.username = Some(username.into());
+    self
+  }
+
+  /// Use `url` instead of `HATENA_URL_SESSION_CHECK` to validate the session
+  pub fn with_session_check(mut self, url: &str) -> Self {
+    self.session_check = url.into();
+    self
+  }
+
+  /// Send requests of `get` and `validate` with `client` (eg. of `Fotolife`, which sets it automatically)
+  ///
+  /// A redirect to the login page is reported as `Expired`, whether `client` follows redirects or not.
+  pub fn with_http_client(mut self, client: reqwest::blocking::Client) -> Self {
+    self.client = Some(client);
+    self
+  }
+
+  /// Send requests of `get_async` and `validate_async` with `client`
+  #[cfg(feature = "async")]
+  pub fn with_async_http_client(mut self, client: reqwest::Client) -> Self {
+    self.async_client = Some(client);
+    self
+  }
+
+  /// Value of `Cookie` header
+  pub fn header_value(&self) -> String {
+    self
+      .cookies
+      .iter()
+      .map(|(name, value)| format!("{}={}", name, value.expose_secret()))
+      .collect::<Vec<_>>()
+      .join("; ")
+  }
+
+  /// Hatena ID of the logged-in user, if already known
+  pub fn username(&self) -> Option<&str> {
+    self.username.as_deref()
+  }
+
+  /// Check that the session is logged in, and return Hatena ID of the user
+  pub fn validate(&mut self) -> Result<String, CookieError> {
+    let html = self.get(&self.session_check.clone())?.text()?;
+    let username = parse_logged_in_user(&html).ok_or(CookieError::NotLoggedIn)?;
+    self.username = Some(username.clone());
+    Ok(username)
+  }
+
+  /// Asynchronous version of `validate`
+  #[cfg(feature = "async")]
+  pub async fn validate_async(&mut self) -> Result<String, CookieError> {
+    let html = self
+      .get_async(&self.session_check.clone())
+      .await?
+      .text()
+      .await?;
+    let username = parse_logged_in_user(&html).ok_or(CookieError::NotLoggedIn)?;
+    self.username = Some(username.clone());
+    Ok(username)
+  }
+
+  /// Send GET request with the cookie by the client of `with_http_client`
+  ///
+  /// A redirect to the login page is reported as `Expired`.
+  /// If no client is given, a client which does not follow redirects is built.
+  ///
+  /// # Arguments
+  ///
+  /// * `url` - URL of the page to get
+  pub fn get(&self, url: &str) -> Result<reqwest::blocking::Response, CookieError> {
+    match &self.client {
+      Some(client) => self.get_with(client, url),
+      None => {
+        let client = reqwest::blocking::Client::builder()
+          .user_agent(DEFAULT_USER_AGENT)
+          .redirect(Policy::none())
+          .build()?;
+        self.get_with(&client, url)
+      }
+    }
+  }
+
+  /// Send GET request with the cookie by `client`
+  ///
+  /// A redirect to the login page is reported as `Expired`, whether `client` follows redirects or not.
+  ///
+  /// # Arguments
+  ///
+  /// * `client` - HTTP client to send the request with
+  /// * `url` - URL of the page to get
+  pub fn get_with(
+    &self,
+    client: &reqwest::blocking::Client,
+    url: &str,
+  ) -> Result<reqwest::blocking::Response, CookieError> {
+    let res = client.get(url).header(COOKIE, self.header_value()).send()?;
+
+    check_expired(res.status(), res.headers(), res.url())?;
+    Ok(res)
+  }
+
+  /// Asynchronous version of `get`
+  #[cfg(feature = "async")]
+  pub async fn get_async(&self, url: &str) -> Result<reqwest::Response, CookieError> {
+    match &self.async_client {
+      Some(client) => self.get_async_with(client, url).await,
+      None => {
+        let client = reqwest::Client::builder()
+          .user_agent(DEFAULT_USER_AGENT)
+          .redirect(Policy::none())
+          .build()?;
+        self.get_async_with(&client, url).await
+      }
+    }
+  }
+
+  /// Asynchronous version of `get_with`
+  #[cfg(feature = "async")]
+  pub async fn get_async_with(
+    &self,
+    client: &reqwest::Client,
+    url: &str,
+  ) -> Result<reqwest::Response, CookieError> {
+    let res = client
+      .get(url)
+      .header(COOKIE, self.header_value())
+      .send()
+      .await?;
+
+    check_expired(res.status(), res.headers(), res.url())?;
+    Ok(res)
+  }
+}
+
+impl Authenticator for HatenaCookieSession {
+  fn authenticate(&mut self, request: &mut HatenaRequest) -> Result<(), AuthError> {
+    request.replace_header(COOKIE.as_str(), &self.header_value());
+    Ok(())
+  }
+
+  /// Hatena ID of the logged-in user, validating the session if unknown
+  fn username(&mut self) -> Result<Option<String>, AuthError> {
+    match &self.username {
+      Some(username) => Ok(Some(username.clone())),
+      None => Ok(Some(self.validate()?)),
+    }
+  }
+
+  fn use_http_client(&mut self, client: &reqwest::blocking::Client) {
+    self.client = Some(client.clone());
+  }
+}
+
+#[cfg(feature = "async")]
+impl AsyncAuthenticator for HatenaCookieSession {
+  async fn authenticate(&mut self, request: &mut HatenaRequest) -> Result<(), AuthError> {
+    Authenticator::authenticate(self, request)
+  }
+
+  async fn username(&mut self) -> Result<Option<String>, AuthError> {
+    match &self.username {
+      Some(username) => Ok(Some(username.clone())),
+      None => Ok(Some(self.validate_async().await?)),
+    }
+  }
+
+  fn use_http_client(&mut self, client: &reqwest::Client) {
+    self.async_client = Some(client.clone());
+  }
+}
+
+/// Error if the response redirects (or was redirected) to the login page
+fn check_expired(
+  status: reqwest::StatusCode,
+  headers: &reqwest::header::HeaderMap,
+  url: &reqwest::Url,
+) -> Result<(), CookieError> {
+  if url.path().starts_with("/login") {
+    return Err(CookieError::Expired {
+      location: url.to_string(),
+    });
+  }
+  if !status.is_redirection() {
+    return Ok(());
+  }
+  let location = headers
+    .get(LOCATION)
+    .and_then(|location| location.to_str().ok())
+    .unwrap_or_default();
+  if location.contains("/login") {
+    Err(CookieError::Expired {
+      location: location.into(),
+    })
+  } else {
+    Ok(())
+  }
+}
+
+/// Parse unexpired cookies of Hatena in Netscape cookies.txt format
+///
+/// Each line is `domain, include_subdomains, path, secure, expires, name, value` separated by tabs.
+fn parse_cookie_file(content: &str, now: i64) -> Vec<(String, SecretString)> {
+  let mut cookies = vec![];
+  for line in content.lines() {
+    let line = line.strip_prefix("#HttpOnly_").unwrap_or(line);
+    if line.starts_with('#') || line.trim().is_empty() {
+      continue;
+    }
+    let fields: Vec<&str> = line.split('\t').collect();
+    if fields.len() != 7 {
+      continue;
+    }
+
+    let domain = fields[0].trim_start_matches('.');
+    let is_hatena = HATENA_DOMAINS
+      .iter()
+      .any(|hatena| domain == *hatena || domain.ends_with(&format!(".{}", hatena)));
+    let expires: i64 = fields[4].parse().unwrap_or(0);
+    if is_hatena && (expires == 0 || expires > now) {
+      cookies.push((fields[5].to_string(), fields[6].into()));
+    }
+  }
+
+  cookies
+}
+
+/// Hatena ID of the logged-in user, which is `data-user-name` attribute of the page
+fn parse_logged_in_user(html: &str) -> Option<String> {
+  let document = Html::parse_document(html);
+  let selector = Selector::parse("[data-user-name]").unwrap();
+
+  document
+    .select(&selector)
+    .filter_map(|element| element.value().attr("data-user-name"))
+    .find(|username| !username.is_empty())
+    .map(|username| username.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::*;
+
+  #[test]
+  fn test_parse_cookie_file() {
+    let content = "# Netscape HTTP Cookie File\n\
+      .hatena.ne.jp\tTRUE\t/\tTRUE\t0\trk\tsession\n\
+      #HttpOnly_.hatena.ne.jp\tTRUE\t/\tTRUE\t2000\tb\tbrowser\n\
+      .hatena.ne.jp\tTRUE\t/\tTRUE\t500\told\texpired\n\
+      .example.com\tTRUE\t/\tFALSE\t0\trk\tother\n";
+
+    assert_eq!(
+      parse_cookie_file(content, 1000),
+      vec![
+        ("rk".to_string(), "session".into()),
+        ("b".to_string(), "browser".into()),
+      ]
+    );
+    assert!(matches!(
+      HatenaCookieSession::from_cookies(parse_cookie_file("", 1000)),
+      Err(CookieError::NoHatenaCookie)
+    ));
+  }
+
+  #[test]
+  fn test_header_value() {
+    let session = HatenaCookieSession::new(" rk=session ; b=browser").unwrap();
+    assert_eq!(session.header_value(), "rk=session; b=browser");
+
+    let mut session = session.with_username("smallkirby");
+    let mut request = HatenaRequest::new(reqwest::Method::GET, "https://f.hatena.ne.jp/");
+    request.set_header("cookie", "stale");
+    Authenticator::authenticate(&mut session, &mut request).unwrap();
+    assert_eq!(
+      request.headers,
+      vec![("cookie".into(), "rk=session; b=browser".into())]
+    );
+    assert_eq!(
+      Authenticator::username(&mut session).unwrap(),
+      Some("smallkirby".into())
+    );
+  }
+
+  #[test]
+  fn test_validate() {
+    let (base, server) = spawn_stub_server(vec![
+      response(
+        "200 OK",
+        &[],
+        r#"<html><body data-user-name="smallkirby"></body></html>"#,
+      ),
+      response(
+        "302 Found",
+        &[(
+          "Location",
+          "https://www.hatena.ne.jp/login?location=%2Fmy%2F",
+        )],
+        "",
+      ),
+    ]);
+    let mut session =
+      HatenaCookieSession::from_rk("session").with_session_check(&format!("{}/my/", base));
+
+    assert_eq!(session.validate().unwrap(), "smallkirby");
+    assert_eq!(session.username(), Some("smallkirby"));
+    assert!(matches!(
+      session.validate(),
+      Err(CookieError::Expired { location }) if location.starts_with("https://www.hatena.ne.jp/login")
+    ));
+
+    let requests = server.join().unwrap();
+    assert_eq!(requests[0].request_line, "GET /my/ HTTP/1.1");
+    assert_eq!(requests[0].header("cookie"), Some("rk=session"));
+  }
+}
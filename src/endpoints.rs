@@ -0,0 +1,95 @@
+use crate::fotolife::consts::*;
+use crate::oauth::consts::*;
+
+/// Endpoint URLs used by API clients
+///
+/// By default, it points to the production Hatena servers.
+/// Override it to use a local mock server, a recording proxy or a staging host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HatenaEndpoints {
+  /// OAuth endpoint to get a request token
+  pub oauth_request_token: String,
+  /// OAuth endpoint to which a user is redirected to grant a permission
+  pub oauth_authorize: String,
+  /// OAuth endpoint to get an access token
+  pub oauth_access_token: String,
+  /// Endpoint to get the profile of the authenticated user
+  pub oauth_user_info: String,
+  /// Fotolife AtomPub endpoint to post an image
+  pub fotolife_post: String,
+  /// Fotolife AtomPub endpoint to edit an image
+  pub fotolife_edit: String,
+  /// Fotolife top page, under which user's photo lists are
+  pub fotolife_list: String,
+}
+
+impl Default for HatenaEndpoints {
+  fn default() -> Self {
+    Self {
+      oauth_request_token: OAUTH_URL_REQUEST_TOKEN.into(),
+      oauth_authorize: OAUTH_URL_GRANT_PERMISSION.into(),
+      oauth_access_token: OAUTH_URL_ACCESS_TOKEN.into(),
+      oauth_user_info: OAUTH_URL_USER_INFO.into(),
+      fotolife_post: FOTOLIFE_URL_POST.into(),
+      fotolife_edit: FOTOLIFE_URL_EDIT.into(),
+      fotolife_list: FOTOLIFE_URL_LIST.into(),
+    }
+  }
+}
+
+impl HatenaEndpoints {
+  /// Endpoints of all APIs served under a single `base` URL (eg. `http://localhost:49494`)
+  pub fn from_base_url(base: &str) -> Self {
+    let mut endpoints = Self::default()
+      .with_oauth_base(base)
+      .with_fotolife_base(base);
+    endpoints.oauth_user_info = format!("{}/applications/my.json", base.trim_end_matches('/'));
+    endpoints
+  }
+
+  /// Replace OAuth endpoints with the ones under `base` URL
+  ///
+  /// `oauth_user_info` is kept as is, because Hatena serves it on another host.
+  pub fn with_oauth_base(mut self, base: &str) -> Self {
+    let base = base.trim_end_matches('/');
+    self.oauth_request_token = format!("{}/oauth/initiate", base);
+    self.oauth_authorize = format!("{}/oauth/authorize", base);
+    self.oauth_access_token = format!("{}/oauth/token", base);
+    self
+  }
+
+  /// Replace Fotolife endpoints with the ones under `base` URL
+  pub fn with_fotolife_base(mut self, base: &str) -> Self {
+    let base = base.trim_end_matches('/');
+    self.fotolife_post = format!("{}/atom/post", base);
+    self.fotolife_edit = format!("{}/atom/edit", base);
+    self.fotolife_list = base.into();
+    self
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_from_base_url() {
+    let endpoints = HatenaEndpoints::from_base_url("http://localhost:49494/");
+    assert_eq!(
+      endpoints.oauth_request_token,
+      "http://localhost:49494/oauth/initiate"
+    );
+    assert_eq!(endpoints.fotolife_post, "http://localhost:49494/atom/post");
+    assert_eq!(endpoints.fotolife_list, "http://localhost:49494");
+    assert_eq!(
+      endpoints.oauth_user_info,
+      "http://localhost:49494/applications/my.json"
+    );
+
+    let default = HatenaEndpoints::default();
+    assert_eq!(
+      default.with_oauth_base("https://www.hatena.com"),
+      HatenaEndpoints::default()
+    );
+  }
+}
@@ -1,36 +1,150 @@
-mod consts;
+#[cfg(feature = "async")]
+mod async_fotolife;
+pub mod consts;
 mod error;
+#[allow(clippy::module_inception)]
 mod fotolife;
 
-use std::io::Read;
 use std::path::Path;
+use std::time::Duration;
 
-use crate::fotolife::consts::*;
+use crate::auth::*;
+use crate::client::*;
+use crate::cookie::HatenaCookieSession;
+use crate::endpoints::HatenaEndpoints;
 use crate::fotolife::error::*;
 use crate::fotolife::fotolife::*;
+use crate::oauth::consts::OauthScope;
+use crate::oauth::request::*;
 use crate::oauth::HatenaOauth;
+use crate::wsse::*;
 
-use reqwest::StatusCode;
-use scraper::{Html, Selector};
+use reqwest::blocking::Response;
+use reqwest::{Method, StatusCode};
+
+#[cfg(feature = "async")]
+pub use crate::fotolife::async_fotolife::AsyncFotolife;
 
 /// Hatena Fotolife client instance
-pub struct Fotolife {
-  // OAuth manager client
-  pub oauth: HatenaOauth,
+///
+/// Requests are authenticated by `A`, which is OAuth by default.
+pub struct Fotolife<A: Authenticator = HatenaOauth> {
+  /// Authenticator of requests
+  auth: A,
+  /// Endpoints of Hatena Fotolife
+  endpoints: HatenaEndpoints,
+  /// HTTP client shared by all requests
+  client: reqwest::blocking::Client,
 }
 
-impl Fotolife {
+impl Fotolife<HatenaOauth> {
   /// Create a new Fotolife client instance
   ///
   /// # Arguments
   ///
-  /// * `access_token` - Access token for Hatena API
+  /// * `oauth` - OAuth client for Hatena API
   pub fn new(oauth: HatenaOauth) -> Self {
-    Self { oauth }
+    let endpoints = oauth.endpoints().clone();
+    let client = oauth.http_client().clone();
+    Self {
+      auth: oauth,
+      endpoints,
+      client,
+    }
+  }
+}
+
+impl Fotolife<WsseCredentials> {
+  /// Create a new Fotolife client instance authenticated by WSSE instead of OAuth
+  ///
+  /// # Arguments
+  ///
+  /// * `credentials` - Hatena ID and API key
+  pub fn with_wsse(credentials: WsseCredentials) -> Result<Self, FotolifeError> {
+    Self::with_authenticator(credentials)
+  }
+}
+
+impl Fotolife<HatenaCookieSession> {
+  /// List image in specific `path` of user's Fotolife using Cookie of the authenticator.
+  ///
+  /// When success, returns a list of image IDs.
+  ///
+  /// Note that this doesn't use API.
+  ///
+  /// # Arguments
+  ///
+  /// * `path` - Path to list images
+  /// * `username` - Hatena username. If not specified, it asks the session (validating it if needed).
+  pub fn list_images_directory(
+    &mut self,
+    path: &str,
+    username: Option<&str>,
+  ) -> Result<Vec<String>, FotolifeError> {
+    let session = self.auth.clone();
+    self.list_images_directory_with(path, &session, username)
+  }
+}
+
+impl<A: Authenticator> Fotolife<A> {
+  /// Create a new Fotolife client instance authenticated by `auth`
+  ///
+  /// It fails if the HTTP client cannot be built (eg. TLS is unavailable).
+  ///
+  /// # Arguments
+  ///
+  /// * `auth` - Authenticator of requests (eg. `WsseCredentials`, `NoAuth`)
+  pub fn with_authenticator(auth: A) -> Result<Self, FotolifeError> {
+    let client = HttpClientConfig::default().build_blocking()?;
+    Ok(
+      Self {
+        auth,
+        endpoints: HatenaEndpoints::default(),
+        client,
+      }
+      .share_http_client(),
+    )
+  }
+
+  /// Authenticator of requests (eg. `HatenaOauth`)
+  pub fn authenticator(&mut self) -> &mut A {
+    &mut self.auth
+  }
+
+  /// Use `endpoints` instead of the ones of the OAuth client
+  ///
+  /// # Arguments
+  ///
+  /// * `endpoints` - Endpoints to send requests to
+  pub fn with_endpoints(mut self, endpoints: HatenaEndpoints) -> Self {
+    self.endpoints = endpoints;
+    self
+  }
+
+  /// Send requests with a client built from `config`, instead of the one of the OAuth client
+  ///
+  /// It fails if the proxy URL or a certificate in `config` is invalid.
+  pub fn with_http_config(self, config: &HttpClientConfig) -> Result<Self, FotolifeError> {
+    Ok(self.with_http_client(config.build_blocking()?))
+  }
+
+  /// Send requests with `client`, instead of the one of the OAuth client
+  ///
+  /// The authenticator sends its own requests (eg. validating a cookie session) with it too.
+  pub fn with_http_client(mut self, client: reqwest::blocking::Client) -> Self {
+    self.client = client;
+    self.share_http_client()
+  }
+
+  fn share_http_client(mut self) -> Self {
+    self.auth.use_http_client(&self.client);
+    self
   }
 
   /// Upload a photo to Hatena Fotolife
   ///
+  /// If authenticated by OAuth, the access token needs `write_public` or `write_private` scope.
+  ///
   /// # Arguments
   ///
   /// * `image_path`: Path to the image file to upload
@@ -42,24 +156,21 @@ impl Fotolife {
     title: &str,
     timeout: u64,
   ) -> Result<FotolifePostResponse, FotolifeError> {
-    let xml = self.generate_post_xml(image_path, title, "hatena-rs")?;
-    let res = self.oauth.post(FOTOLIFE_URL_POST, &xml, false, timeout)?;
+    self
+      .auth
+      .require_scopes(&[OauthScope::WritePublic, OauthScope::WritePrivate])?;
+    let xml = generate_post_xml(image_path, title, "hatena-rs")?;
+    let mut request = HatenaRequest::new(Method::POST, &self.endpoints.fotolife_post);
+    request.body = Some(xml.into_bytes());
+    request.timeout = Some(Duration::from_secs(timeout));
+    let res = self.send(request)?;
 
     if res.status().is_success() {
-      let location = res
-        .headers()
-        .get("location")
-        .unwrap()
-        .to_str()
-        .unwrap()
-        .to_string();
-      Ok(FotolifePostResponse::new(
-        location.split("/").last().unwrap().to_string(),
-      ))
+      FotolifePostResponse::from_location(res.headers())
     } else {
-      return Err(FotolifeError::UploadFailure {
+      Err(FotolifeError::UploadFailure {
         status: res.status(),
-      });
+      })
     }
   }
 
@@ -72,50 +183,45 @@ impl Fotolife {
   /// * `image_id` - ID of the image to get
   #[allow(dead_code)]
   pub fn get_image(&mut self, photo_id: &str) -> Result<(), FotolifeError> {
-    let url = format!("{}/{}", FOTOLIFE_URL_EDIT, photo_id);
-    let res = self.oauth.get(&url, false)?; // XXX
+    let url = format!("{}/{}", self.endpoints.fotolife_edit, photo_id);
+    let _res = self.send(HatenaRequest::new(Method::GET, &url))?; // XXX
 
     unimplemented!();
-
-    Ok(())
   }
 
-  /// List image in specific `path` of user's Fotolife using Cookie.
+  /// List image in specific `path` of user's Fotolife using Cookie of `session`.
   ///
   /// When success, returns a list of image IDs.
   ///
-  /// Note that this doesn't use API and needs Cookie (`rk`).
+  /// Note that this doesn't use API and needs a logged-in Cookie session.
+  /// Clients authenticated by a cookie session use `list_images_directory` instead.
   ///
   /// # Arguments
   ///
   /// * `path` - Path to list images
-  /// * `cookie` - logged-in Cookie named `rk`
-  /// * `username` - Hatena username. If not specified, it uses OAuth API to fetch username.
-  ///
-  ///
-  pub fn list_images_directory(
+  /// * `session` - logged-in Cookie session
+  /// * `username` - Hatena username. If not specified, it asks the session, then the authenticator (eg. OAuth API).
+  pub fn list_images_directory_with(
     &mut self,
     path: &str,
-    cookie: &str,
+    session: &HatenaCookieSession,
     username: Option<&str>,
   ) -> Result<Vec<String>, FotolifeError> {
-    let username = if username.is_none() {
-      let me_info = self.oauth.get_access_token(false)?;
-      me_info.url_name
-    } else {
-      username.unwrap().into()
+    let username = match username.or(session.username()) {
+      Some(username) => username.to_string(),
+      None => self
+        .auth
+        .username()?
+        .ok_or(FotolifeError::UsernameUnknown)?,
     };
 
-    let url = format!("{}/{}/{}/", FOTOLIFE_URL_LIST, username, path);
-    let res = reqwest::blocking::Client::new()
-      .get(&url)
-      .header("Cookie", format!("rk={}", cookie))
-      .send()?;
+    let url = format!("{}/{}/{}/", self.endpoints.fotolife_list, username, path);
+    let res = session.get_with(&self.client, &url)?;
 
     match res.status() {
       StatusCode::OK => {
-        let body = res.text().unwrap();
-        let image_ids = self.parse_photolist_html(&body, &username);
+        let body = res.text()?;
+        let image_ids = parse_photolist_html(&body, &username);
         Ok(image_ids)
       }
       StatusCode::NOT_FOUND => Ok(vec![]),
@@ -125,63 +231,23 @@ impl Fotolife {
     }
   }
 
-  fn generate_post_xml(
-    &self,
-    image_path: &Path,
-    title: &str,
-    generator: &str,
-  ) -> Result<String, FotolifeError> {
-    if !image_path.exists() || !image_path.is_file() {
-      return Err(FotolifeError::ResourceNotFound {
-        resource: image_path.to_string_lossy().to_string(),
-      });
-    }
-
-    let file = std::fs::File::open(image_path).map_err(|_| FotolifeError::ResourceNotFound {
-      resource: image_path.to_string_lossy().to_string(),
-    })?;
-    let bytes = file.bytes().map(|b| b.unwrap()).collect::<Vec<u8>>();
-    let encoded_image = base64::encode(&bytes);
-    let typestr = format!(
-      "image/{}",
-      image_path.extension().unwrap().to_str().unwrap()
-    );
-
-    Ok(format!(
-      r#"
-        <entry xmlns="http://purl.org/atom/ns#">
-          <title>{}</title>
-          <content mode="base64" type="{}">{}</content>
-          <generator>{}</generator>
-        </entry>
-      "#,
-      title, typestr, encoded_image, generator,
-    ))
-  }
+  /// Send a request authenticated by the authenticator, retrying once if it asks
+  fn send(&mut self, mut request: HatenaRequest) -> Result<Response, FotolifeError> {
+    let mut retried = false;
+    loop {
+      self.auth.authenticate(&mut request)?;
+      let res = request.to_blocking(&self.client)?.send()?;
+      if retried || res.status().is_success() {
+        return Ok(res);
+      }
 
-  fn parse_photolist_html(&self, html: &str, user_name: &str) -> Vec<String> {
-    let mut photos = vec![];
-    let document = Html::parse_document(html);
-    let selector = Selector::parse("img.foto_thumb").unwrap();
-
-    for element in document.select(&selector) {
-      let a_elem = if let Some(a_elem) = element.parent() {
-        a_elem.value().as_element().unwrap()
-      } else {
-        continue;
-      };
-      let href = if let Some(href) = a_elem.attr("href") {
-        href
-      } else {
-        continue;
-      };
-      if href.starts_with(&format!("/{}/", user_name)) {
-        let id = href.split("/").last().unwrap();
-        photos.push(id.into());
+      let (status, version, headers) = (res.status(), res.version(), res.headers().clone());
+      let body = res.bytes()?.to_vec();
+      if !self.auth.retry(status, &headers, &body)? {
+        return Ok(rebuild_response(status, version, headers, body));
       }
+      retried = true;
     }
-
-    photos
   }
 }
 
@@ -189,31 +255,37 @@ impl Fotolife {
 mod tests {
   use super::*;
   use crate::oauth::consts::OauthScope;
+  use crate::oauth::store::MemoryTokenStore;
   use crate::oauth::{HatenaConsumerInfo, HatenaOauth};
+  use crate::test_util::*;
+  use crate::testing::cassette::*;
 
   #[test]
   fn test_post_image() {
-    let consumer_info = HatenaConsumerInfo::from_env().unwrap();
-    let oauth = HatenaOauth::new(
+    let cassette = cassette("fotolife_post_image");
+    let oauth = cassette_oauth(
+      &cassette,
       vec![
         OauthScope::WritePublic,
         OauthScope::WritePrivate,
         OauthScope::ReadPublic,
         OauthScope::ReadPrivate,
       ],
-      None,
-      consumer_info,
     )
+    .with_token_store(Box::new(MemoryTokenStore::with_token(replay_token())))
     .unwrap();
     let mut fotolife = Fotolife::new(oauth);
+    let image = std::env::temp_dir().join(format!("hatena-rs-post-{}.png", std::process::id()));
+    std::fs::write(&image, b"\x89PNG").unwrap();
 
-    let res = fotolife
-      .post_image(Path::new("test.png"), "test rust", 10)
-      .unwrap();
-    println!("{:?}", res);
+    let res = fotolife.post_image(&image, "test rust", 10).unwrap();
+    assert_eq!(res.image_id, "20220101000000");
+    cassette.finish().unwrap();
+    std::fs::remove_file(image).unwrap();
   }
 
   #[test]
+  #[ignore = "get_image is not implemented yet, and it needs credentials of Hatena"]
   fn test_get_image() {
     let consumer_info = HatenaConsumerInfo::from_env().unwrap();
     let oauth = HatenaOauth::new(
@@ -234,24 +306,173 @@ mod tests {
 
   #[test]
   fn test_list_photos() {
-    let consumer_info = HatenaConsumerInfo::from_env().unwrap();
-    let oauth = HatenaOauth::new(
-      vec![
-        OauthScope::WritePublic,
-        OauthScope::WritePrivate,
-        OauthScope::ReadPublic,
-        OauthScope::ReadPrivate,
-      ],
-      None,
-      consumer_info,
-    )
-    .unwrap();
-    let mut fotolife = Fotolife::new(oauth);
-    let cookie = std::env::var("FOTOLIFE_COOKIE").unwrap();
+    let cassette = cassette("fotolife_list_photos");
+    let cookie = match cassette.mode() {
+      CassetteMode::Record => std::env::var("FOTOLIFE_COOKIE").unwrap(),
+      CassetteMode::Replay => SCRUBBED.into(),
+    };
+    let session = HatenaCookieSession::from_rk(&cookie);
+    let mut fotolife = Fotolife::with_authenticator(session)
+      .unwrap()
+      .with_endpoints(cassette.endpoints());
 
     let ids = fotolife
-      .list_images_directory("hatena-rs", &cookie, Some("smallkirby"))
+      .list_images_directory("hatena-rs", Some("smallkirby"))
       .unwrap();
-    println!("{:?}", ids);
+    assert_eq!(ids, vec!["20220101000000", "20220102000000"]);
+    cassette.finish().unwrap();
+  }
+
+  #[test]
+  fn test_post_image_wsse() {
+    let (base, server) = spawn_stub_server(vec![response(
+      "201 Created",
+      &[(
+        "Location",
+        "https://f.hatena.ne.jp/atom/edit/20220101000000",
+      )],
+      "",
+    )]);
+    let image = std::env::temp_dir().join(format!("hatena-rs-wsse-{}.png", std::process::id()));
+    std::fs::write(&image, b"\x89PNG").unwrap();
+
+    let mut fotolife = Fotolife::with_wsse(WsseCredentials::new("smallkirby", "api_key"))
+      .unwrap()
+      .with_endpoints(HatenaEndpoints::from_base_url(&base));
+    let res = fotolife.post_image(&image, "title", 10).unwrap();
+    assert_eq!(res.image_id, "20220101000000");
+
+    let requests = server.join().unwrap();
+    assert_eq!(requests[0].request_line, "POST /atom/post HTTP/1.1");
+    assert!(requests[0]
+      .header("x-wsse")
+      .unwrap()
+      .starts_with(r#"UsernameToken Username="smallkirby", PasswordDigest=""#));
+    assert!(requests[0].header("authorization").is_none());
+    assert_eq!(requests[0].header("user-agent"), Some(DEFAULT_USER_AGENT));
+    std::fs::remove_file(image).unwrap();
+  }
+
+  #[test]
+  fn test_post_image_without_location() {
+    let (base, server) = spawn_stub_server(vec![
+      response("201 Created", &[], ""),
+      response("200 OK", &[("Location", "https://f.hatena.ne.jp/")], ""),
+    ]);
+    let image = std::env::temp_dir().join(format!("hatena-rs-noloc-{}.png", std::process::id()));
+    std::fs::write(&image, b"\x89PNG").unwrap();
+
+    let mut fotolife = Fotolife::with_wsse(WsseCredentials::new("smallkirby", "api_key"))
+      .unwrap()
+      .with_endpoints(HatenaEndpoints::from_base_url(&base));
+    assert!(matches!(
+      fotolife.post_image(&image, "title", 10),
+      Err(FotolifeError::InvalidLocation { location: None })
+    ));
+    assert!(matches!(
+      fotolife.post_image(&image, "title", 10),
+      Err(FotolifeError::InvalidLocation { location: Some(_) })
+    ));
+    server.join().unwrap();
+    std::fs::remove_file(image).unwrap();
+  }
+
+  #[test]
+  fn test_generate_post_xml() {
+    let dir = std::env::temp_dir().join(format!("hatena-rs-xml-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let image = dir.join("image.JPG");
+    std::fs::write(&image, b"jpeg").unwrap();
+    let xml = generate_post_xml(&image, "<a> & \"b\"", "hatena-rs").unwrap();
+    assert!(xml.contains("<title>&lt;a&gt; &amp; &quot;b&quot;</title>"));
+    assert!(xml.contains(r#"type="image/jpeg""#));
+
+    for name in ["image", "image.txt"] {
+      std::fs::write(dir.join(name), b"data").unwrap();
+      assert!(matches!(
+        generate_post_xml(&dir.join(name), "title", "hatena-rs"),
+        Err(FotolifeError::UnsupportedImageType { .. })
+      ));
+    }
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn test_cookie_session_shares_client() {
+    let (proxy, server) = spawn_stub_server(vec![response(
+      "200 OK",
+      &[],
+      r#"<html><body data-user-name="smallkirby"></body></html>"#,
+    )]);
+    let session =
+      HatenaCookieSession::from_rk("rk").with_session_check("http://www.hatena.invalid/my/");
+    let mut fotolife = Fotolife::with_authenticator(session)
+      .unwrap()
+      .with_http_config(
+        &HttpClientConfig::new()
+          .with_user_agent("hatena-rs-test")
+          .with_proxy(&proxy),
+      )
+      .unwrap();
+
+    assert_eq!(
+      Authenticator::username(fotolife.authenticator())
+        .unwrap()
+        .as_deref(),
+      Some("smallkirby")
+    );
+    let requests = server.join().unwrap();
+    assert_eq!(
+      requests[0].request_line,
+      "GET http://www.hatena.invalid/my/ HTTP/1.1"
+    );
+    assert_eq!(requests[0].header("user-agent"), Some("hatena-rs-test"));
+    assert_eq!(requests[0].header("cookie"), Some("rk=rk"));
+  }
+
+  /// Authenticator which asks to retry once on 503
+  struct RetryOnce {
+    authenticated: usize,
+  }
+
+  impl Authenticator for RetryOnce {
+    fn authenticate(&mut self, request: &mut HatenaRequest) -> Result<(), AuthError> {
+      self.authenticated += 1;
+      request.replace_header("X-Attempt", &self.authenticated.to_string());
+      Ok(())
+    }
+
+    fn retry(
+      &mut self,
+      status: StatusCode,
+      _headers: &reqwest::header::HeaderMap,
+      body: &[u8],
+    ) -> Result<bool, AuthError> {
+      Ok(status == StatusCode::SERVICE_UNAVAILABLE && body == b"busy")
+    }
+  }
+
+  #[test]
+  fn test_authenticator_retry() {
+    let (base, server) = spawn_stub_server(vec![
+      response("503 Service Unavailable", &[], "busy"),
+      response("404 Not Found", &[], "missing"),
+    ]);
+    let mut fotolife = Fotolife::with_authenticator(RetryOnce { authenticated: 0 })
+      .unwrap()
+      .with_endpoints(HatenaEndpoints::from_base_url(&base));
+
+    let res = fotolife
+      .send(HatenaRequest::new(
+        Method::GET,
+        &format!("{}/atom/edit/1", base),
+      ))
+      .unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    assert_eq!(res.text().unwrap(), "missing");
+    assert_eq!(fotolife.authenticator().authenticated, 2);
+
+    let requests = server.join().unwrap();
+    assert_eq!(requests[1].header("x-attempt"), Some("2"));
   }
 }
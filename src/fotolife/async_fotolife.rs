@@ -0,0 +1,280 @@
+use std::path::Path;
+use std::time::Duration;
+
+use reqwest::{Method, Response, StatusCode};
+
+use crate::auth::*;
+use crate::client::*;
+use crate::cookie::HatenaCookieSession;
+use crate::endpoints::HatenaEndpoints;
+use crate::fotolife::error::*;
+use crate::fotolife::fotolife::*;
+use crate::oauth::consts::OauthScope;
+use crate::oauth::request::*;
+use crate::oauth::AsyncHatenaOauth;
+use crate::wsse::*;
+
+/// Asynchronous Hatena Fotolife client instance
+///
+/// It has the same semantics as `Fotolife`, but built on `AsyncAuthenticator` (`AsyncHatenaOauth` by default).
+pub struct AsyncFotolife<A: AsyncAuthenticator = AsyncHatenaOauth> {
+  /// Authenticator of requests
+  auth: A,
+  /// Endpoints of Hatena Fotolife
+  endpoints: HatenaEndpoints,
+  /// HTTP client shared by all requests
+  client: reqwest::Client,
+}
+
+impl AsyncFotolife<AsyncHatenaOauth> {
+  /// Create a new asynchronous Fotolife client instance
+  ///
+  /// # Arguments
+  ///
+  /// * `oauth` - Asynchronous OAuth client
+  pub fn new(oauth: AsyncHatenaOauth) -> Self {
+    let endpoints = oauth.endpoints().clone();
+    let client = oauth.http_client().clone();
+    Self {
+      auth: oauth,
+      endpoints,
+      client,
+    }
+  }
+}
+
+impl AsyncFotolife<WsseCredentials> {
+  /// Create a new asynchronous Fotolife client instance authenticated by WSSE instead of OAuth
+  ///
+  /// # Arguments
+  ///
+  /// * `credentials` - Hatena ID and API key
+  pub fn with_wsse(credentials: WsseCredentials) -> Result<Self, FotolifeError> {
+    Self::with_authenticator(credentials)
+  }
+}
+
+impl AsyncFotolife<HatenaCookieSession> {
+  /// List image in specific `path` of user's Fotolife using Cookie of the authenticator.
+  ///
+  /// See `Fotolife::list_images_directory`.
+  ///
+  /// # Arguments
+  ///
+  /// * `path` - Path to list images
+  /// * `username` - Hatena username. If not specified, it asks the session (validating it if needed).
+  pub async fn list_images_directory(
+    &mut self,
+    path: &str,
+    username: Option<&str>,
+  ) -> Result<Vec<String>, FotolifeError> {
+    let session = self.auth.clone();
+    self
+      .list_images_directory_with(path, &session, username)
+      .await
+  }
+}
+
+impl<A: AsyncAuthenticator> AsyncFotolife<A> {
+  /// Create a new asynchronous Fotolife client instance authenticated by `auth`
+  ///
+  /// It fails if the HTTP client cannot be built (eg. TLS is unavailable).
+  ///
+  /// # Arguments
+  ///
+  /// * `auth` - Authenticator of requests (eg. `WsseCredentials`, `NoAuth`)
+  pub fn with_authenticator(auth: A) -> Result<Self, FotolifeError> {
+    let client = HttpClientConfig::default().build_async()?;
+    Ok(
+      Self {
+        auth,
+        endpoints: HatenaEndpoints::default(),
+        client,
+      }
+      .share_http_client(),
+    )
+  }
+
+  /// Authenticator of requests (eg. `AsyncHatenaOauth`)
+  pub fn authenticator(&mut self) -> &mut A {
+    &mut self.auth
+  }
+
+  /// Use `endpoints` instead of the ones of the OAuth client
+  ///
+  /// # Arguments
+  ///
+  /// * `endpoints` - Endpoints to send requests to
+  pub fn with_endpoints(mut self, endpoints: HatenaEndpoints) -> Self {
+    self.endpoints = endpoints;
+    self
+  }
+
+  /// Send requests with a client built from `config`
+  ///
+  /// See `Fotolife::with_http_config`.
+  pub fn with_http_config(self, config: &HttpClientConfig) -> Result<Self, FotolifeError> {
+    Ok(self.with_http_client(config.build_async()?))
+  }
+
+  /// Send requests with `client`, instead of the one of the OAuth client
+  pub fn with_http_client(mut self, client: reqwest::Client) -> Self {
+    self.client = client;
+    self.share_http_client()
+  }
+
+  fn share_http_client(mut self) -> Self {
+    self.auth.use_http_client(&self.client);
+    self
+  }
+
+  /// Upload a photo to Hatena Fotolife
+  ///
+  /// # Arguments
+  ///
+  /// * `image_path`: Path to the image file to upload
+  /// * `title` - Title of the photo
+  /// * `timeout` - Timeout in seconds
+  pub async fn post_image(
+    &mut self,
+    image_path: &Path,
+    title: &str,
+    timeout: u64,
+  ) -> Result<FotolifePostResponse, FotolifeError> {
+    self
+      .auth
+      .require_scopes(&[OauthScope::WritePublic, OauthScope::WritePrivate])
+      .await?;
+    let xml = generate_post_xml(image_path, title, "hatena-rs")?;
+    let mut request = HatenaRequest::new(Method::POST, &self.endpoints.fotolife_post);
+    request.body = Some(xml.into_bytes());
+    request.timeout = Some(Duration::from_secs(timeout));
+    let res = self.send(request).await?;
+
+    if res.status().is_success() {
+      FotolifePostResponse::from_location(res.headers())
+    } else {
+      Err(FotolifeError::UploadFailure {
+        status: res.status(),
+      })
+    }
+  }
+
+  /// List image in specific `path` of user's Fotolife using Cookie of `session`.
+  ///
+  /// See `Fotolife::list_images_directory_with`.
+  ///
+  /// # Arguments
+  ///
+  /// * `path` - Path to list images
+  /// * `session` - logged-in Cookie session
+  /// * `username` - Hatena username. If not specified, it asks the session, then the authenticator (eg. OAuth API).
+  pub async fn list_images_directory_with(
+    &mut self,
+    path: &str,
+    session: &HatenaCookieSession,
+    username: Option<&str>,
+  ) -> Result<Vec<String>, FotolifeError> {
+    let username = match username.or(session.username()) {
+      Some(username) => username.to_string(),
+      None => self
+        .auth
+        .username()
+        .await?
+        .ok_or(FotolifeError::UsernameUnknown)?,
+    };
+
+    let url = format!("{}/{}/{}/", self.endpoints.fotolife_list, username, path);
+    let res = session.get_async_with(&self.client, &url).await?;
+
+    match res.status() {
+      StatusCode::OK => {
+        let body = res.text().await?;
+        Ok(parse_photolist_html(&body, &username))
+      }
+      StatusCode::NOT_FOUND => Ok(vec![]),
+      _ => Err(FotolifeError::UploadFailure {
+        status: res.status(),
+      }),
+    }
+  }
+
+  /// Send a request authenticated by the authenticator, retrying once if it asks
+  async fn send(&mut self, mut request: HatenaRequest) -> Result<Response, FotolifeError> {
+    let mut retried = false;
+    loop {
+      self.auth.authenticate(&mut request).await?;
+      let res = request.to_async(&self.client)?.send().await?;
+      if retried || res.status().is_success() {
+        return Ok(res);
+      }
+
+      let (status, version, headers) = (res.status(), res.version(), res.headers().clone());
+      let body = res.bytes().await?.to_vec();
+      if !self.auth.retry(status, &headers, &body).await? {
+        return Ok(rebuild_response(status, version, headers, body));
+      }
+      retried = true;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::oauth::HatenaConsumerInfo;
+  use crate::test_util::*;
+
+  #[tokio::test]
+  async fn test_async_post_image() {
+    let (base, server) = spawn_stub_server(vec![
+      response("401 Unauthorized", &[], "oauth_problem=token_rejected"),
+      response(
+        "200 OK",
+        &[],
+        "oauth_token=req&oauth_token_secret=req_secret&oauth_callback_confirmed=true",
+      ),
+      response(
+        "200 OK",
+        &[],
+        "oauth_token=new&oauth_token_secret=new_secret&url_name=smallkirby&display_name=kirby",
+      ),
+      response(
+        "201 Created",
+        &[(
+          "Location",
+          "https://f.hatena.ne.jp/atom/edit/20220101000000",
+        )],
+        "",
+      ),
+    ]);
+    let mut oauth = AsyncHatenaOauth::new(
+      vec![OauthScope::WritePublic],
+      Some(Box::new(|_| Ok("verifier".to_string()))),
+      HatenaConsumerInfo::new("key", "secret").unwrap(),
+    )
+    .unwrap()
+    .with_browser_launcher(None)
+    .with_endpoints(HatenaEndpoints::from_base_url(&base));
+    oauth.core.access_token = Some(sample_token());
+    let image = std::env::temp_dir().join(format!("hatena-rs-async-{}.png", std::process::id()));
+    std::fs::write(&image, b"\x89PNG").unwrap();
+
+    let mut fotolife = AsyncFotolife::new(oauth);
+    let res = fotolife.post_image(&image, "title", 10).await.unwrap();
+    assert_eq!(res.image_id, "20220101000000");
+
+    let requests = server.join().unwrap();
+    assert_eq!(requests[0].request_line, "POST /atom/post HTTP/1.1");
+    assert!(requests[0]
+      .header("authorization")
+      .unwrap()
+      .contains("oauth_token=\"token\""));
+    assert_eq!(requests[3].request_line, "POST /atom/post HTTP/1.1");
+    assert!(requests[3]
+      .header("authorization")
+      .unwrap()
+      .contains("oauth_token=\"new\""));
+    std::fs::remove_file(image).unwrap();
+  }
+}
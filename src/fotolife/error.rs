@@ -1,6 +1,8 @@
 use reqwest::StatusCode;
 use thiserror::Error;
 
+use crate::auth::AuthError;
+use crate::cookie::CookieError;
 use crate::oauth::error::OauthError;
 
 #[derive(Debug, Error)]
@@ -8,6 +10,9 @@ pub enum FotolifeError {
   #[error("failed to open requested resource: {resource:?}")]
   ResourceNotFound { resource: String },
 
+  #[error("image type is not supported by Fotolife: {path:?}")]
+  UnsupportedImageType { path: String },
+
   #[error("request failed")]
   RequestFailure(#[from] OauthError),
 
@@ -16,4 +21,26 @@ pub enum FotolifeError {
 
   #[error("request failed")]
   HttpFailure(#[from] reqwest::Error),
+
+  #[error("authentication failed")]
+  AuthFailure(#[source] AuthError),
+
+  #[error("cookie session failed")]
+  SessionFailure(#[from] CookieError),
+
+  #[error("username is neither given nor known by the authenticator")]
+  UsernameUnknown,
+
+  #[error("response lacks Location of the uploaded image: {location:?}")]
+  InvalidLocation { location: Option<String> },
+}
+
+impl From<AuthError> for FotolifeError {
+  fn from(err: AuthError) -> Self {
+    match err {
+      AuthError::Oauth(err) => FotolifeError::RequestFailure(err),
+      AuthError::Cookie(err) => FotolifeError::SessionFailure(err),
+      err => FotolifeError::AuthFailure(err),
+    }
+  }
 }
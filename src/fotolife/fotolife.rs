@@ -1,3 +1,10 @@
+use std::path::Path;
+
+use reqwest::header::HeaderMap;
+use scraper::{Html, Selector};
+
+use crate::fotolife::error::*;
+
 /// Response from Fotolife POST API
 #[derive(Debug)]
 pub struct FotolifePostResponse {
@@ -8,4 +15,110 @@ impl FotolifePostResponse {
   pub fn new(image_id: String) -> Self {
     Self { image_id }
   }
+
+  /// Build from headers of the response, whose `Location` points to the uploaded image
+  ///
+  /// It fails with `InvalidLocation` if `Location` is missing or has no image ID.
+  pub fn from_location(headers: &HeaderMap) -> Result<Self, FotolifeError> {
+    let location = headers
+      .get("location")
+      .map(|location| String::from_utf8_lossy(location.as_bytes()).to_string());
+    let image_id = location
+      .as_deref()
+      .and_then(|location| url::Url::parse(location).ok())
+      .and_then(|url| url.path_segments()?.next_back().map(str::to_string))
+      .filter(|id| !id.is_empty());
+
+    match image_id {
+      Some(image_id) => Ok(Self::new(image_id)),
+      None => Err(FotolifeError::InvalidLocation { location }),
+    }
+  }
+}
+
+/// Generate an AtomPub entry to post an image
+pub fn generate_post_xml(
+  image_path: &Path,
+  title: &str,
+  generator: &str,
+) -> Result<String, FotolifeError> {
+  if !image_path.exists() || !image_path.is_file() {
+    return Err(FotolifeError::ResourceNotFound {
+      resource: image_path.to_string_lossy().to_string(),
+    });
+  }
+
+  let typestr = image_content_type(image_path)?;
+  let bytes = std::fs::read(image_path).map_err(|_| FotolifeError::ResourceNotFound {
+    resource: image_path.to_string_lossy().to_string(),
+  })?;
+  let encoded_image = base64::encode(&bytes);
+
+  Ok(format!(
+    r#"
+        <entry xmlns="http://purl.org/atom/ns#">
+          <title>{}</title>
+          <content mode="base64" type="{}">{}</content>
+          <generator>{}</generator>
+        </entry>
+      "#,
+    escape_xml(title),
+    typestr,
+    encoded_image,
+    escape_xml(generator),
+  ))
+}
+
+/// MIME type of an image judged from the extension of `image_path`
+///
+/// It fails with `UnsupportedImageType` if the extension is missing or not an image type Fotolife accepts.
+fn image_content_type(image_path: &Path) -> Result<&'static str, FotolifeError> {
+  let extension = image_path
+    .extension()
+    .and_then(|extension| extension.to_str())
+    .map(|extension| extension.to_ascii_lowercase());
+
+  match extension.as_deref() {
+    Some("jpg" | "jpeg") => Ok("image/jpeg"),
+    Some("png") => Ok("image/png"),
+    Some("gif") => Ok("image/gif"),
+    Some("bmp") => Ok("image/bmp"),
+    _ => Err(FotolifeError::UnsupportedImageType {
+      path: image_path.to_string_lossy().to_string(),
+    }),
+  }
+}
+
+fn escape_xml(text: &str) -> String {
+  text
+    .replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+}
+
+/// Extract image IDs of `user_name` from HTML of a photo list page
+pub fn parse_photolist_html(html: &str, user_name: &str) -> Vec<String> {
+  let mut photos = vec![];
+  let document = Html::parse_document(html);
+  let selector = Selector::parse("img.foto_thumb").unwrap();
+
+  for element in document.select(&selector) {
+    let a_elem = if let Some(a_elem) = element.parent() {
+      a_elem.value().as_element().unwrap()
+    } else {
+      continue;
+    };
+    let href = if let Some(href) = a_elem.attr("href") {
+      href
+    } else {
+      continue;
+    };
+    if href.starts_with(&format!("/{}/", user_name)) {
+      let id = href.split("/").last().unwrap();
+      photos.push(id.into());
+    }
+  }
+
+  photos
 }
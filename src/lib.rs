@@ -1,2 +1,14 @@
+pub mod auth;
+pub mod client;
+pub mod config;
+pub mod cookie;
+pub mod endpoints;
 pub mod fotolife;
 pub mod oauth;
+pub mod secret;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod wsse;
+
+#[cfg(test)]
+mod test_util;
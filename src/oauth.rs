@@ -1,18 +1,49 @@
+#[cfg(feature = "async")]
+mod async_oauth;
+pub mod callback;
+pub mod clock;
 pub mod consts;
+pub mod encrypted_store;
 pub mod error;
+pub mod headless;
+#[allow(clippy::module_inception)]
 mod oauth;
+mod oauth_core;
+mod problem;
+pub mod request;
+pub mod signature;
+pub mod store;
 pub mod token;
-mod util;
+pub(crate) mod util;
 
 use std::env;
+use std::sync::Arc;
+use std::time::Duration;
 
+use crate::auth::*;
+use crate::client::HttpClientConfig;
+use crate::oauth::callback::*;
 use crate::oauth::consts::*;
 use crate::oauth::error::*;
 use crate::oauth::oauth::*;
+use crate::oauth::oauth_core::*;
+use crate::oauth::problem::*;
+use crate::oauth::request::*;
+use crate::oauth::signature::*;
+use crate::oauth::store::*;
 use crate::oauth::token::*;
+use crate::secret::SecretString;
 
 use reqwest::blocking::Response;
-use reqwest::header::AUTHORIZATION;
+use reqwest::header::HeaderMap;
+use reqwest::{Method, StatusCode};
+
+#[cfg(any(test, feature = "testing"))]
+pub(crate) use crate::oauth::oauth::signature_base_string;
+pub(crate) use crate::oauth::oauth::RequestToken;
+
+#[cfg(feature = "async")]
+pub use crate::oauth::async_oauth::{AsyncHatenaOauth, AsyncSignedRequestBuilder};
 
 /// OAuth key info
 #[derive(Debug, Clone)]
@@ -20,14 +51,17 @@ pub struct HatenaConsumerInfo {
   /// Consumer key
   consumer_key: String,
   /// Consumer secret
-  consumer_secret: String,
+  consumer_secret: SecretString,
+  /// Method to sign requests
+  signature_method: SignatureMethod,
 }
 
 impl HatenaConsumerInfo {
   pub fn new(consumer_key: &str, consumer_secret: &str) -> Result<Self, OauthError> {
     Ok(Self {
       consumer_key: consumer_key.to_string(),
-      consumer_secret: consumer_secret.to_string(),
+      consumer_secret: consumer_secret.into(),
+      signature_method: SignatureMethod::default(),
     })
   }
 
@@ -39,24 +73,28 @@ impl HatenaConsumerInfo {
 
     Ok(Self {
       consumer_key,
-      consumer_secret,
+      consumer_secret: consumer_secret.into(),
+      signature_method: SignatureMethod::default(),
     })
   }
+
+  /// Sign requests of the consumer by `signature_method` instead of HMAC-SHA1
+  pub fn with_signature_method(mut self, signature_method: SignatureMethod) -> Self {
+    self.signature_method = signature_method;
+    self
+  }
+
+  pub fn signature_method(&self) -> &SignatureMethod {
+    &self.signature_method
+  }
 }
 
 /// OAuth client instance
 pub struct HatenaOauth {
-  consumer_info: HatenaConsumerInfo,
-  /// Scopes granted for the access token
-  scopes: Vec<OauthScope>,
-  /// Cache of request token response
-  request_token: Option<OauthTokenResponse>,
-  /// Cache of access token response
-  access_token: Option<AccessTokenResponse>,
-  /// Cache of oauth verifier
-  verifier: Option<String>,
-  /// Callback after redirecting user to grant permission
-  grant_permission_callback: fn() -> Result<String, OauthError>,
+  /// State and logic shared with `AsyncHatenaOauth`
+  pub(crate) core: OauthCore,
+  /// HTTP client shared by all requests
+  pub(crate) client: reqwest::blocking::Client,
 }
 
 impl HatenaOauth {
@@ -69,30 +107,95 @@ impl HatenaOauth {
   /// # Arguments
   ///
   /// * `scopes` - Scopes to be requested for the access token
-  /// * `grant_permission_callback` - Callback after redirecting user to grant permission, which prompts user to input a given token
+  /// * `grant_permission_callback` - Callback after redirecting user to grant permission, which receives the authorize URL and request token and returns a verifier. If `None`, it prompts user to input a given token.
   /// * `consumer_info` - A consumer info for Hatena OAuth
   pub fn new(
     scopes: Vec<OauthScope>,
-    grant_permission_callback: Option<fn() -> Result<String, OauthError>>,
+    grant_permission_callback: Option<GrantPermissionCallback>,
     consumer_info: HatenaConsumerInfo,
   ) -> Result<Self, OauthError> {
-    let access_token = get_access_token_from_env();
-    let callback = if let Some(callback) = grant_permission_callback {
-      callback
-    } else {
-      || grant_permission_default_callback()
-    };
-
     Ok(Self {
-      consumer_info,
-      scopes,
-      request_token: None,
-      access_token,
-      verifier: None,
-      grant_permission_callback: callback,
+      core: OauthCore::new(scopes, grant_permission_callback, consumer_info),
+      client: HttpClientConfig::default().build_blocking()?,
     })
   }
 
+  /// Use `store` as a persistent storage of access token
+  ///
+  /// If no access token is cached from environment variables, it loads the one saved in `store`.
+  /// Access tokens obtained afterwards are saved to `store` automatically.
+  ///
+  /// # Arguments
+  ///
+  /// * `store` - Token store to load and save access token
+  pub fn with_token_store(mut self, store: Box<dyn TokenStore>) -> Result<Self, OauthError> {
+    if self.core.access_token.is_none() {
+      self.core.access_token = store.load()?;
+    }
+    self.core.token_store = Some(Arc::from(store));
+
+    Ok(self)
+  }
+
+  impl_oauth_options!();
+
+  /// Send requests with a client built from `config`
+  ///
+  /// It fails if the proxy URL or a certificate in `config` is invalid.
+  pub fn with_http_config(self, config: &HttpClientConfig) -> Result<Self, OauthError> {
+    Ok(self.with_http_client(config.build_blocking()?))
+  }
+
+  /// Send requests with `client`, eg. to share a connection pool with other clients
+  pub fn with_http_client(mut self, client: reqwest::blocking::Client) -> Self {
+    self.client = client;
+    self
+  }
+
+  /// HTTP client this client sends requests with
+  pub fn http_client(&self) -> &reqwest::blocking::Client {
+    &self.client
+  }
+
+  /// Check that the access token has any of `required` scopes before calling an API
+  ///
+  /// If no access token is cached, the scopes to be requested are checked instead.
+  /// If granted scopes of the token are unknown, it passes.
+  /// If incremental authorization is enabled, the first of `required` is added and it re-authorizes.
+  ///
+  /// # Arguments
+  ///
+  /// * `required` - Scopes any of which the API needs
+  pub fn require_scopes(&mut self, required: &[OauthScope]) -> Result<(), OauthError> {
+    if self.core.check_scopes(required)? {
+      self.get_access_token(true)?;
+    }
+
+    Ok(())
+  }
+
+  /// Discard the cached access token, removing it from the token store too.
+  pub fn clear_access_token(&mut self) -> Result<(), OauthError> {
+    self.core.access_token = None;
+    if let Some(store) = &self.core.token_store {
+      store.clear()?;
+    }
+
+    Ok(())
+  }
+
+  /// Build a request signed with OAuth Access Token
+  ///
+  /// Headers, query parameters, body and timeout can be set on the returned builder before sending.
+  ///
+  /// # Arguments
+  ///
+  /// * `method` - HTTP method of the request
+  /// * `url` - URL to send the request
+  pub fn request(&mut self, method: Method, url: &str) -> SignedRequestBuilder<'_> {
+    SignedRequestBuilder::new(self, method, url)
+  }
+
   /// Send GET request with OAuth Acess Token
   ///
   /// If access token is not cached, it first fetches access token.
@@ -102,26 +205,7 @@ impl HatenaOauth {
   /// * `url` - URL to send GET request
   /// * `force` - If true, it fetches access token even if it is cached
   pub fn get(&mut self, url: &str, force: bool) -> Result<Response, OauthError> {
-    if force || self.access_token.is_none() {
-      self.get_access_token(true)?;
-    }
-
-    let req_token = RequestToken::new(
-      &self.consumer_info.consumer_key,
-      &self.consumer_info.consumer_secret,
-      Some(&self.access_token.as_ref().unwrap().oauth_token),
-      Some(&self.access_token.as_ref().unwrap().oauth_token_secret),
-    );
-    let client = reqwest::blocking::Client::new();
-    let response = client
-      .get(url)
-      .header(
-        AUTHORIZATION,
-        req_token.to_header_string(url, "GET", None, None),
-      )
-      .send()?;
-
-    Ok(response)
+    self.request(Method::GET, url).force(force).send()
   }
 
   /// Send POST request with OAuth Acess Token
@@ -141,28 +225,36 @@ impl HatenaOauth {
     force: bool,
     timeout: u64,
   ) -> Result<Response, OauthError> {
-    if force || self.access_token.is_none() {
-      self.get_access_token(true)?;
-    }
+    self
+      .request(Method::POST, url)
+      .force(force)
+      .timeout(Duration::from_secs(timeout))
+      .body(body)
+      .send()
+  }
 
-    let req_token = RequestToken::new(
-      &self.consumer_info.consumer_key,
-      &self.consumer_info.consumer_secret,
-      Some(&self.access_token.as_ref().unwrap().oauth_token),
-      Some(&self.access_token.as_ref().unwrap().oauth_token_secret),
-    );
-    let client = reqwest::blocking::Client::new();
-    let response = client
-      .post(url)
-      .timeout(std::time::Duration::from_secs(timeout))
-      .header(
-        AUTHORIZATION,
-        req_token.to_header_string(url, "POST", None, Some("")),
-      ) // XXX
-      .body(body.to_string())
-      .send()?;
+  /// Get the profile of the user who granted the access token
+  ///
+  /// It can also be used as a cheap check that the access token is still valid.
+  /// `url_name` and `display_name` of the cached access token are updated with the result,
+  /// which is useful when the token is given by environment variables.
+  ///
+  /// # Arguments
+  ///
+  /// * `force` - If true, it fetches access token even if it is cached
+  pub fn me(&mut self, force: bool) -> Result<HatenaUser, OauthError> {
+    let url = self.core.endpoints.oauth_user_info.clone();
+    let res = self.request(Method::GET, &url).force(force).send()?;
+    if res.status() != 200 {
+      return Err(response_error(res)?);
+    }
+    let user = HatenaUser::from(&res.text()?)?;
+    if let Some(access_token) = &mut self.core.access_token {
+      access_token.url_name = user.url_name.clone();
+      access_token.display_name = user.display_name.clone();
+    }
 
-    Ok(response)
+    Ok(user)
   }
 
   /// Get an access token for pre-defined scopes.
@@ -174,46 +266,132 @@ impl HatenaOauth {
   /// * `force` - If true, this function would request a new access token even if the access token is already cached.
   pub fn get_access_token(&mut self, force: bool) -> Result<AccessTokenResponse, OauthError> {
     // Use cached access token if exists
-    if !force && self.access_token.is_some() {
-      return Ok(self.access_token.clone().unwrap());
+    if let Some(access_token) = self.core.access_token.as_ref().filter(|_| !force) {
+      return Ok(access_token.clone());
     }
 
     // Use cached request token and verifier if exists
-    if force || (self.request_token.is_none() || self.verifier.is_none()) {
-      self.get_request_token()?;
-      self.get_verifier()?;
+    if self.core.needs_verifier(force) {
+      self.get_request_token(OAUTH_CALLBACK_OOB)?;
+      self.core.verifier = Some(self.core.grant_permission_task()()?);
     }
 
-    self.access_token = Some(get_access_token(
-      &self.request_token.as_ref().unwrap(),
-      &self.verifier.as_ref().unwrap(),
-      &self.consumer_info.consumer_key,
-      &self.consumer_info.consumer_secret,
-    )?);
+    self.fetch_access_token()
+  }
 
-    Ok(self.access_token.as_ref().unwrap().clone())
+  /// Get an access token for pre-defined scopes, capturing the verifier by a loopback server.
+  ///
+  /// Unlike `get_access_token`, a user does not have to copy the verifier from the browser.
+  /// A short-lived HTTP server is started on 127.0.0.1 and passed as `oauth_callback`,
+  /// then the verifier is taken from the redirect of the authorization endpoint.
+  ///
+  /// # Arguments
+  ///
+  /// * `timeout` - How long to wait for a user to grant a permission
+  pub fn get_access_token_loopback(
+    &mut self,
+    timeout: Duration,
+  ) -> Result<AccessTokenResponse, OauthError> {
+    let server = LoopbackServer::bind()?;
+    self.get_request_token(server.url())?;
+    self.core.verifier = Some(self.core.grant_permission_loopback_task(server, timeout)()?);
+
+    self.fetch_access_token()
   }
 
-  fn get_request_token(&mut self) -> Result<(), OauthError> {
-    self.request_token = Some(get_request_token(
-      &self.scopes,
-      &self.consumer_info.consumer_key,
-      &self.consumer_info.consumer_secret,
-    )?);
+  /// Signer with the access token, fetching it first if not cached or `force` is true
+  fn access_request_token(&mut self, force: bool) -> Result<RequestToken, OauthError> {
+    if force || self.core.access_token.is_none() {
+      self.get_access_token(true)?;
+    }
 
-    Ok(())
+    Ok(self.core.access_signer())
   }
 
-  fn get_verifier(&mut self) -> Result<(), OauthError> {
-    self.verifier = Some(grant_permission_browser(
-      &self.request_token.as_ref().unwrap(),
-      self.grant_permission_callback,
-    )?);
+  /// Recover from `err` reported by a response, so that the failed request can be retried once.
+  ///
+  /// See `OauthCore::recover`. A rejected access token is removed from the token store too.
+  pub(crate) fn recover(&mut self, err: OauthError) -> Result<(), OauthError> {
+    if matches!(err, OauthError::TokenRejected { .. }) {
+      self.clear_access_token()?;
+    }
+    self.core.recover(err)
+  }
+
+  fn fetch_access_token(&mut self) -> Result<AccessTokenResponse, OauthError> {
+    let request = |core: &OauthCore| {
+      get_access_token(
+        &self.client,
+        &core.endpoints,
+        &core.consumer_signer(),
+        core.request_token.as_ref().unwrap(),
+        core.verifier.as_ref().unwrap(),
+      )
+    };
+    let access_token = match request(&self.core) {
+      Err(err) if self.core.adjust_clock(&err) => request(&self.core)?,
+      res => res?,
+    };
+    let access_token = self.core.granted(access_token);
+    if let Some(store) = &self.core.token_store {
+      store.save(&access_token)?;
+    }
+    self.core.access_token = Some(access_token.clone());
+
+    Ok(access_token)
+  }
+
+  fn get_request_token(&mut self, callback: &str) -> Result<(), OauthError> {
+    let request = |core: &OauthCore| {
+      get_request_token(
+        &self.client,
+        &core.endpoints,
+        &core.scopes,
+        &core.consumer_signer(),
+        callback,
+      )
+    };
+    self.core.request_token = Some(match request(&self.core) {
+      Err(err) if self.core.adjust_clock(&err) => request(&self.core)?,
+      res => res?,
+    });
 
     Ok(())
   }
 }
 
+impl Authenticator for HatenaOauth {
+  /// Sign `request` with the access token, fetching it first if not cached
+  fn authenticate(&mut self, request: &mut HatenaRequest) -> Result<(), AuthError> {
+    let req_token = self.access_request_token(false)?;
+    Ok(request.sign(&req_token)?)
+  }
+
+  fn username(&mut self) -> Result<Option<String>, AuthError> {
+    Ok(Some(self.get_access_token(false)?.url_name))
+  }
+
+  fn require_scopes(&mut self, required: &[OauthScope]) -> Result<(), AuthError> {
+    Ok(HatenaOauth::require_scopes(self, required)?)
+  }
+
+  /// Retry if the response reports a revoked token or a refused timestamp
+  fn retry(
+    &mut self,
+    status: StatusCode,
+    headers: &HeaderMap,
+    body: &[u8],
+  ) -> Result<bool, AuthError> {
+    match parse_problem(status, headers, body) {
+      Some(err) => {
+        self.recover(err)?;
+        Ok(true)
+      }
+      None => Ok(false),
+    }
+  }
+}
+
 fn get_access_token_from_env() -> Option<AccessTokenResponse> {
   let access_token = env::var(ENV_OAUTH_ACCESS_TOKEN).unwrap_or("".into());
   let access_secret = env::var(ENV_OAUTH_ACCESS_SECRET).unwrap_or("".into());
@@ -224,9 +402,11 @@ fn get_access_token_from_env() -> Option<AccessTokenResponse> {
   } else {
     Some(AccessTokenResponse {
       oauth_token: access_token,
-      oauth_token_secret: access_secret,
+      oauth_token_secret: access_secret.into(),
       url_name: url_name.clone(),
       display_name: url_name,
+      scopes: None,
+      extra: Default::default(),
     })
   }
 }
@@ -234,12 +414,91 @@ fn get_access_token_from_env() -> Option<AccessTokenResponse> {
 #[cfg(test)]
 mod tests {
   use super::*;
+  use crate::endpoints::HatenaEndpoints;
+  use crate::test_util::*;
 
   #[test]
   fn test_get_access_token() {
-    let consumer_info = HatenaConsumerInfo::from_env().unwrap();
+    let cassette = cassette("oauth_get_access_token");
+    let mut oauth = cassette_oauth(&cassette, vec![OauthScope::ReadPublic]);
+    let token = oauth.get_access_token(true).unwrap();
+    assert_eq!(token.url_name, "smallkirby");
+    cassette.finish().unwrap();
+  }
+
+  #[test]
+  fn test_token_store_reuse() {
+    let consumer_info = HatenaConsumerInfo::new("key", "secret").unwrap();
+    let store = MemoryTokenStore::with_token(sample_token());
+    let mut oauth = HatenaOauth::new(vec![OauthScope::ReadPublic], None, consumer_info).unwrap();
+    // Not to use tokens given by environment variables
+    oauth.core.access_token = None;
+    let mut oauth = oauth.with_token_store(Box::new(store.clone())).unwrap();
+    assert_eq!(oauth.get_access_token(false).unwrap().oauth_token, "token");
+
+    oauth.clear_access_token().unwrap();
+    assert!(store.load().unwrap().is_none());
+  }
+
+  #[test]
+  fn test_me() {
+    let body = r#"{"url_name":"smallkirby","display_name":"kirby","profile_image_url":"https://cdn.profile-image.st-hatena.com/users/smallkirby/profile.png"}"#;
+    let (base, server) = spawn_stub_server(vec![response(
+      "200 OK",
+      &[("Content-Type", "application/json")],
+      body,
+    )]);
+    let consumer_info = HatenaConsumerInfo::new("key", "secret").unwrap();
+    let mut oauth = HatenaOauth::new(vec![OauthScope::ReadPublic], None, consumer_info)
+      .unwrap()
+      .with_endpoints(HatenaEndpoints::from_base_url(&base));
+    // Not to use tokens given by environment variables
+    oauth.core.access_token = Some(sample_token());
+
+    let user = oauth.me(false).unwrap();
+    assert_eq!(user.url_name, "smallkirby");
+    assert_eq!(user.display_name, "kirby");
+    assert_eq!(
+      oauth.core.access_token.as_ref().unwrap().display_name,
+      "kirby"
+    );
+
+    let requests = server.join().unwrap();
+    assert_eq!(
+      requests[0].request_line,
+      "GET /applications/my.json HTTP/1.1"
+    );
+    assert!(requests[0]
+      .header("authorization")
+      .unwrap()
+      .contains("oauth_token=\"token\""));
+  }
+
+  #[test]
+  fn test_require_scopes() {
+    let consumer_info = HatenaConsumerInfo::new("key", "secret").unwrap();
     let mut oauth = HatenaOauth::new(vec![OauthScope::ReadPublic], None, consumer_info).unwrap();
-    let token = oauth.get_access_token(false).unwrap();
-    println!("{:?}", token);
+    oauth.core.access_token = None;
+    assert!(oauth.require_scopes(&[OauthScope::ReadPublic]).is_ok());
+    assert!(matches!(
+      oauth.require_scopes(&[OauthScope::WritePublic]),
+      Err(OauthError::MissingScope { .. })
+    ));
+
+    oauth.core.access_token = Some(AccessTokenResponse {
+      scopes: Some(vec![OauthScope::ReadPublic]),
+      ..sample_token()
+    });
+    match oauth.require_scopes(&[OauthScope::WritePublic, OauthScope::WritePrivate]) {
+      Err(OauthError::MissingScope { required, granted }) => {
+        assert_eq!(required.len(), 2);
+        assert_eq!(granted, vec![OauthScope::ReadPublic]);
+      }
+      res => panic!("unexpected result: {:?}", res),
+    }
+
+    // Tokens of unknown scopes are not checked
+    oauth.core.access_token.as_mut().unwrap().scopes = None;
+    assert!(oauth.require_scopes(&[OauthScope::WritePublic]).is_ok());
   }
 }
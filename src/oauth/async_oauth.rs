@@ -0,0 +1,561 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use reqwest::header::{HeaderMap, AUTHORIZATION, CONTENT_TYPE};
+use reqwest::{Method, Response, StatusCode};
+
+use crate::auth::*;
+use crate::client::HttpClientConfig;
+use crate::oauth::callback::*;
+use crate::oauth::consts::*;
+use crate::oauth::error::*;
+use crate::oauth::oauth::*;
+use crate::oauth::oauth_core::*;
+use crate::oauth::problem::*;
+use crate::oauth::request::*;
+use crate::oauth::store::*;
+use crate::oauth::token::*;
+use crate::oauth::HatenaConsumerInfo;
+
+/// Asynchronous OAuth client instance
+///
+/// It has the same semantics as `HatenaOauth`, but sends requests with non-blocking `reqwest::Client`
+/// so that it can be used inside tokio applications.
+/// Permission callbacks, browser launchers and token stores run on blocking threads.
+pub struct AsyncHatenaOauth {
+  /// State and logic shared with `HatenaOauth`
+  pub(crate) core: OauthCore,
+  /// HTTP client shared by all requests
+  pub(crate) client: reqwest::Client,
+}
+
+impl AsyncHatenaOauth {
+  /// Create a new asynchronous OAuth client instance
+  ///
+  /// It gets cached access token from environment variables if exist.
+  ///
+  /// # Arguments
+  ///
+  /// * `scopes` - Scopes to be requested for the access token
+  /// * `grant_permission_callback` - Callback after redirecting user to grant permission, which receives the authorize URL and request token and returns a verifier. If `None`, it prompts user to input a given token. It runs on a blocking thread.
+  /// * `consumer_info` - A consumer info for Hatena OAuth
+  pub fn new(
+    scopes: Vec<OauthScope>,
+    grant_permission_callback: Option<GrantPermissionCallback>,
+    consumer_info: HatenaConsumerInfo,
+  ) -> Result<Self, OauthError> {
+    Ok(Self {
+      core: OauthCore::new(scopes, grant_permission_callback, consumer_info),
+      client: HttpClientConfig::default().build_async()?,
+    })
+  }
+
+  /// Use `store` as a persistent storage of access token
+  ///
+  /// See `HatenaOauth::with_token_store`. The token is loaded on a blocking thread.
+  pub async fn with_token_store(mut self, store: Box<dyn TokenStore>) -> Result<Self, OauthError> {
+    self.core.token_store = Some(Arc::from(store));
+    if self.core.access_token.is_none() {
+      self.core.access_token = self.run_store(|store| store.load()).await?.flatten();
+    }
+
+    Ok(self)
+  }
+
+  impl_oauth_options!();
+
+  /// Send requests with a client built from `config`
+  ///
+  /// See `HatenaOauth::with_http_config`.
+  pub fn with_http_config(self, config: &HttpClientConfig) -> Result<Self, OauthError> {
+    Ok(self.with_http_client(config.build_async()?))
+  }
+
+  /// Send requests with `client`, eg. to share a connection pool with other clients
+  pub fn with_http_client(mut self, client: reqwest::Client) -> Self {
+    self.client = client;
+    self
+  }
+
+  /// HTTP client this client sends requests with
+  pub fn http_client(&self) -> &reqwest::Client {
+    &self.client
+  }
+
+  /// Check that the access token has any of `required` scopes before calling an API
+  ///
+  /// See `HatenaOauth::require_scopes`.
+  ///
+  /// # Arguments
+  ///
+  /// * `required` - Scopes any of which the API needs
+  pub async fn require_scopes(&mut self, required: &[OauthScope]) -> Result<(), OauthError> {
+    if self.core.check_scopes(required)? {
+      self.get_access_token(true).await?;
+    }
+
+    Ok(())
+  }
+
+  /// Discard the cached access token, removing it from the token store too.
+  pub async fn clear_access_token(&mut self) -> Result<(), OauthError> {
+    self.core.access_token = None;
+    self.run_store(|store| store.clear()).await?;
+
+    Ok(())
+  }
+
+  /// Build a request signed with OAuth Access Token
+  ///
+  /// See `HatenaOauth::request`.
+  ///
+  /// # Arguments
+  ///
+  /// * `method` - HTTP method of the request
+  /// * `url` - URL to send the request
+  pub fn request(&mut self, method: Method, url: &str) -> AsyncSignedRequestBuilder<'_> {
+    AsyncSignedRequestBuilder {
+      oauth: self,
+      request: HatenaRequest::new(method, url),
+      force: false,
+    }
+  }
+
+  /// Send GET request with OAuth Acess Token
+  ///
+  /// If access token is not cached, it first fetches access token.
+  ///
+  /// # Arguments
+  ///
+  /// * `url` - URL to send GET request
+  /// * `force` - If true, it fetches access token even if it is cached
+  pub async fn get(&mut self, url: &str, force: bool) -> Result<Response, OauthError> {
+    self.request(Method::GET, url).force(force).send().await
+  }
+
+  /// Send POST request with OAuth Acess Token
+  ///
+  /// If access token is not cached, it first fetches access token.
+  ///
+  /// # Arguments
+  ///
+  /// * `url` - URL to send POST request
+  /// * `body` - body of POST request to send
+  /// * `force` - If true, it fetches access token even if it is cached
+  /// * `timeout` - Timeout in seconds
+  pub async fn post(
+    &mut self,
+    url: &str,
+    body: &str,
+    force: bool,
+    timeout: u64,
+  ) -> Result<Response, OauthError> {
+    self
+      .request(Method::POST, url)
+      .force(force)
+      .timeout(Duration::from_secs(timeout))
+      .body(body)
+      .send()
+      .await
+  }
+
+  /// Get the profile of the user who granted the access token
+  ///
+  /// It can also be used as a cheap check that the access token is still valid.
+  /// `url_name` and `display_name` of the cached access token are updated with the result.
+  ///
+  /// # Arguments
+  ///
+  /// * `force` - If true, it fetches access token even if it is cached
+  pub async fn me(&mut self, force: bool) -> Result<HatenaUser, OauthError> {
+    let url = self.core.endpoints.oauth_user_info.clone();
+    let res = self.request(Method::GET, &url).force(force).send().await?;
+    if res.status() != 200 {
+      return Err(async_response_error(res).await?);
+    }
+    let user = HatenaUser::from(&res.text().await?)?;
+    if let Some(access_token) = &mut self.core.access_token {
+      access_token.url_name = user.url_name.clone();
+      access_token.display_name = user.display_name.clone();
+    }
+
+    Ok(user)
+  }
+
+  /// Get an access token for pre-defined scopes.
+  ///
+  /// This function would open a browser and wait for a user to grant a permission.
+  ///
+  /// # Arguments
+  ///
+  /// * `force` - If true, this function would request a new access token even if the access token is already cached.
+  pub async fn get_access_token(&mut self, force: bool) -> Result<AccessTokenResponse, OauthError> {
+    // Use cached access token if exists
+    if let Some(access_token) = self.core.access_token.as_ref().filter(|_| !force) {
+      return Ok(access_token.clone());
+    }
+
+    // Use cached request token and verifier if exists
+    if self.core.needs_verifier(force) {
+      self.get_request_token(OAUTH_CALLBACK_OOB).await?;
+      let task = self.core.grant_permission_task();
+      self.core.verifier = Some(tokio::task::spawn_blocking(task).await??);
+    }
+
+    self.fetch_access_token().await
+  }
+
+  /// Get an access token for pre-defined scopes, capturing the verifier by a loopback server.
+  ///
+  /// See `HatenaOauth::get_access_token_loopback`.
+  ///
+  /// # Arguments
+  ///
+  /// * `timeout` - How long to wait for a user to grant a permission
+  pub async fn get_access_token_loopback(
+    &mut self,
+    timeout: Duration,
+  ) -> Result<AccessTokenResponse, OauthError> {
+    let server = LoopbackServer::bind()?;
+    self.get_request_token(server.url()).await?;
+    let task = self.core.grant_permission_loopback_task(server, timeout);
+    self.core.verifier = Some(tokio::task::spawn_blocking(task).await??);
+
+    self.fetch_access_token().await
+  }
+
+  /// Run `f` with the token store on a blocking thread, since stores read files and derive keys.
+  /// Returns `None` if no store is set.
+  async fn run_store<T: Send + 'static>(
+    &self,
+    f: impl FnOnce(&dyn TokenStore) -> Result<T, OauthError> + Send + 'static,
+  ) -> Result<Option<T>, OauthError> {
+    match self.core.token_store.clone() {
+      Some(store) => Ok(Some(
+        tokio::task::spawn_blocking(move || f(store.as_ref())).await??,
+      )),
+      None => Ok(None),
+    }
+  }
+
+  async fn fetch_access_token(&mut self) -> Result<AccessTokenResponse, OauthError> {
+    let access_token = match self.request_access_token().await {
+      Err(err) if self.core.adjust_clock(&err) => self.request_access_token().await?,
+      res => res?,
+    };
+    let access_token = self.core.granted(access_token);
+    let saved = access_token.clone();
+    self.run_store(move |store| store.save(&saved)).await?;
+    self.core.access_token = Some(access_token.clone());
+
+    Ok(access_token)
+  }
+
+  async fn request_access_token(&self) -> Result<AccessTokenResponse, OauthError> {
+    let authorization = access_token_authorization(
+      &self.core.endpoints,
+      &self.core.consumer_signer(),
+      self.core.request_token.as_ref().unwrap(),
+      self.core.verifier.as_ref().unwrap(),
+    );
+    let res = self
+      .client
+      .post(&self.core.endpoints.oauth_access_token)
+      .header(AUTHORIZATION, authorization)
+      .send()
+      .await?;
+
+    if res.status() == 200 {
+      AccessTokenResponse::from(&res.text().await?)
+    } else {
+      Err(async_response_error(res).await?)
+    }
+  }
+
+  async fn get_request_token(&mut self, callback: &str) -> Result<(), OauthError> {
+    self.core.request_token = Some(match self.request_request_token(callback).await {
+      Err(err) if self.core.adjust_clock(&err) => self.request_request_token(callback).await?,
+      res => res?,
+    });
+
+    Ok(())
+  }
+
+  async fn request_request_token(&self, callback: &str) -> Result<OauthTokenResponse, OauthError> {
+    let (authorization, body) = request_token_request(
+      &self.core.endpoints,
+      &self.core.scopes,
+      &self.core.consumer_signer(),
+      callback,
+    );
+    let res = self
+      .client
+      .post(&self.core.endpoints.oauth_request_token)
+      .header(AUTHORIZATION, authorization)
+      .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+      .body(body)
+      .send()
+      .await?;
+
+    if res.status() == 200 {
+      OauthTokenResponse::from(&res.text().await?)
+    } else {
+      Err(async_response_error(res).await?)
+    }
+  }
+
+  /// Signer with the access token, fetching it first if not cached or `force` is true
+  async fn access_request_token(&mut self, force: bool) -> Result<RequestToken, OauthError> {
+    if force || self.core.access_token.is_none() {
+      self.get_access_token(true).await?;
+    }
+
+    Ok(self.core.access_signer())
+  }
+
+  /// Recover from `err` reported by a response, so that the failed request can be retried once.
+  ///
+  /// See `HatenaOauth::recover`.
+  pub(crate) async fn recover(&mut self, err: OauthError) -> Result<(), OauthError> {
+    if matches!(err, OauthError::TokenRejected { .. }) {
+      self.clear_access_token().await?;
+    }
+    self.core.recover(err)
+  }
+}
+
+impl AsyncAuthenticator for AsyncHatenaOauth {
+  /// Sign `request` with the access token, fetching it first if not cached
+  async fn authenticate(&mut self, request: &mut HatenaRequest) -> Result<(), AuthError> {
+    let req_token = self.access_request_token(false).await?;
+    Ok(request.sign(&req_token)?)
+  }
+
+  async fn username(&mut self) -> Result<Option<String>, AuthError> {
+    Ok(Some(self.get_access_token(false).await?.url_name))
+  }
+
+  async fn require_scopes(&mut self, required: &[OauthScope]) -> Result<(), AuthError> {
+    Ok(AsyncHatenaOauth::require_scopes(self, required).await?)
+  }
+
+  /// Retry if the response reports a revoked token or a refused timestamp
+  async fn retry(
+    &mut self,
+    status: StatusCode,
+    headers: &HeaderMap,
+    body: &[u8],
+  ) -> Result<bool, AuthError> {
+    match parse_problem(status, headers, body) {
+      Some(err) => {
+        self.recover(err).await?;
+        Ok(true)
+      }
+      None => Ok(false),
+    }
+  }
+}
+
+/// Builder of a request signed with the access token of `AsyncHatenaOauth`
+///
+/// Created by `AsyncHatenaOauth::request`. See `SignedRequestBuilder`.
+pub struct AsyncSignedRequestBuilder<'a> {
+  oauth: &'a mut AsyncHatenaOauth,
+  request: HatenaRequest,
+  force: bool,
+}
+
+impl<'a> AsyncSignedRequestBuilder<'a> {
+  impl_request_options!();
+
+  /// Sign and send the request
+  ///
+  /// See `SignedRequestBuilder::send`.
+  pub async fn send(mut self) -> Result<Response, OauthError> {
+    let mut force = self.force;
+    let mut retried = false;
+    loop {
+      let res = self.send_once(force).await?;
+      if !is_problem_status(res.status()) {
+        return Ok(res);
+      }
+
+      let (status, version, headers) = (res.status(), res.version(), res.headers().clone());
+      let body = res.bytes().await?.to_vec();
+      match problem_to_recover(status, &headers, &body, retried)? {
+        Some(err) => self.oauth.recover(err).await?,
+        None => return Ok(rebuild_response(status, version, headers, body)),
+      }
+      force = false;
+      retried = true;
+    }
+  }
+
+  async fn send_once(&mut self, force: bool) -> Result<Response, OauthError> {
+    let req_token = self.oauth.access_request_token(force).await?;
+    self.request.sign(&req_token)?;
+
+    Ok(self.request.to_async(&self.oauth.client)?.send().await?)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::endpoints::HatenaEndpoints;
+  use crate::oauth::clock::FixedClock;
+  use crate::test_util::*;
+
+  fn stub_oauth(base: &str) -> AsyncHatenaOauth {
+    let consumer_info = HatenaConsumerInfo::new("key", "secret").unwrap();
+    let mut oauth = AsyncHatenaOauth::new(
+      vec![OauthScope::ReadPublic],
+      Some(Box::new(|_| Ok("verifier".to_string()))),
+      consumer_info,
+    )
+    .unwrap()
+    .with_browser_launcher(None)
+    .with_endpoints(HatenaEndpoints::from_base_url(base));
+    oauth.core.access_token = Some(AccessTokenResponse {
+      oauth_token: "revoked".into(),
+      oauth_token_secret: "revoked_secret".into(),
+      ..sample_token()
+    });
+    oauth
+  }
+
+  #[tokio::test]
+  async fn test_async_token_store_reuse() {
+    let consumer_info = HatenaConsumerInfo::new("key", "secret").unwrap();
+    let store = MemoryTokenStore::with_token(sample_token());
+    let mut oauth =
+      AsyncHatenaOauth::new(vec![OauthScope::ReadPublic], None, consumer_info).unwrap();
+    // Not to use tokens given by environment variables
+    oauth.core.access_token = None;
+    let mut oauth = oauth
+      .with_token_store(Box::new(store.clone()))
+      .await
+      .unwrap();
+    let token = oauth.get_access_token(false).await.unwrap();
+    assert_eq!(token.oauth_token, "token");
+
+    oauth.clear_access_token().await.unwrap();
+    assert!(store.load().unwrap().is_none());
+  }
+
+  #[tokio::test]
+  async fn test_async_signed_request() {
+    let (base, server) = spawn_stub_server(vec![response("200 OK", &[], "ok")]);
+    let mut oauth = stub_oauth(&base);
+
+    let res = oauth
+      .request(Method::PUT, &format!("{}/atom/edit/1", base))
+      .query(&[("a", "b c")])
+      .header("Content-Type", "application/atom+xml")
+      .body("<entry />")
+      .send()
+      .await
+      .unwrap();
+    assert_eq!(res.text().await.unwrap(), "ok");
+
+    let requests = server.join().unwrap();
+    assert_eq!(requests[0].request_line, "PUT /atom/edit/1?a=b+c HTTP/1.1");
+    assert_eq!(
+      requests[0].header("content-type"),
+      Some("application/atom+xml")
+    );
+    assert!(requests[0]
+      .header("authorization")
+      .unwrap()
+      .contains("oauth_token=\"revoked\""));
+  }
+
+  #[tokio::test]
+  async fn test_async_revoked_token_reauthorize() {
+    let (base, server) = spawn_stub_server(vec![
+      response("401 Unauthorized", &[], "oauth_problem=token_rejected"),
+      response(
+        "200 OK",
+        &[],
+        "oauth_token=req&oauth_token_secret=req_secret&oauth_callback_confirmed=true",
+      ),
+      response(
+        "200 OK",
+        &[],
+        "oauth_token=new&oauth_token_secret=new_secret&url_name=smallkirby&display_name=kirby",
+      ),
+      response("200 OK", &[], "ok"),
+    ]);
+    let store = MemoryTokenStore::new();
+    let mut oauth = stub_oauth(&base)
+      .with_token_store(Box::new(store.clone()))
+      .await
+      .unwrap();
+
+    let res = oauth
+      .get(&format!("{}/atom/feed", base), false)
+      .await
+      .unwrap();
+    assert_eq!(res.text().await.unwrap(), "ok");
+    assert_eq!(oauth.core.access_token.as_ref().unwrap().oauth_token, "new");
+    assert_eq!(store.load().unwrap().unwrap().oauth_token, "new");
+
+    let requests = server.join().unwrap();
+    assert_eq!(requests[1].request_line, "POST /oauth/initiate HTTP/1.1");
+    assert_eq!(requests[2].request_line, "POST /oauth/token HTTP/1.1");
+    assert!(requests[3]
+      .header("authorization")
+      .unwrap()
+      .contains("oauth_token=\"new\""));
+  }
+
+  #[tokio::test]
+  async fn test_async_revoked_token_non_interactive() {
+    let (base, server) = spawn_stub_server(vec![response(
+      "401 Unauthorized",
+      &[],
+      "oauth_problem=token_revoked",
+    )]);
+    let store = MemoryTokenStore::new();
+    let mut oauth = stub_oauth(&base)
+      .with_token_store(Box::new(store.clone()))
+      .await
+      .unwrap()
+      .with_interactive(false);
+    store
+      .save(oauth.core.access_token.as_ref().unwrap())
+      .unwrap();
+
+    let res = oauth.get(&format!("{}/atom/feed", base), false).await;
+    assert!(matches!(res, Err(OauthError::TokenRevoked { problem }) if problem == "token_revoked"));
+    assert!(oauth.core.access_token.is_none());
+    assert!(store.load().unwrap().is_none());
+    server.join().unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_async_timestamp_refused_adjusts_clock() {
+    let (base, server) = spawn_stub_server(vec![
+      response(
+        "401 Unauthorized",
+        &[],
+        "oauth_problem=timestamp_refused&oauth_acceptable_timestamps=1900-2100",
+      ),
+      response("200 OK", &[], "ok"),
+    ]);
+    let mut oauth = stub_oauth(&base).with_clock(Arc::new(FixedClock(1000)));
+
+    let res = oauth
+      .get(&format!("{}/atom/feed", base), false)
+      .await
+      .unwrap();
+    assert_eq!(res.text().await.unwrap(), "ok");
+    assert_eq!(oauth.clock_offset(), 1000);
+
+    let requests = server.join().unwrap();
+    assert!(requests[1]
+      .header("authorization")
+      .unwrap()
+      .contains("oauth_timestamp=\"2000\""));
+  }
+}
@@ -0,0 +1,359 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use url::form_urlencoded;
+
+use crate::oauth::error::*;
+use crate::oauth::token::*;
+
+/// Path of the loopback server which Hatena redirects a user to
+pub const CALLBACK_PATH: &str = "/callback";
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// How long to wait for a request on each connection
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Information about a pending authorization, passed to callbacks
+#[derive(Debug, Clone)]
+pub struct AuthorizeRequest {
+  /// URL of the authorization endpoint to which a user should be redirected
+  pub authorize_url: String,
+  /// Request token which a user is asked to authorize
+  pub request_token: OauthTokenResponse,
+}
+
+/// Callback after redirecting user to grant permission, which returns `oauth_verifier`
+pub type GrantPermissionCallback =
+  Box<dyn FnMut(&AuthorizeRequest) -> Result<String, OauthError> + Send>;
+
+/// Hook to redirect a user to the authorization URL (eg. opening a browser)
+pub type BrowserLauncher = Box<dyn FnMut(&str) -> Result<(), OauthError> + Send>;
+
+/// Browser launcher which opens the URL with the default browser
+///
+/// It fails with `BrowserUnavailable` if no browser can be opened (eg. on a server without display).
+pub fn default_browser_launcher() -> BrowserLauncher {
+  Box::new(|url| webbrowser::open(url).map_err(|_| OauthError::BrowserUnavailable))
+}
+
+/// Short-lived HTTP listener on 127.0.0.1 which receives the redirect from the authorization endpoint.
+///
+/// Pass `url()` as `oauth_callback` when requesting a request token, then `wait_for_verifier` captures `oauth_verifier`.
+pub struct LoopbackServer {
+  listener: TcpListener,
+  url: String,
+}
+
+impl LoopbackServer {
+  /// Start listening on an ephemeral port of 127.0.0.1
+  pub fn bind() -> Result<Self, OauthError> {
+    Self::bind_port(0)
+  }
+
+  /// Start listening on `port` of 127.0.0.1
+  ///
+  /// # Arguments
+  ///
+  /// * `port` - Port to listen on. If 0, an ephemeral port is assigned.
+  pub fn bind_port(port: u16) -> Result<Self, OauthError> {
+    let listener =
+      TcpListener::bind((Ipv4Addr::LOCALHOST, port)).map_err(OauthError::CallbackServerFailure)?;
+    let port = listener
+      .local_addr()
+      .map_err(OauthError::CallbackServerFailure)?
+      .port();
+
+    Ok(Self {
+      listener,
+      url: format!("http://{}:{}{}", Ipv4Addr::LOCALHOST, port, CALLBACK_PATH),
+    })
+  }
+
+  /// URL to be passed as `oauth_callback`
+  pub fn url(&self) -> &str {
+    &self.url
+  }
+
+  /// Wait for the redirect from the authorization endpoint and return `oauth_verifier`.
+  ///
+  /// Connections which fail, lack `oauth_verifier`, or carry an `oauth_token` other than the one of `token`
+  /// are answered and dropped, and it keeps waiting until `timeout`. Only an explicit `denied` parameter for `token`
+  /// fails with `PermissionDeniedUser`. It then fails with `CallbackTokenMismatch` if such a redirect came,
+  /// otherwise `CallbackTimeout`.
+  ///
+  /// # Arguments
+  ///
+  /// * `token` - A request token returned from request endpoint
+  /// * `timeout` - How long to wait for the redirect
+  pub fn wait_for_verifier(
+    &self,
+    token: &OauthTokenResponse,
+    timeout: Duration,
+  ) -> Result<String, OauthError> {
+    let deadline = Instant::now() + timeout;
+    let mut mismatched = false;
+    self
+      .listener
+      .set_nonblocking(true)
+      .map_err(OauthError::CallbackServerFailure)?;
+
+    loop {
+      let now = Instant::now();
+      if now >= deadline {
+        return Err(if mismatched {
+          OauthError::CallbackTokenMismatch
+        } else {
+          OauthError::CallbackTimeout
+        });
+      }
+      let stream = match self.listener.accept() {
+        Ok((stream, _)) => stream,
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+          thread::sleep(POLL_INTERVAL);
+          continue;
+        }
+        Err(e) => return Err(OauthError::CallbackServerFailure(e)),
+      };
+
+      // A connection without a request (eg. preconnect of a browser) must not block the others for long
+      let read_timeout = CONNECTION_TIMEOUT.min(deadline - now);
+      match handle_callback(stream, token, read_timeout) {
+        Ok(Callback::Verifier(verifier)) => return Ok(verifier),
+        Ok(Callback::Denied) => return Err(OauthError::PermissionDeniedUser),
+        Ok(Callback::Mismatch) => mismatched = true,
+        // Requests other than the callback (eg. favicon or reload), and connections which failed
+        Ok(Callback::Ignored) | Err(_) => {}
+      }
+    }
+  }
+}
+
+/// Result of a connection to the loopback server
+enum Callback {
+  Verifier(String),
+  /// The user denied the authorization, which is told by `denied` parameter
+  Denied,
+  /// `oauth_token` is not the one being authorized
+  Mismatch,
+  Ignored,
+}
+
+fn handle_callback(
+  mut stream: TcpStream,
+  token: &OauthTokenResponse,
+  read_timeout: Duration,
+) -> io::Result<Callback> {
+  stream.set_nonblocking(false)?;
+  stream.set_read_timeout(Some(read_timeout))?;
+
+  let mut reader = BufReader::new(&stream);
+  let mut request_line = String::new();
+  reader.read_line(&mut request_line)?;
+  // Drain headers
+  loop {
+    let mut line = String::new();
+    let n = reader.read_line(&mut line)?;
+    if n == 0 || line == "\r\n" || line == "\n" {
+      break;
+    }
+  }
+
+  let target = request_line.split_whitespace().nth(1).unwrap_or("");
+  let (path, query) = target.split_once('?').unwrap_or((target, ""));
+  if path != CALLBACK_PATH {
+    respond(&mut stream, "404 Not Found", "Not Found")?;
+    return Ok(Callback::Ignored);
+  }
+
+  let mut oauth_token = None;
+  let mut oauth_verifier = None;
+  let mut denied = None;
+  for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+    match key.as_ref() {
+      "oauth_token" => oauth_token = Some(value.into_owned()),
+      "oauth_verifier" => oauth_verifier = Some(value.into_owned()),
+      "denied" => denied = Some(value.into_owned()),
+      _ => {}
+    }
+  }
+
+  if let Some(denied) = denied {
+    // `denied` carries the request token, but accept `oauth_token` as well
+    let ours = denied == token.oauth_token || oauth_token.as_deref() == Some(&token.oauth_token);
+    return if ours {
+      respond(
+        &mut stream,
+        "200 OK",
+        "Authorization was not granted. You can close this window.",
+      )?;
+      Ok(Callback::Denied)
+    } else {
+      respond(&mut stream, "400 Bad Request", "Unknown request token.")?;
+      Ok(Callback::Mismatch)
+    };
+  }
+
+  match (oauth_token, oauth_verifier) {
+    (Some(oauth_token), Some(oauth_verifier)) if oauth_token == token.oauth_token => {
+      respond(
+        &mut stream,
+        "200 OK",
+        "Authorization completed. You can close this window.",
+      )?;
+      Ok(Callback::Verifier(oauth_verifier))
+    }
+    (Some(_), Some(_)) => {
+      respond(&mut stream, "400 Bad Request", "Unknown request token.")?;
+      Ok(Callback::Mismatch)
+    }
+    _ => {
+      respond(&mut stream, "400 Bad Request", "Missing oauth_verifier.")?;
+      Ok(Callback::Ignored)
+    }
+  }
+}
+
+fn respond(stream: &mut TcpStream, status: &str, message: &str) -> io::Result<()> {
+  let body = format!("<html><body><p>{}</p></body></html>", message);
+  write!(
+    stream,
+    "HTTP/1.1 {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+    status,
+    body.len(),
+    body,
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Stand-in for Hatena's authorize endpoint, which redirects a browser back to `callback`.
+  fn spawn_authorize_endpoint(callback: String, verifier: &'static str) -> String {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let url = format!("http://{}/oauth/authorize", listener.local_addr().unwrap());
+
+    thread::spawn(move || {
+      let (mut stream, _) = listener.accept().unwrap();
+      let mut request_line = String::new();
+      BufReader::new(&stream)
+        .read_line(&mut request_line)
+        .unwrap();
+      let target = request_line.split_whitespace().nth(1).unwrap();
+      let query = target.split_once('?').unwrap().1;
+      let location = format!("{}?{}&oauth_verifier={}", callback, query, verifier);
+      write!(
+        stream,
+        "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        location,
+      )
+      .unwrap();
+    });
+
+    url
+  }
+
+  fn request_token(oauth_token: &str) -> OauthTokenResponse {
+    OauthTokenResponse {
+      oauth_token: oauth_token.into(),
+      oauth_token_secret: "secret".into(),
+      extra: Default::default(),
+    }
+  }
+
+  fn open_browser(url: String) -> thread::JoinHandle<reqwest::StatusCode> {
+    thread::spawn(move || {
+      let client = reqwest::blocking::Client::builder()
+        .no_proxy()
+        .build()
+        .unwrap();
+      client.get(url).send().unwrap().status()
+    })
+  }
+
+  #[test]
+  fn test_loopback_round_trip() {
+    let server = LoopbackServer::bind().unwrap();
+    let authorize_url = spawn_authorize_endpoint(server.url().into(), "verifier+1");
+    let browser = open_browser(format!("{}?oauth_token=req%2Ftoken", authorize_url));
+
+    let verifier = server
+      .wait_for_verifier(&request_token("req/token"), Duration::from_secs(10))
+      .unwrap();
+    assert_eq!(verifier, "verifier 1");
+    assert_eq!(browser.join().unwrap(), reqwest::StatusCode::OK);
+  }
+
+  #[test]
+  fn test_loopback_token_mismatch() {
+    let server = LoopbackServer::bind().unwrap();
+    let authorize_url = spawn_authorize_endpoint(server.url().into(), "verifier");
+    let browser = open_browser(format!("{}?oauth_token=forged", authorize_url));
+
+    let result = server.wait_for_verifier(&request_token("genuine"), Duration::from_secs(1));
+    assert!(matches!(result, Err(OauthError::CallbackTokenMismatch)));
+    assert_eq!(browser.join().unwrap(), reqwest::StatusCode::BAD_REQUEST);
+  }
+
+  #[test]
+  fn test_loopback_survives_stray_connections() {
+    let server = LoopbackServer::bind().unwrap();
+    let callback_addr = server.url().trim_end_matches(CALLBACK_PATH).to_string();
+    // Preconnect which never sends a request
+    let idle = TcpStream::connect(callback_addr.trim_start_matches("http://")).unwrap();
+    let forged_url = format!("{}?oauth_token=forged&oauth_verifier=v", server.url());
+    let authorize_url = spawn_authorize_endpoint(server.url().into(), "verifier");
+    // The genuine redirect comes after the forged one is answered
+    let browser = thread::spawn(move || {
+      let forged = open_browser(forged_url).join().unwrap();
+      let genuine = open_browser(format!("{}?oauth_token=genuine", authorize_url));
+      (forged, genuine.join().unwrap())
+    });
+
+    let verifier = server
+      .wait_for_verifier(&request_token("genuine"), Duration::from_secs(10))
+      .unwrap();
+    assert_eq!(verifier, "verifier");
+    assert_eq!(
+      browser.join().unwrap(),
+      (reqwest::StatusCode::BAD_REQUEST, reqwest::StatusCode::OK)
+    );
+    drop(idle);
+  }
+
+  #[test]
+  fn test_loopback_malformed_and_denied() {
+    let server = LoopbackServer::bind().unwrap();
+    let callback_url = server.url().to_string();
+    let browser = thread::spawn(move || {
+      let reload = open_browser(callback_url.clone()).join().unwrap();
+      let stray = open_browser(format!("{}?oauth_token=genuine", callback_url))
+        .join()
+        .unwrap();
+      let denied = open_browser(format!("{}?denied=genuine", callback_url))
+        .join()
+        .unwrap();
+      (reload, stray, denied)
+    });
+
+    let result = server.wait_for_verifier(&request_token("genuine"), Duration::from_secs(10));
+    assert!(matches!(result, Err(OauthError::PermissionDeniedUser)));
+    assert_eq!(
+      browser.join().unwrap(),
+      (
+        reqwest::StatusCode::BAD_REQUEST,
+        reqwest::StatusCode::BAD_REQUEST,
+        reqwest::StatusCode::OK
+      )
+    );
+  }
+
+  #[test]
+  fn test_loopback_timeout() {
+    let server = LoopbackServer::bind().unwrap();
+    let result = server.wait_for_verifier(&request_token("token"), Duration::from_millis(100));
+    assert!(matches!(result, Err(OauthError::CallbackTimeout)));
+  }
+}
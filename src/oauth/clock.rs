@@ -0,0 +1,84 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+
+/// Source of `oauth_timestamp`
+pub trait Clock: Send + Sync {
+  /// Current time in seconds since the Unix epoch
+  fn now(&self) -> i64;
+}
+
+/// Clock which reads the system time
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+  fn now(&self) -> i64 {
+    Utc::now().timestamp()
+  }
+}
+
+/// Clock which always returns the same time
+#[derive(Debug, Clone, Copy)]
+pub struct FixedClock(pub i64);
+
+impl Clock for FixedClock {
+  fn now(&self) -> i64 {
+    self.0
+  }
+}
+
+/// Clock which shifts another clock by an offset, used to follow the server time
+#[derive(Clone)]
+pub struct OffsetClock {
+  inner: Arc<dyn Clock>,
+  offset: i64,
+}
+
+impl OffsetClock {
+  /// # Arguments
+  ///
+  /// * `inner` - Clock to be shifted
+  /// * `offset` - Seconds added to the time of `inner`
+  pub fn new(inner: Arc<dyn Clock>, offset: i64) -> Self {
+    Self { inner, offset }
+  }
+}
+
+impl Clock for OffsetClock {
+  fn now(&self) -> i64 {
+    self.inner.now() + self.offset
+  }
+}
+
+/// Source of `oauth_nonce`
+pub trait NonceSource: Send + Sync {
+  /// Generate a nonce unique to a request
+  fn nonce(&self) -> String;
+}
+
+/// Nonce source which generates 32 random alphanumeric characters
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RandomNonce;
+
+impl NonceSource for RandomNonce {
+  fn nonce(&self) -> String {
+    thread_rng()
+      .sample_iter(&Alphanumeric)
+      .take(32)
+      .map(char::from)
+      .collect()
+  }
+}
+
+/// Nonce source which always returns the same nonce
+#[derive(Debug, Clone)]
+pub struct FixedNonce(pub String);
+
+impl NonceSource for FixedNonce {
+  fn nonce(&self) -> String {
+    self.0.clone()
+  }
+}
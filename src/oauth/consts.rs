@@ -1,12 +1,17 @@
 use std::fmt;
+use std::str::FromStr;
 
 use percent_encoding::AsciiSet;
+use serde::Deserialize;
 
+use crate::oauth::error::OauthError;
+
+/// Characters to be percent-encoded in OAuth (RFC 5849 section 3.6), all but unreserved ones
 pub static STRICT_ENCODE_SET: &AsciiSet = &percent_encoding::NON_ALPHANUMERIC
-  .remove(b'*')
   .remove(b'-')
   .remove(b'.')
-  .remove(b'_');
+  .remove(b'_')
+  .remove(b'~');
 
 pub const ENV_CONSUMER_KEY: &str = "HATENA_CONSUMER_KEY";
 pub const ENV_CONSUMER_SECRET: &str = "HATENA_CONSUMER_SECRET";
@@ -16,10 +21,16 @@ pub const ENV_OAUTH_ACCESS_SECRET: &str = "HATENA_OAUTH_ACCESS_SECRET";
 pub const ENV_OAUTH_URL_NAME: &str = "HATENA_OAUTH_URL_NAME";
 
 pub const OAUTH_URL_REQUEST_TOKEN: &str = "https://www.hatena.com/oauth/initiate";
-//pub const OAUTH_URL_REQUEST_TOKEN: &str = "http://localhost:49494/oauth/initiate";
 pub const OAUTH_URL_GRANT_PERMISSION: &str = "https://www.hatena.com/oauth/authorize";
 pub const OAUTH_URL_ACCESS_TOKEN: &str = "https://www.hatena.com/oauth/token";
+pub const OAUTH_URL_USER_INFO: &str = "https://n.hatena.com/applications/my.json";
+
+/// `oauth_callback` value for out-of-band verification
+pub const OAUTH_CALLBACK_OOB: &str = "oob";
 
+/// Scope of Hatena OAuth, which is written as `read_public` and so on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum OauthScope {
   ReadPublic,
   ReadPrivate,
@@ -37,3 +48,63 @@ impl fmt::Display for OauthScope {
     }
   }
 }
+
+impl FromStr for OauthScope {
+  type Err = OauthError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.trim() {
+      "read_public" => Ok(OauthScope::ReadPublic),
+      "read_private" => Ok(OauthScope::ReadPrivate),
+      "write_public" => Ok(OauthScope::WritePublic),
+      "write_private" => Ok(OauthScope::WritePrivate),
+      _ => Err(OauthError::InvalidScope {
+        scope: s.to_string(),
+      }),
+    }
+  }
+}
+
+impl OauthScope {
+  /// Parse comma-separated scopes (eg. `read_public,write_public`), which is the format of `scope` parameter
+  pub fn parse_list(s: &str) -> Result<Vec<Self>, OauthError> {
+    let mut scopes = s
+      .split(',')
+      .filter(|scope| !scope.trim().is_empty())
+      .map(|scope| scope.parse())
+      .collect::<Result<Vec<Self>, OauthError>>()?;
+    scopes.sort();
+    scopes.dedup();
+
+    Ok(scopes)
+  }
+
+  /// Join scopes with commas, which `parse_list` can parse
+  pub fn join_list(scopes: &[Self]) -> String {
+    scopes
+      .iter()
+      .map(|s| s.to_string())
+      .collect::<Vec<String>>()
+      .join(",")
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_scope_list() {
+    let scopes = OauthScope::parse_list("write_public, read_public,read_public").unwrap();
+    assert_eq!(
+      scopes,
+      vec![OauthScope::ReadPublic, OauthScope::WritePublic]
+    );
+    assert_eq!(OauthScope::join_list(&scopes), "read_public,write_public");
+    assert!(OauthScope::parse_list("").unwrap().is_empty());
+    assert!(matches!(
+      "write_all".parse::<OauthScope>(),
+      Err(OauthError::InvalidScope { .. })
+    ));
+  }
+}
@@ -0,0 +1,313 @@
+use std::fs;
+use std::io;
+use std::num::NonZeroU32;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::constant_time::verify_slices_are_equal;
+use ring::pbkdf2::{self, PBKDF2_HMAC_SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+use zeroize::Zeroizing;
+
+use crate::oauth::error::*;
+use crate::oauth::store::*;
+use crate::oauth::token::*;
+use crate::secret::SecretString;
+
+/// Name of the file used by `EncryptedTokenStore` by default
+pub const SEALED_TOKEN_FILE_NAME: &str = "access_token.sealed";
+
+/// Default number of PBKDF2 iterations
+pub const DEFAULT_PBKDF2_ITERATIONS: u32 = 100_000;
+
+/// Numbers of PBKDF2 iterations accepted when sealing and loading
+///
+/// A file claiming iterations out of this range is rejected as tampered, rather than spending minutes on it.
+pub const PBKDF2_ITERATIONS_RANGE: RangeInclusive<u32> = 1_000..=10_000_000;
+
+/// Magic bytes at the head of a sealed token file
+const MAGIC: &[u8; 8] = b"HRTOKEN1";
+const SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+/// Length of the key-check value, which tells a wrong passphrase from tampering
+const CHECK_LEN: usize = 32;
+const HEADER_LEN: usize = MAGIC.len() + SALT_LEN + 4 + CHECK_LEN + NONCE_LEN;
+
+/// Token store which seals the token with a passphrase
+///
+/// The token is encrypted by AES-256-GCM with a key derived from the passphrase by PBKDF2-HMAC-SHA256.
+/// The file consists of a magic, salt, iterations, key-check value, nonce and ciphertext.
+/// Loading returns `WrongPassphrase` if the key-check value does not match,
+/// and `TokenTampered` if it matches but the ciphertext fails authentication.
+/// The key-check value is derived from the salt and iterations as well as the passphrase, so tampering them
+/// is also reported as `WrongPassphrase`; there is no way to tell the two apart without the passphrase.
+pub struct EncryptedTokenStore {
+  path: PathBuf,
+  passphrase: SecretString,
+  iterations: NonZeroU32,
+}
+
+impl EncryptedTokenStore {
+  /// Create a token store backed by the sealed file at `path`
+  ///
+  /// # Arguments
+  ///
+  /// * `path` - Path to the sealed file
+  /// * `passphrase` - Passphrase to derive the key from
+  pub fn new(path: impl Into<PathBuf>, passphrase: SecretString) -> Self {
+    Self {
+      path: path.into(),
+      passphrase,
+      iterations: NonZeroU32::new(DEFAULT_PBKDF2_ITERATIONS).unwrap(),
+    }
+  }
+
+  /// Create a token store backed by `access_token.sealed` file under the XDG config dir.
+  ///
+  /// It returns `TokenStoreFailure` error if neither `XDG_CONFIG_HOME` nor `HOME` is set.
+  pub fn from_default_path(passphrase: SecretString) -> Result<Self, OauthError> {
+    Ok(Self::new(
+      default_file_path(SEALED_TOKEN_FILE_NAME)?,
+      passphrase,
+    ))
+  }
+
+  /// Use `iterations` of PBKDF2 when sealing. Files sealed before keep their own iterations.
+  ///
+  /// It returns `InvalidIterations` error if `iterations` is out of `PBKDF2_ITERATIONS_RANGE`.
+  pub fn with_iterations(mut self, iterations: u32) -> Result<Self, OauthError> {
+    if !PBKDF2_ITERATIONS_RANGE.contains(&iterations) {
+      return Err(OauthError::InvalidIterations { iterations });
+    }
+    self.iterations = NonZeroU32::new(iterations).unwrap();
+    Ok(self)
+  }
+
+  /// Path to the backing file
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  /// Move the token in `plain` (eg. `FileTokenStore`) into this store, and clear `plain`
+  ///
+  /// It does nothing if `plain` has no token.
+  pub fn lock(&self, plain: &dyn TokenStore) -> Result<(), OauthError> {
+    if let Some(token) = plain.load()? {
+      self.save(&token)?;
+      plain.clear()?;
+    }
+    Ok(())
+  }
+
+  /// Move the token in this store into `plain` (eg. `FileTokenStore`), and clear this store
+  ///
+  /// It does nothing if this store has no token.
+  pub fn unlock(&self, plain: &dyn TokenStore) -> Result<(), OauthError> {
+    if let Some(token) = self.load()? {
+      plain.save(&token)?;
+      self.clear()?;
+    }
+    Ok(())
+  }
+
+  /// Re-seal the stored token with `new_passphrase`, which is used afterwards
+  pub fn rotate(&mut self, new_passphrase: SecretString) -> Result<(), OauthError> {
+    let token = self.load()?;
+    self.passphrase = new_passphrase;
+    if let Some(token) = token {
+      self.save(&token)?;
+    }
+    Ok(())
+  }
+
+  /// Derive the AEAD key and key-check value from the passphrase, `salt` and `iterations`
+  fn derive_key(
+    &self,
+    salt: &[u8],
+    iterations: NonZeroU32,
+  ) -> Zeroizing<[u8; KEY_LEN + CHECK_LEN]> {
+    let mut out = Zeroizing::new([0u8; KEY_LEN + CHECK_LEN]);
+    pbkdf2::derive(
+      PBKDF2_HMAC_SHA256,
+      iterations,
+      salt,
+      self.passphrase.expose_secret().as_bytes(),
+      &mut out[..],
+    );
+    out
+  }
+
+  fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>, OauthError> {
+    let rng = SystemRandom::new();
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    rng.fill(&mut salt).map_err(|_| rng_error())?;
+    rng.fill(&mut nonce).map_err(|_| rng_error())?;
+
+    let derived = self.derive_key(&salt, self.iterations);
+    let mut sealed = MAGIC.to_vec();
+    sealed.extend_from_slice(&salt);
+    sealed.extend_from_slice(&self.iterations.get().to_be_bytes());
+    sealed.extend_from_slice(&derived[KEY_LEN..]);
+    sealed.extend_from_slice(&nonce);
+
+    let mut data = plaintext.to_vec();
+    aead_key(&derived[..KEY_LEN])
+      .seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(&sealed),
+        &mut data,
+      )
+      .map_err(|_| io::Error::other("failed to seal the token"))?;
+    sealed.extend_from_slice(&data);
+
+    Ok(sealed)
+  }
+
+  fn open(&self, sealed: &[u8]) -> Result<Vec<u8>, OauthError> {
+    if sealed.len() < HEADER_LEN + AES_256_GCM.tag_len() || !sealed.starts_with(MAGIC) {
+      return Err(OauthError::TokenTampered);
+    }
+    let (header, ciphertext) = sealed.split_at(HEADER_LEN);
+    let salt = &header[MAGIC.len()..MAGIC.len() + SALT_LEN];
+    let iterations = &header[MAGIC.len() + SALT_LEN..MAGIC.len() + SALT_LEN + 4];
+    let check = &header[MAGIC.len() + SALT_LEN + 4..HEADER_LEN - NONCE_LEN];
+    let nonce = &header[HEADER_LEN - NONCE_LEN..];
+
+    let iterations = u32::from_be_bytes(iterations.try_into().unwrap());
+    if !PBKDF2_ITERATIONS_RANGE.contains(&iterations) {
+      return Err(OauthError::TokenTampered);
+    }
+    let iterations = NonZeroU32::new(iterations).unwrap();
+    let derived = self.derive_key(salt, iterations);
+    verify_slices_are_equal(&derived[KEY_LEN..], check).map_err(|_| OauthError::WrongPassphrase)?;
+
+    let mut data = ciphertext.to_vec();
+    let plaintext = aead_key(&derived[..KEY_LEN])
+      .open_in_place(
+        Nonce::try_assume_unique_for_key(nonce).unwrap(),
+        Aad::from(header),
+        &mut data,
+      )
+      .map_err(|_| OauthError::TokenTampered)?;
+
+    Ok(plaintext.to_vec())
+  }
+}
+
+impl TokenStore for EncryptedTokenStore {
+  fn load(&self) -> Result<Option<AccessTokenResponse>, OauthError> {
+    let sealed = match fs::read(&self.path) {
+      Ok(sealed) => sealed,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+      Err(e) => return Err(e.into()),
+    };
+    let plaintext = SecretString::new(
+      String::from_utf8(self.open(&sealed)?).map_err(|_| OauthError::TokenTampered)?,
+    );
+
+    Ok(Some(AccessTokenResponse::from(plaintext.expose_secret())?))
+  }
+
+  fn save(&self, token: &AccessTokenResponse) -> Result<(), OauthError> {
+    let plaintext = SecretString::new(token.to_form_string());
+    let sealed = self.seal(plaintext.expose_secret().as_bytes())?;
+
+    Ok(write_private(&self.path, &sealed)?)
+  }
+
+  fn clear(&self) -> Result<(), OauthError> {
+    Ok(remove_if_exists(&self.path)?)
+  }
+}
+
+fn aead_key(key: &[u8]) -> LessSafeKey {
+  LessSafeKey::new(UnboundKey::new(&AES_256_GCM, key).unwrap())
+}
+
+fn rng_error() -> OauthError {
+  io::Error::other("failed to generate random bytes").into()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_util::sample_token;
+  use std::env;
+
+  fn store(path: &Path, passphrase: &str) -> EncryptedTokenStore {
+    EncryptedTokenStore::new(path, passphrase.into())
+      .with_iterations(1_000)
+      .unwrap()
+  }
+
+  #[test]
+  fn test_encrypted_token_store() {
+    let dir = env::temp_dir().join(format!("hatena-rs-sealed-{}", std::process::id()));
+    let path = dir.join(SEALED_TOKEN_FILE_NAME);
+    let mut sealed = store(&path, "passphrase");
+    assert!(sealed.load().unwrap().is_none());
+
+    let plain = FileTokenStore::new(dir.join(TOKEN_FILE_NAME));
+    plain.save(&sample_token()).unwrap();
+    sealed.lock(&plain).unwrap();
+    assert!(plain.load().unwrap().is_none());
+    let bytes = fs::read(&path).unwrap();
+    for needle in [&b"token_secret"[..], b"smallkirby"] {
+      assert!(!bytes.windows(needle.len()).any(|window| window == needle));
+    }
+    assert_eq!(sealed.load().unwrap().unwrap().url_name, "smallkirby");
+
+    assert!(matches!(
+      store(&path, "wrong").load(),
+      Err(OauthError::WrongPassphrase)
+    ));
+
+    sealed.rotate("rotated".into()).unwrap();
+    assert!(matches!(
+      store(&path, "passphrase").load(),
+      Err(OauthError::WrongPassphrase)
+    ));
+    assert!(store(&path, "rotated").load().unwrap().is_some());
+
+    let mut bytes = fs::read(&path).unwrap();
+    *bytes.last_mut().unwrap() ^= 1;
+    fs::write(&path, &bytes).unwrap();
+    assert!(matches!(sealed.load(), Err(OauthError::TokenTampered)));
+    fs::write(&path, b"HRTOKEN1").unwrap();
+    assert!(matches!(sealed.load(), Err(OauthError::TokenTampered)));
+
+    sealed.save(&sample_token()).unwrap();
+    let mut bytes = fs::read(&path).unwrap();
+    let iterations = MAGIC.len() + SALT_LEN;
+    bytes[iterations..iterations + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+    fs::write(&path, &bytes).unwrap();
+    assert!(matches!(sealed.load(), Err(OauthError::TokenTampered)));
+
+    sealed.save(&sample_token()).unwrap();
+    let mut bytes = fs::read(&path).unwrap();
+    bytes[MAGIC.len()] ^= 1;
+    fs::write(&path, &bytes).unwrap();
+    assert!(matches!(sealed.load(), Err(OauthError::WrongPassphrase)));
+    assert!(matches!(
+      EncryptedTokenStore::new(&path, "passphrase".into()).with_iterations(100),
+      Err(OauthError::InvalidIterations { iterations: 100 })
+    ));
+
+    sealed.save(&sample_token()).unwrap();
+    sealed.unlock(&plain).unwrap();
+    assert!(sealed.load().unwrap().is_none());
+    assert_eq!(
+      plain
+        .load()
+        .unwrap()
+        .unwrap()
+        .oauth_token_secret
+        .expose_secret(),
+      "token_secret"
+    );
+
+    fs::remove_dir_all(dir).unwrap();
+  }
+}
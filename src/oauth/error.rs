@@ -1,5 +1,8 @@
+use reqwest::StatusCode;
 use thiserror::Error;
 
+use crate::oauth::consts::OauthScope;
+
 #[derive(Debug, Error)]
 pub enum OauthError {
   #[error("request failed")]
@@ -8,12 +11,110 @@ pub enum OauthError {
   #[error("invalid request ({problem:?})")]
   InvalidRequest { problem: String },
 
+  #[error("invalid URL: {url:?}")]
+  InvalidUrl { url: String },
+
   #[error("invalid response format: {response:?}")]
   InvalidResponse { response: String },
 
+  #[error("response lacks field: {field}")]
+  MissingField { field: String },
+
+  #[error("oauth_callback_confirmed of the request token response is not true")]
+  CallbackNotConfirmed,
+
+  #[error("invalid OAuth scope: {scope:?}")]
+  InvalidScope { scope: String },
+
+  #[error("access token lacks any of the scopes {required:?} (granted: {granted:?})")]
+  MissingScope {
+    required: Vec<OauthScope>,
+    granted: Vec<OauthScope>,
+  },
+
+  #[error("OAuth signature is invalid ({status}, advice: {advice:?})")]
+  SignatureInvalid {
+    status: StatusCode,
+    advice: Option<String>,
+  },
+
+  #[error("OAuth timestamp is refused ({status}, advice: {advice:?})")]
+  TimestampRefused {
+    status: StatusCode,
+    advice: Option<String>,
+    /// Range of timestamps the server accepts, if reported
+    acceptable_timestamps: Option<(i64, i64)>,
+    /// Current time of the server, taken from `acceptable_timestamps` or `Date` header
+    server_time: Option<i64>,
+  },
+
+  #[error("OAuth nonce is already used ({status}, advice: {advice:?})")]
+  NonceUsed {
+    status: StatusCode,
+    advice: Option<String>,
+  },
+
+  #[error("OAuth token is rejected: {problem} ({status}, advice: {advice:?})")]
+  TokenRejected {
+    status: StatusCode,
+    problem: String,
+    advice: Option<String>,
+  },
+
+  #[error("OAuth consumer key is unknown ({status}, advice: {advice:?})")]
+  ConsumerKeyUnknown {
+    status: StatusCode,
+    advice: Option<String>,
+  },
+
+  #[error("OAuth problem: {problem} ({status}, advice: {advice:?})")]
+  OauthProblem {
+    status: StatusCode,
+    problem: String,
+    advice: Option<String>,
+  },
+
+  #[error("access token is revoked or expired ({problem})")]
+  TokenRevoked { problem: String },
+
   #[error("HATENA_CONSUMER_KEY or HATENA_CONSUMER_SECRET is not set")]
   InsufficientSecret,
 
   #[error("permission denied by yourself")]
   PermissionDeniedUser,
+
+  #[error("no browser is available to open the authorization URL")]
+  BrowserUnavailable,
+
+  #[error("timed out waiting for the OAuth verifier")]
+  VerifierTimeout,
+
+  #[error("failed to access token store")]
+  TokenStoreFailure(#[from] std::io::Error),
+
+  /// The key-check value does not match the passphrase.
+  ///
+  /// It can also mean that the salt, iterations or key-check value in the header is tampered,
+  /// because the key-check value cannot be verified without the passphrase.
+  #[error("passphrase of the encrypted token store is wrong, or its header is tampered")]
+  WrongPassphrase,
+
+  #[error("PBKDF2 iterations {iterations} is out of the accepted range")]
+  InvalidIterations { iterations: u32 },
+
+  #[error("encrypted token is corrupted or tampered")]
+  TokenTampered,
+
+  #[error("loopback callback server failed")]
+  CallbackServerFailure(std::io::Error),
+
+  #[error("timed out waiting for the OAuth callback")]
+  CallbackTimeout,
+
+  #[error("oauth_token of the callback does not match the request token")]
+  CallbackTokenMismatch,
+
+  #[cfg(feature = "async")]
+  #[error("blocking task of the async client failed")]
+  BlockingTaskFailure(#[from] tokio::task::JoinError),
 }
@@ -0,0 +1,242 @@
+use std::env;
+use std::fs;
+use std::io::BufRead;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::oauth::callback::*;
+use crate::oauth::consts::*;
+use crate::oauth::error::*;
+
+/// Where to read `oauth_verifier` from in headless mode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifierSource {
+  /// A line input to stdin
+  Stdin,
+  /// An environment variable of the name
+  Env(String),
+  /// Contents of the file, which can be written after the prompt (eg. via another SSH session)
+  File(PathBuf),
+}
+
+/// Options of headless authorization
+#[derive(Debug, Clone)]
+pub struct HeadlessOptions {
+  /// Sources polled for the verifier in order
+  pub sources: Vec<VerifierSource>,
+  /// How long to wait for the verifier. If `None`, it waits forever.
+  pub timeout: Option<Duration>,
+  /// Interval to poll environment variables and files
+  pub poll_interval: Duration,
+}
+
+impl Default for HeadlessOptions {
+  /// Read the verifier from `HATENA_OAUTH_VERIFIER` or stdin, waiting for 5 minutes
+  fn default() -> Self {
+    Self {
+      sources: vec![
+        VerifierSource::Env(ENV_OAUTH_VERIFIER.into()),
+        VerifierSource::Stdin,
+      ],
+      timeout: Some(Duration::from_secs(300)),
+      poll_interval: Duration::from_millis(200),
+    }
+  }
+}
+
+/// Permission callback for machines without a browser (eg. over SSH or in CI).
+///
+/// It prints the authorize URL to stderr, and waits for the verifier from any of `options.sources`.
+/// It fails with `VerifierTimeout` if nothing is given within `options.timeout`.
+/// stdin is read only after the other sources have nothing; a line typed after it returns is kept for the next call.
+pub fn headless_callback(options: HeadlessOptions) -> GrantPermissionCallback {
+  Box::new(move |request| wait_for_verifier(request, &options))
+}
+
+fn wait_for_verifier(
+  request: &AuthorizeRequest,
+  options: &HeadlessOptions,
+) -> Result<String, OauthError> {
+  eprintln!(
+    "Open the following URL in a browser and grant the permission:\n\n  {}\n",
+    request.authorize_url
+  );
+  let mut hints = vec![];
+  for source in &options.sources {
+    match source {
+      VerifierSource::Stdin => hints.push("input it here".to_string()),
+      VerifierSource::Env(name) => hints.push(format!("set {}", name)),
+      VerifierSource::File(path) => hints.push(format!("write it to {}", path.display())),
+    }
+  }
+  eprintln!("Then {} with the displayed token.", hints.join(", or "));
+
+  let wants_stdin = options.sources.contains(&VerifierSource::Stdin);
+  let deadline = options.timeout.map(|timeout| Instant::now() + timeout);
+
+  loop {
+    // Sources other than stdin never block, so check them before asking stdin for a line
+    let verifier = options
+      .sources
+      .iter()
+      .filter_map(|source| match source {
+        VerifierSource::Stdin => None,
+        VerifierSource::Env(name) => env::var(name).ok(),
+        VerifierSource::File(path) => fs::read_to_string(path).ok(),
+      })
+      .find(|v| !v.trim().is_empty());
+    if let Some(verifier) = verifier {
+      return Ok(verifier.trim().to_string());
+    }
+
+    if wants_stdin {
+      match stdin_reader().lock().unwrap().poll() {
+        Some(StdinLine::Line(line)) if !line.trim().is_empty() => {
+          return Ok(line.trim().to_string())
+        }
+        // stdin is closed and there is no other source to wait for
+        Some(StdinLine::Closed) if options.sources == [VerifierSource::Stdin] => {
+          return Err(OauthError::PermissionDeniedUser)
+        }
+        _ => {}
+      }
+    }
+
+    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+      return Err(OauthError::VerifierTimeout);
+    }
+    thread::sleep(options.poll_interval);
+  }
+}
+
+enum StdinLine {
+  Line(String),
+  Closed,
+}
+
+/// Reader of stdin shared by all headless callbacks in the process
+///
+/// A line is read only when `poll` asks for one, and at most one read is in flight.
+/// If a callback returns while the read is in flight, the line is kept for the next callback instead of being dropped.
+struct StdinReader {
+  requests: Sender<()>,
+  lines: Receiver<StdinLine>,
+  pending: bool,
+  closed: bool,
+}
+
+impl StdinReader {
+  fn spawn() -> Self {
+    let (request_tx, request_rx) = mpsc::channel::<()>();
+    let (line_tx, line_rx) = mpsc::channel();
+    thread::spawn(move || {
+      for () in request_rx {
+        let mut line = String::new();
+        let read = match std::io::stdin().lock().read_line(&mut line) {
+          Ok(0) | Err(_) => StdinLine::Closed,
+          Ok(_) => StdinLine::Line(line),
+        };
+        let closed = matches!(read, StdinLine::Closed);
+        if line_tx.send(read).is_err() || closed {
+          break;
+        }
+      }
+    });
+
+    Self {
+      requests: request_tx,
+      lines: line_rx,
+      pending: false,
+      closed: false,
+    }
+  }
+
+  /// Ask for a line if not yet, and take it if it has been read
+  fn poll(&mut self) -> Option<StdinLine> {
+    if self.closed {
+      return Some(StdinLine::Closed);
+    }
+    if !self.pending {
+      self.pending = self.requests.send(()).is_ok();
+      if !self.pending {
+        self.closed = true;
+        return Some(StdinLine::Closed);
+      }
+    }
+    match self.lines.try_recv() {
+      Ok(line) => {
+        self.pending = false;
+        self.closed = matches!(line, StdinLine::Closed);
+        Some(line)
+      }
+      Err(TryRecvError::Empty) => None,
+      Err(TryRecvError::Disconnected) => {
+        self.closed = true;
+        Some(StdinLine::Closed)
+      }
+    }
+  }
+}
+
+fn stdin_reader() -> &'static Mutex<StdinReader> {
+  static READER: OnceLock<Mutex<StdinReader>> = OnceLock::new();
+  READER.get_or_init(|| Mutex::new(StdinReader::spawn()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::oauth::token::OauthTokenResponse;
+
+  fn authorize_request() -> AuthorizeRequest {
+    AuthorizeRequest {
+      authorize_url: "https://www.hatena.com/oauth/authorize?oauth_token=token".into(),
+      request_token: OauthTokenResponse {
+        oauth_token: "token".into(),
+        oauth_token_secret: "secret".into(),
+        extra: Default::default(),
+      },
+    }
+  }
+
+  #[test]
+  fn test_headless_verifier_from_file() {
+    let path = env::temp_dir().join(format!("hatena-rs-verifier-{}", std::process::id()));
+    let writer_path = path.clone();
+    let writer = thread::spawn(move || {
+      thread::sleep(Duration::from_millis(100));
+      fs::write(writer_path, "verifier\n").unwrap();
+    });
+
+    let mut callback = headless_callback(HeadlessOptions {
+      sources: vec![
+        VerifierSource::Env("HATENA_RS_TEST_UNSET_VERIFIER".into()),
+        VerifierSource::File(path.clone()),
+      ],
+      timeout: Some(Duration::from_secs(10)),
+      poll_interval: Duration::from_millis(10),
+    });
+    assert_eq!(callback(&authorize_request()).unwrap(), "verifier");
+
+    writer.join().unwrap();
+    fs::remove_file(path).unwrap();
+  }
+
+  #[test]
+  fn test_headless_timeout() {
+    let mut callback = headless_callback(HeadlessOptions {
+      sources: vec![VerifierSource::File(
+        env::temp_dir().join("hatena-rs-verifier-never-written"),
+      )],
+      timeout: Some(Duration::from_millis(50)),
+      poll_interval: Duration::from_millis(10),
+    });
+    assert!(matches!(
+      callback(&authorize_request()),
+      Err(OauthError::VerifierTimeout)
+    ));
+  }
+}
@@ -1,24 +1,35 @@
 use std::collections::HashMap;
 use std::env;
 use std::io::Write;
+use std::sync::Arc;
+use std::time::Duration;
 
-use chrono::Utc;
-use rand::distributions::Alphanumeric;
-use rand::{thread_rng, Rng};
 use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
-use ring::hmac::{self, HMAC_SHA1_FOR_LEGACY_USE_ONLY};
-use webbrowser;
+use url::{form_urlencoded, Url};
 
+use crate::endpoints::HatenaEndpoints;
+use crate::oauth::callback::*;
+use crate::oauth::clock::*;
 use crate::oauth::consts::*;
 use crate::oauth::error::*;
+use crate::oauth::problem::*;
+use crate::oauth::signature::*;
 use crate::oauth::token::*;
 use crate::oauth::util::*;
+use crate::secret::SecretString;
 
+#[derive(Clone)]
 pub struct RequestToken {
   consumer_key: String,
-  consumer_secret: String,
+  consumer_secret: SecretString,
   oauth_token: Option<String>,
-  oauth_token_secret: Option<String>,
+  oauth_token_secret: Option<SecretString>,
+  /// Source of `oauth_timestamp`
+  clock: Arc<dyn Clock>,
+  /// Source of `oauth_nonce`
+  nonce_source: Arc<dyn NonceSource>,
+  /// Method to sign requests
+  signature_method: SignatureMethod,
 }
 
 impl RequestToken {
@@ -30,12 +41,52 @@ impl RequestToken {
   ) -> Self {
     RequestToken {
       consumer_key: consumer_key.to_string(),
-      consumer_secret: consumer_secret.to_string(),
+      consumer_secret: consumer_secret.into(),
       oauth_token: oauth_token.map(|s| s.to_string()),
-      oauth_token_secret: oauth_token_secret.map(|s| s.to_string()),
+      oauth_token_secret: oauth_token_secret.map(|s| s.into()),
+      clock: Arc::new(SystemClock),
+      nonce_source: Arc::new(RandomNonce),
+      signature_method: SignatureMethod::default(),
     }
   }
 
+  /// Use `clock` to generate `oauth_timestamp` instead of the system time
+  pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+    self.clock = clock;
+    self
+  }
+
+  /// Use `nonce_source` to generate `oauth_nonce` instead of random strings
+  pub fn with_nonce_source(mut self, nonce_source: Arc<dyn NonceSource>) -> Self {
+    self.nonce_source = nonce_source;
+    self
+  }
+
+  /// Sign requests by `signature_method` instead of HMAC-SHA1
+  pub fn with_signature_method(mut self, signature_method: SignatureMethod) -> Self {
+    self.signature_method = signature_method;
+    self
+  }
+
+  /// Signer of the same consumer and sources with another token
+  pub fn with_token(&self, oauth_token: &str, oauth_token_secret: &str) -> Self {
+    Self {
+      oauth_token: Some(oauth_token.to_string()),
+      oauth_token_secret: Some(oauth_token_secret.into()),
+      ..self.clone()
+    }
+  }
+
+  /// Build a value of `Authorization` header signed as RFC 5849 by the signature method.
+  ///
+  /// Query parameters of `url` and parameters of `body` are included in the signature.
+  ///
+  /// # Arguments
+  ///
+  /// * `url` - URL to send a request to, which may contain a query string
+  /// * `method` - HTTP method of the request
+  /// * `params` - Additional protocol parameters (eg. `oauth_callback`, `oauth_verifier`)
+  /// * `body` - Body of the request. Pass it only if it is `application/x-www-form-urlencoded`.
   pub fn to_header_string(
     &self,
     url: &str,
@@ -47,34 +98,27 @@ impl RequestToken {
       Some(map) => map.clone(),
       None => HashMap::new(),
     };
-    let timestamp = &format!("{}", &Utc::now().timestamp());
-    let nonce: String = thread_rng()
-      .sample_iter(&Alphanumeric)
-      .take(32)
-      .map(char::from)
-      .collect();
+    let timestamp = &format!("{}", self.clock.now());
+    let nonce = self.nonce_source.nonce();
 
     headers.insert("oauth_consumer_key", &self.consumer_key);
     headers.insert("oauth_nonce", &nonce);
-    headers.insert("oauth_signature_method", "HMAC-SHA1");
+    headers.insert("oauth_signature_method", self.signature_method.name());
     headers.insert("oauth_timestamp", timestamp);
     headers.insert("oauth_version", "1.0");
     if let Some(oauth_token) = &self.oauth_token {
       headers.insert("oauth_token", oauth_token);
     }
 
-    let mut params_for_signature = headers.clone();
-    if body.is_some() {
-      let body = body.unwrap();
-      for pair in body.split('&') {
-        let mut parts = pair.split('=');
-        let key = parts.next();
-        let value = parts.next();
-        if key.is_none() || value.is_none() {
-          continue;
-        }
-        params_for_signature.insert(key.unwrap(), value.unwrap());
-      }
+    let mut params_for_signature = headers
+      .iter()
+      .map(|(k, v)| (k.to_string(), v.to_string()))
+      .collect::<Vec<(String, String)>>();
+    if let Ok(url) = Url::parse(url) {
+      params_for_signature.extend(url.query_pairs().into_owned());
+    }
+    if let Some(body) = body {
+      params_for_signature.extend(form_urlencoded::parse(body.as_bytes()).into_owned());
     }
     let signature = self.get_signature(url, method, &params_for_signature);
     headers.insert("oauth_signature", &signature);
@@ -89,30 +133,66 @@ impl RequestToken {
     format!("OAuth {}", header_strs.join(", "),)
   }
 
-  fn get_signature(&self, url: &str, method: &str, params: &HashMap<&str, &str>) -> String {
-    let key = format!(
-      "{}&{}",
-      encode(&self.consumer_secret),
-      encode(&self.oauth_token_secret.clone().unwrap_or("".into()))
-    );
+  fn get_signature(&self, url: &str, method: &str, params: &[(String, String)]) -> String {
+    let base_string = signature_base_string(method, url, params);
 
-    let mut sorted_params: Vec<_> = params
-      .iter()
-      .map(|(k, v)| format!("{}={}", encode(k), encode(v),))
-      .collect::<Vec<String>>();
-    sorted_params.sort();
+    self.signature_method.sign(
+      self.consumer_secret.expose_secret(),
+      self
+        .oauth_token_secret
+        .as_ref()
+        .map_or("", |secret| secret.expose_secret()),
+      &base_string,
+    )
+  }
+}
 
-    let base_string = format!(
-      "{}&{}&{}",
-      encode(method),
-      encode(url),
-      encode(&sorted_params.join("&")),
-    );
+/// Build a signature base string (RFC 5849 section 3.4.1)
+///
+/// # Arguments
+///
+/// * `method` - HTTP method of the request
+/// * `url` - URL of the request. Its query string is ignored, so pass query parameters in `params`.
+/// * `params` - Decoded parameters of the request, which may contain duplicate keys
+pub fn signature_base_string(method: &str, url: &str, params: &[(String, String)]) -> String {
+  let mut encoded_params = params
+    .iter()
+    .map(|(k, v)| (encode(k), encode(v)))
+    .collect::<Vec<(String, String)>>();
+  encoded_params.sort();
+  let normalized_params = encoded_params
+    .iter()
+    .map(|(k, v)| format!("{}={}", k, v))
+    .collect::<Vec<String>>()
+    .join("&");
 
-    let sign_key = hmac::Key::new(HMAC_SHA1_FOR_LEGACY_USE_ONLY, key.as_ref());
-    let signature = hmac::sign(&sign_key, base_string.as_bytes());
+  format!(
+    "{}&{}&{}",
+    encode(&method.to_uppercase()),
+    encode(&base_string_uri(url)),
+    encode(&normalized_params),
+  )
+}
 
-    base64::encode(&signature.as_ref())
+/// Normalize `url` into a base string URI (RFC 5849 section 3.4.1.2)
+///
+/// Scheme and host are lowercased, a default port is dropped, and query and fragment are removed.
+fn base_string_uri(url: &str) -> String {
+  match Url::parse(url) {
+    Ok(url) => {
+      let port = match url.port() {
+        Some(port) => format!(":{}", port),
+        None => String::new(),
+      };
+      format!(
+        "{}://{}{}{}",
+        url.scheme(),
+        url.host_str().unwrap_or(""),
+        port,
+        url.path(),
+      )
+    }
+    Err(_) => url.split(['?', '#']).next().unwrap_or(url).to_string(),
   }
 }
 
@@ -120,77 +200,131 @@ impl RequestToken {
 ///
 /// # Arguments
 ///
+/// * `client` - HTTP client to send the request with
+/// * `endpoints` - Endpoints of Hatena OAuth
 /// * `scopes` - A list of scopes to request
-/// * `consumer_key` - A consumer key for Hatena OAuth
-/// * `consumer_secret` - A consumer secret for Hatena OAuth
+/// * `req_token` - A signer with consumer key and secret for Hatena OAuth
+/// * `callback` - URL to which a user is redirected after granting a permission, or `oob`
 pub fn get_request_token(
-  scopes: &Vec<OauthScope>,
-  consumer_key: &str,
-  consumer_secret: &str,
+  client: &reqwest::blocking::Client,
+  endpoints: &HatenaEndpoints,
+  scopes: &[OauthScope],
+  req_token: &RequestToken,
+  callback: &str,
 ) -> Result<OauthTokenResponse, OauthError> {
-  let params: HashMap<&str, &str> = vec![("oauth_callback", "oob")].into_iter().collect();
-  let req_token = RequestToken::new(&consumer_key, &consumer_secret, None, None);
-  let scopes_str = scopes
-    .iter()
-    .map(|s| format!("{}", s))
-    .collect::<Vec<String>>()
-    .join(",");
+  let (authorization, body) = request_token_request(endpoints, scopes, req_token, callback);
 
-  let client = reqwest::blocking::Client::new();
   let res = client
-    .post(OAUTH_URL_REQUEST_TOKEN)
-    .header(
-      AUTHORIZATION,
-      req_token.to_header_string(
-        OAUTH_URL_REQUEST_TOKEN,
-        "POST",
-        Some(&params),
-        Some(&format!("scope={}", scopes_str)),
-      ),
-    )
+    .post(&endpoints.oauth_request_token)
+    .header(AUTHORIZATION, authorization)
     .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
-    .body(format!("scope={}", encode(&scopes_str)))
+    .body(body)
     .send();
 
   if let Ok(res) = res {
     if res.status() == 200 {
       let text = res.text()?;
-      match OauthTokenResponse::from(&text) {
-        Ok(token) => Ok(token),
-        Err(e) => Err(e),
-      }
+      OauthTokenResponse::from(&text)
     } else {
-      Err(OauthError::InvalidRequest {
-        problem: res.text()?,
-      })
+      Err(response_error(res)?)
     }
   } else {
     Err(OauthError::RequestFailure(res.unwrap_err()))
   }
 }
 
+/// Build `Authorization` header and form body of a request to the request token endpoint
+///
+/// # Arguments
+///
+/// * `endpoints` - Endpoints of Hatena OAuth
+/// * `scopes` - A list of scopes to request
+/// * `req_token` - A signer with consumer key and secret for Hatena OAuth
+/// * `callback` - URL to which a user is redirected after granting a permission, or `oob`
+pub fn request_token_request(
+  endpoints: &HatenaEndpoints,
+  scopes: &[OauthScope],
+  req_token: &RequestToken,
+  callback: &str,
+) -> (String, String) {
+  let params: HashMap<&str, &str> = vec![("oauth_callback", callback)].into_iter().collect();
+  let body = format!("scope={}", encode(&OauthScope::join_list(scopes)));
+  let authorization = req_token.to_header_string(
+    &endpoints.oauth_request_token,
+    "POST",
+    Some(&params),
+    Some(&body),
+  );
+
+  (authorization, body)
+}
+
 /// Grant a permission from a user to get an access token.
 ///
-/// This function opens a browser and waits for a user to grant a permission.
+/// This function redirects a user to the authorization endpoint by `launcher` and waits for the user to grant a permission.
 ///
 /// # Arguments
 ///
+/// * `endpoints` - Endpoints of Hatena OAuth
 /// * `token` - A request token returned from request endpoint
+/// * `launcher` - A hook to redirect a user (eg. opening a browser). If `None`, nothing is launched.
 /// * `callback` - A callback function after redirecting a user to grants a permission, which prompts user to enter a token.
 pub fn grant_permission_browser(
+  endpoints: &HatenaEndpoints,
   token: &OauthTokenResponse,
-  callback: fn() -> Result<String, OauthError>,
+  launcher: Option<&mut BrowserLauncher>,
+  callback: &mut GrantPermissionCallback,
 ) -> Result<String, OauthError> {
-  webbrowser::open(&format!(
-    "{}?oauth_token={}",
-    OAUTH_URL_GRANT_PERMISSION, token.oauth_token,
-  ))
-  .map_err(|_| OauthError::PermissionDeniedUser)?;
+  let request = AuthorizeRequest {
+    authorize_url: authorize_url(endpoints, token),
+    request_token: token.clone(),
+  };
+  if let Some(launcher) = launcher {
+    launcher(&request.authorize_url)?;
+  }
 
-  Ok(callback()?)
+  callback(&request)
 }
 
-pub fn grant_permission_default_callback() -> Result<String, OauthError> {
+/// Grant a permission from a user, capturing the verifier by a loopback server.
+///
+/// This function redirects a user by `launcher` and waits for the authorization endpoint to redirect the user back to `server`.
+/// `token` must have been requested with `server.url()` as its callback.
+///
+/// # Arguments
+///
+/// * `endpoints` - Endpoints of Hatena OAuth
+/// * `token` - A request token returned from request endpoint
+/// * `launcher` - A hook to redirect a user (eg. opening a browser). If `None`, nothing is launched.
+/// * `server` - A loopback server which receives the redirect
+/// * `timeout` - How long to wait for the user to grant a permission
+pub fn grant_permission_loopback(
+  endpoints: &HatenaEndpoints,
+  token: &OauthTokenResponse,
+  launcher: Option<&mut BrowserLauncher>,
+  server: &LoopbackServer,
+  timeout: Duration,
+) -> Result<String, OauthError> {
+  if let Some(launcher) = launcher {
+    launcher(&authorize_url(endpoints, token))?;
+  }
+
+  server.wait_for_verifier(token, timeout)
+}
+
+/// URL of the authorization endpoint to which a user is redirected to grant a permission
+pub fn authorize_url(endpoints: &HatenaEndpoints, token: &OauthTokenResponse) -> String {
+  format!(
+    "{}?oauth_token={}",
+    endpoints.oauth_authorize,
+    encode(&token.oauth_token),
+  )
+}
+
+/// Default permission callback, which prompts a user to input the verifier to stdin
+pub fn grant_permission_default_callback(
+  _request: &AuthorizeRequest,
+) -> Result<String, OauthError> {
   let mut oauth_verifier = String::new();
   print!(
     "Input token printed on the browser (or, 'set {}=<token>' and Enter): ",
@@ -210,45 +344,275 @@ pub fn grant_permission_default_callback() -> Result<String, OauthError> {
 ///
 /// # Arguments
 ///
+/// * `client` - HTTP client to send the request with
+/// * `endpoints` - Endpoints of Hatena OAuth
+/// * `req_token` - A signer with consumer key and secret for Hatena OAuth
 /// * `token` - A request token returned from request endpoint
 /// * `oauth_verifier` - OAuth verifier returned from authorization endpoint
-/// * `consumer_key` - A consumer key for Hatena OAuth
-/// * `consumer_secret` - A consumer secret for Hatena OAuth
 pub fn get_access_token(
+  client: &reqwest::blocking::Client,
+  endpoints: &HatenaEndpoints,
+  req_token: &RequestToken,
   token: &OauthTokenResponse,
   oauth_verifier: &str,
-  consumer_key: &str,
-  consumer_secret: &str,
 ) -> Result<AccessTokenResponse, OauthError> {
-  let req_token = RequestToken::new(
-    &consumer_key,
-    &consumer_secret,
-    Some(&token.oauth_token),
-    Some(&token.oauth_token_secret),
-  );
-  let params: HashMap<&str, &str> = vec![("oauth_verifier", oauth_verifier)]
-    .into_iter()
-    .collect();
+  let authorization = access_token_authorization(endpoints, req_token, token, oauth_verifier);
 
-  let client = reqwest::blocking::Client::new();
   let res = client
-    .post(OAUTH_URL_ACCESS_TOKEN)
-    .header(
-      AUTHORIZATION,
-      req_token.to_header_string(OAUTH_URL_ACCESS_TOKEN, "POST", Some(&params), None),
-    )
+    .post(&endpoints.oauth_access_token)
+    .header(AUTHORIZATION, authorization)
     .send();
 
   if let Ok(res) = res {
     if res.status() == 200 {
       let text = res.text()?;
-      Ok(AccessTokenResponse::from(&text).map_err(|e| e)?)
+      Ok(AccessTokenResponse::from(&text)?)
     } else {
-      Err(OauthError::InvalidRequest {
-        problem: res.text()?,
-      })
+      Err(response_error(res)?)
     }
   } else {
     Err(OauthError::RequestFailure(res.unwrap_err()))
   }
 }
+
+/// Build `Authorization` header of a request to the access token endpoint
+///
+/// # Arguments
+///
+/// * `endpoints` - Endpoints of Hatena OAuth
+/// * `req_token` - A signer with consumer key and secret for Hatena OAuth
+/// * `token` - A request token returned from request endpoint
+/// * `oauth_verifier` - OAuth verifier returned from authorization endpoint
+pub fn access_token_authorization(
+  endpoints: &HatenaEndpoints,
+  req_token: &RequestToken,
+  token: &OauthTokenResponse,
+  oauth_verifier: &str,
+) -> String {
+  let req_token =
+    req_token.with_token(&token.oauth_token, token.oauth_token_secret.expose_secret());
+  let params: HashMap<&str, &str> = vec![("oauth_verifier", oauth_verifier)]
+    .into_iter()
+    .collect();
+
+  req_token.to_header_string(&endpoints.oauth_access_token, "POST", Some(&params), None)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use percent_encoding::percent_decode_str;
+
+  fn fixed_signer(
+    consumer: (&str, &str),
+    token: (&str, &str),
+    timestamp: i64,
+    nonce: &str,
+  ) -> RequestToken {
+    RequestToken::new(consumer.0, consumer.1, Some(token.0), Some(token.1))
+      .with_clock(Arc::new(FixedClock(timestamp)))
+      .with_nonce_source(Arc::new(FixedNonce(nonce.into())))
+  }
+
+  fn header_param(header: &str, key: &str) -> String {
+    let prefix = format!("{}=\"", key);
+    let value = header
+      .trim_start_matches("OAuth ")
+      .split(", ")
+      .find_map(|pair| pair.strip_prefix(&prefix))
+      .unwrap()
+      .trim_end_matches('"');
+
+    percent_decode_str(value).decode_utf8().unwrap().to_string()
+  }
+
+  #[test]
+  fn test_signature_oauth_core_appendix() {
+    // Example of OAuth Core 1.0a Appendix A.5
+    let signer = fixed_signer(
+      ("dpf43f3p2l4k3l03", "kd94hf93k423kf44"),
+      ("nnch734d00sl2jdk", "pfkkdhi9sl3r4s00"),
+      1191242096,
+      "kllo9940pd9333jh",
+    );
+    let header = signer.to_header_string(
+      "http://photos.example.net/photos?file=vacation.jpg&size=original",
+      "GET",
+      None,
+      None,
+    );
+
+    assert_eq!(
+      header_param(&header, "oauth_signature"),
+      "tR3+Ty81lMeYAr/Fid0kMTYa/WM="
+    );
+    assert_eq!(header_param(&header, "oauth_nonce"), "kllo9940pd9333jh");
+    assert_eq!(header_param(&header, "oauth_timestamp"), "1191242096");
+    assert!(!header.contains("file="));
+  }
+
+  #[test]
+  fn test_signature_with_query_and_form_body() {
+    // Widely used example of Twitter developer documentation "Creating a signature"
+    let signer = fixed_signer(
+      (
+        "xvz1evFS4wEEPTGEFPHBog",
+        "kAcSOqF21Fu85e7zjz7ZN2U4ZRhfV3WpwPAoE3Z7kBw",
+      ),
+      (
+        "370773112-GmHxMAgYyLbNEtIKZeRNFsMKPR9EyMZeS9weJAEb",
+        "LswwdoUaIvS8ltyTt5jkRh4J50vUPVVHtR2YPi5kE",
+      ),
+      1318622958,
+      "kYjzVBB8Y0ZFabxSWbWovY3uYSQ2pTgmZeNu2VS4cg",
+    );
+    let header = signer.to_header_string(
+      "https://api.twitter.com/1.1/statuses/update.json?include_entities=true",
+      "POST",
+      None,
+      Some("status=Hello%20Ladies%20%2b%20Gentlemen%2c%20a%20signed%20OAuth%20request%21"),
+    );
+
+    assert_eq!(
+      header_param(&header, "oauth_signature"),
+      "hCtSmYh+iHYCEqBWrE7C7hYmtUk="
+    );
+  }
+
+  #[test]
+  fn test_signature_hatena_request_token() {
+    // Request token request in the form of Hatena Developer Center, whose scope is a form body to be signed.
+    // The expected signature is computed independently of this crate from the base string below.
+    let signer = RequestToken::new("key", "secret", None, None)
+      .with_clock(Arc::new(FixedClock(1291689730)))
+      .with_nonce_source(Arc::new(FixedNonce("0c670efea71547422662".into())));
+    let (header, body) = request_token_request(
+      &HatenaEndpoints::default(),
+      &[OauthScope::ReadPublic, OauthScope::WritePublic],
+      &signer,
+      OAUTH_CALLBACK_OOB,
+    );
+
+    let params = [
+      ("oauth_callback", "oob"),
+      ("oauth_consumer_key", "key"),
+      ("oauth_nonce", "0c670efea71547422662"),
+      ("oauth_signature_method", "HMAC-SHA1"),
+      ("oauth_timestamp", "1291689730"),
+      ("oauth_version", "1.0"),
+      ("scope", "read_public,write_public"),
+    ]
+    .map(|(k, v)| (k.to_string(), v.to_string()));
+    assert_eq!(body, "scope=read_public%2Cwrite_public");
+    assert_eq!(
+      signature_base_string("POST", "https://www.hatena.com/oauth/initiate", &params),
+      "POST&https%3A%2F%2Fwww.hatena.com%2Foauth%2Finitiate&oauth_callback%3Doob%26oauth_consumer_key%3Dkey%26oauth_nonce%3D0c670efea71547422662%26oauth_signature_method%3DHMAC-SHA1%26oauth_timestamp%3D1291689730%26oauth_version%3D1.0%26scope%3Dread_public%252Cwrite_public",
+    );
+    assert_eq!(
+      header_param(&header, "oauth_signature"),
+      "cb0SrFFqg8E4j+BteRCWC2xnBmQ="
+    );
+    assert_eq!(header_param(&header, "oauth_callback"), "oob");
+  }
+
+  #[test]
+  fn test_signature_is_deterministic_with_fixed_sources() {
+    let signer = fixed_signer(("key", "secret"), ("token", "token_secret"), 1, "nonce");
+    let params: HashMap<&str, &str> = vec![("oauth_verifier", "verifier")].into_iter().collect();
+    let first = signer.to_header_string(
+      "https://www.hatena.com/oauth/token",
+      "POST",
+      Some(&params),
+      None,
+    );
+    let second = signer.to_header_string(
+      "https://www.hatena.com/oauth/token",
+      "POST",
+      Some(&params),
+      None,
+    );
+
+    assert_eq!(first, second);
+    assert_eq!(header_param(&first, "oauth_verifier"), "verifier");
+    assert_eq!(header_param(&first, "oauth_version"), "1.0");
+  }
+
+  #[test]
+  fn test_signature_method_plaintext() {
+    let signer = fixed_signer(("key", "c&s"), ("token", "t s"), 1, "nonce")
+      .with_signature_method(SignatureMethod::Plaintext);
+    let header = signer.to_header_string("https://example.com/", "GET", None, None);
+
+    assert_eq!(header_param(&header, "oauth_signature_method"), "PLAINTEXT");
+    assert_eq!(header_param(&header, "oauth_signature"), "c%26s&t%20s");
+  }
+
+  #[test]
+  fn test_signature_base_string_rfc5849() {
+    // Example of RFC 5849 section 3.4.1.1
+    let url = "http://EXAMPLE.COM:80/request?b5=%3D%253D&a3=a&c%40=&a2=r%20b";
+    let mut params = vec![
+      ("oauth_consumer_key", "9djdj82h48djs9d2"),
+      ("oauth_token", "kkk9d7dh3k39sjv7"),
+      ("oauth_signature_method", "HMAC-SHA1"),
+      ("oauth_timestamp", "137131201"),
+      ("oauth_nonce", "7d8f3e4a"),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_string(), v.to_string()))
+    .collect::<Vec<(String, String)>>();
+    params.extend(Url::parse(url).unwrap().query_pairs().into_owned());
+    params.extend(form_urlencoded::parse(b"c2&a3=2+q").into_owned());
+
+    assert_eq!(
+      signature_base_string("POST", url, &params),
+      "POST&http%3A%2F%2Fexample.com%2Frequest&a2%3Dr%2520b%26a3%3D2%2520q%26a3%3Da%26b5%3D%253D%25253D%26c%2540%3D%26c2%3D%26oauth_consumer_key%3D9djdj82h48djs9d2%26oauth_nonce%3D7d8f3e4a%26oauth_signature_method%3DHMAC-SHA1%26oauth_timestamp%3D137131201%26oauth_token%3Dkkk9d7dh3k39sjv7",
+    );
+  }
+
+  #[test]
+  fn test_base_string_uri() {
+    assert_eq!(
+      base_string_uri("HTTP://Example.com:80/r%20v/X?id=123"),
+      "http://example.com/r%20v/X"
+    );
+    assert_eq!(
+      base_string_uri("https://www.example.net:8080/?q=1#frag"),
+      "https://www.example.net:8080/"
+    );
+  }
+
+  #[test]
+  fn test_grant_permission_hooks() {
+    let endpoints = HatenaEndpoints::from_base_url("http://localhost:49494");
+    let token = OauthTokenResponse {
+      oauth_token: "req+token".into(),
+      oauth_token_secret: "secret".into(),
+      extra: Default::default(),
+    };
+    let launched = Arc::new(std::sync::Mutex::new(vec![]));
+    let launched_by_hook = launched.clone();
+    let mut launcher: BrowserLauncher = Box::new(move |url| {
+      launched_by_hook.lock().unwrap().push(url.to_string());
+      Ok(())
+    });
+    let mut calls = 0;
+    let mut callback: GrantPermissionCallback = Box::new(move |request| {
+      calls += 1;
+      Ok(format!("{}-{}", request.request_token.oauth_token, calls))
+    });
+
+    let verifier =
+      grant_permission_browser(&endpoints, &token, Some(&mut launcher), &mut callback).unwrap();
+    assert_eq!(verifier, "req+token-1");
+    assert_eq!(
+      *launched.lock().unwrap(),
+      vec!["http://localhost:49494/oauth/authorize?oauth_token=req%2Btoken".to_string()]
+    );
+
+    let verifier = grant_permission_browser(&endpoints, &token, None, &mut callback).unwrap();
+    assert_eq!(verifier, "req+token-2");
+    assert_eq!(launched.lock().unwrap().len(), 1);
+  }
+}
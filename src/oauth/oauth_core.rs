@@ -0,0 +1,321 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::endpoints::HatenaEndpoints;
+use crate::oauth::callback::*;
+use crate::oauth::clock::*;
+use crate::oauth::consts::*;
+use crate::oauth::error::*;
+use crate::oauth::oauth::*;
+use crate::oauth::store::*;
+use crate::oauth::token::*;
+use crate::oauth::{get_access_token_from_env, HatenaConsumerInfo};
+
+/// State and logic shared by `HatenaOauth` and `AsyncHatenaOauth`, which only differ in how they do I/O
+///
+/// Methods here never touch the network or the token store. Blocking work (permission callbacks and browser launchers)
+/// is returned as closures, which the blocking client calls directly and the async client runs on a blocking thread.
+pub(crate) struct OauthCore {
+  pub(crate) consumer_info: HatenaConsumerInfo,
+  /// Scopes granted for the access token
+  pub(crate) scopes: Vec<OauthScope>,
+  /// Cache of request token response
+  pub(crate) request_token: Option<OauthTokenResponse>,
+  /// Cache of access token response
+  pub(crate) access_token: Option<AccessTokenResponse>,
+  /// Cache of oauth verifier
+  pub(crate) verifier: Option<String>,
+  /// Callback after redirecting user to grant permission
+  pub(crate) grant_permission_callback: Arc<Mutex<GrantPermissionCallback>>,
+  /// Hook to redirect user to the authorization URL
+  pub(crate) browser_launcher: Arc<Mutex<Option<BrowserLauncher>>>,
+  /// Persistent storage of access token
+  pub(crate) token_store: Option<Arc<dyn TokenStore>>,
+  /// Endpoints of Hatena OAuth
+  pub(crate) endpoints: HatenaEndpoints,
+  /// Source of `oauth_timestamp`
+  pub(crate) clock: Arc<dyn Clock>,
+  /// Source of `oauth_nonce`
+  pub(crate) nonce_source: Arc<dyn NonceSource>,
+  /// Whether to re-authorize with additional scopes when a required scope is missing
+  pub(crate) incremental_authorization: bool,
+  /// Whether to run the authorization flow again when the access token is revoked
+  pub(crate) interactive: bool,
+  /// Seconds added to `clock` to follow the server time
+  pub(crate) clock_offset: i64,
+}
+
+impl OauthCore {
+  /// See `HatenaOauth::new`
+  pub(crate) fn new(
+    scopes: Vec<OauthScope>,
+    grant_permission_callback: Option<GrantPermissionCallback>,
+    consumer_info: HatenaConsumerInfo,
+  ) -> Self {
+    let callback =
+      grant_permission_callback.unwrap_or_else(|| Box::new(grant_permission_default_callback));
+
+    Self {
+      consumer_info,
+      scopes,
+      request_token: None,
+      access_token: get_access_token_from_env(),
+      verifier: None,
+      grant_permission_callback: Arc::new(Mutex::new(callback)),
+      browser_launcher: Arc::new(Mutex::new(Some(default_browser_launcher()))),
+      token_store: None,
+      endpoints: HatenaEndpoints::default(),
+      clock: Arc::new(SystemClock),
+      nonce_source: Arc::new(RandomNonce),
+      incremental_authorization: false,
+      interactive: true,
+      clock_offset: 0,
+    }
+  }
+
+  /// Check that the access token has any of `required` scopes. See `HatenaOauth::require_scopes`.
+  ///
+  /// Returns true if a scope has been added and the client must authorize again.
+  pub(crate) fn check_scopes(&mut self, required: &[OauthScope]) -> Result<bool, OauthError> {
+    let granted = match &self.access_token {
+      Some(token) if token.has_any_scope(required) => return Ok(false),
+      Some(token) => token.scopes.clone().unwrap_or_default(),
+      None if required.iter().any(|s| self.scopes.contains(s)) => return Ok(false),
+      None => self.requested_scopes(),
+    };
+    if !self.incremental_authorization || required.is_empty() {
+      return Err(OauthError::MissingScope {
+        required: required.to_vec(),
+        granted,
+      });
+    }
+
+    self.scopes.push(required[0]);
+    Ok(self.access_token.is_some())
+  }
+
+  /// Whether a new request token and verifier are needed to get an access token
+  pub(crate) fn needs_verifier(&self, force: bool) -> bool {
+    force || self.request_token.is_none() || self.verifier.is_none()
+  }
+
+  /// Recover from `err` reported by a response, so that the failed request can be retried once.
+  ///
+  /// A rejected access token is discarded, so that the next request authorizes again
+  /// (or `TokenRevoked` is returned if not interactive). The caller removes it from the token store.
+  /// A refused timestamp adjusts the clock offset. Other errors are returned as is.
+  pub(crate) fn recover(&mut self, err: OauthError) -> Result<(), OauthError> {
+    match err {
+      OauthError::TokenRejected { problem, .. } => {
+        self.access_token = None;
+        if self.interactive {
+          Ok(())
+        } else {
+          Err(OauthError::TokenRevoked { problem })
+        }
+      }
+      err if self.adjust_clock(&err) => Ok(()),
+      err => Err(err),
+    }
+  }
+
+  /// Learn the clock offset from `err` if it is `TimestampRefused` with the server time.
+  /// Returns true if learned, which means the failed request should be retried.
+  pub(crate) fn adjust_clock(&mut self, err: &OauthError) -> bool {
+    match err {
+      OauthError::TimestampRefused {
+        server_time: Some(server_time),
+        ..
+      } => {
+        self.clock_offset = server_time - self.clock.now();
+        true
+      }
+      _ => false,
+    }
+  }
+
+  /// Scopes to be requested, sorted and deduplicated
+  pub(crate) fn requested_scopes(&self) -> Vec<OauthScope> {
+    let mut scopes = self.scopes.clone();
+    scopes.sort();
+    scopes.dedup();
+    scopes
+  }
+
+  /// Signer with the consumer key and secret, not bound to any token
+  pub(crate) fn consumer_signer(&self) -> RequestToken {
+    RequestToken::new(
+      &self.consumer_info.consumer_key,
+      self.consumer_info.consumer_secret.expose_secret(),
+      None,
+      None,
+    )
+    .with_clock(Arc::new(OffsetClock::new(
+      self.clock.clone(),
+      self.clock_offset,
+    )))
+    .with_nonce_source(self.nonce_source.clone())
+    .with_signature_method(self.consumer_info.signature_method.clone())
+  }
+
+  /// Signer with the cached access token
+  ///
+  /// It panics if no access token is cached.
+  pub(crate) fn access_signer(&self) -> RequestToken {
+    let access_token = self.access_token.as_ref().unwrap();
+    self.consumer_signer().with_token(
+      &access_token.oauth_token,
+      access_token.oauth_token_secret.expose_secret(),
+    )
+  }
+
+  /// Record the scopes requested for `access_token` freshly minted, which is to be saved and cached
+  pub(crate) fn granted(&self, mut access_token: AccessTokenResponse) -> AccessTokenResponse {
+    access_token.scopes = Some(self.requested_scopes());
+    access_token
+  }
+
+  /// Work to redirect a user to the authorization URL of the cached request token and wait for the verifier
+  pub(crate) fn grant_permission_task(
+    &self,
+  ) -> impl FnOnce() -> Result<String, OauthError> + Send + 'static {
+    let endpoints = self.endpoints.clone();
+    let token = self.request_token.clone().unwrap();
+    let launcher = self.browser_launcher.clone();
+    let callback = self.grant_permission_callback.clone();
+
+    move || {
+      grant_permission_browser(
+        &endpoints,
+        &token,
+        launcher.lock().unwrap().as_mut(),
+        &mut callback.lock().unwrap(),
+      )
+    }
+  }
+
+  /// Work to redirect a user to the authorization URL of the cached request token and capture the verifier by `server`
+  pub(crate) fn grant_permission_loopback_task(
+    &self,
+    server: LoopbackServer,
+    timeout: Duration,
+  ) -> impl FnOnce() -> Result<String, OauthError> + Send + 'static {
+    let endpoints = self.endpoints.clone();
+    let token = self.request_token.clone().unwrap();
+    let launcher = self.browser_launcher.clone();
+
+    move || {
+      grant_permission_loopback(
+        &endpoints,
+        &token,
+        launcher.lock().unwrap().as_mut(),
+        &server,
+        timeout,
+      )
+    }
+  }
+}
+
+/// Builder methods and accessors which `HatenaOauth` and `AsyncHatenaOauth` share through their `core`
+macro_rules! impl_oauth_options {
+  () => {
+    /// Replace the hook to redirect user to the authorization URL, which opens a browser by default
+    ///
+    /// # Arguments
+    ///
+    /// * `launcher` - A hook called with the authorization URL. If `None`, nothing is launched and only the callback is called.
+    pub fn with_browser_launcher(
+      self,
+      launcher: Option<crate::oauth::callback::BrowserLauncher>,
+    ) -> Self {
+      *self.core.browser_launcher.lock().unwrap() = launcher;
+      self
+    }
+
+    /// Authorize without a browser (eg. over SSH or in CI)
+    ///
+    /// It disables the browser launch, and uses `headless_callback` which prints the authorize URL
+    /// and reads the verifier from stdin, an environment variable or a file.
+    ///
+    /// # Arguments
+    ///
+    /// * `options` - Where to read the verifier from and how long to wait
+    pub fn headless(self, options: crate::oauth::headless::HeadlessOptions) -> Self {
+      *self.core.browser_launcher.lock().unwrap() = None;
+      *self.core.grant_permission_callback.lock().unwrap() =
+        crate::oauth::headless::headless_callback(options);
+      self
+    }
+
+    /// Use `endpoints` instead of the production Hatena servers
+    ///
+    /// # Arguments
+    ///
+    /// * `endpoints` - Endpoints to send requests to
+    pub fn with_endpoints(mut self, endpoints: crate::endpoints::HatenaEndpoints) -> Self {
+      self.core.endpoints = endpoints;
+      self
+    }
+
+    /// Endpoints this client sends requests to
+    pub fn endpoints(&self) -> &crate::endpoints::HatenaEndpoints {
+      &self.core.endpoints
+    }
+
+    /// Use `clock` to generate `oauth_timestamp` instead of the system time
+    pub fn with_clock(mut self, clock: std::sync::Arc<dyn crate::oauth::clock::Clock>) -> Self {
+      self.core.clock = clock;
+      self
+    }
+
+    /// Shift `oauth_timestamp` by `offset` seconds, eg. to restore an offset learned before
+    pub fn with_clock_offset(mut self, offset: i64) -> Self {
+      self.core.clock_offset = offset;
+      self
+    }
+
+    /// Seconds added to the clock to follow the server time
+    ///
+    /// It is learned automatically when the server refuses the timestamp of a request.
+    pub fn clock_offset(&self) -> i64 {
+      self.core.clock_offset
+    }
+
+    /// Use `nonce_source` to generate `oauth_nonce` instead of random strings
+    pub fn with_nonce_source(
+      mut self,
+      nonce_source: std::sync::Arc<dyn crate::oauth::clock::NonceSource>,
+    ) -> Self {
+      self.core.nonce_source = nonce_source;
+      self
+    }
+
+    /// Whether to authorize again when a request is rejected because the access token is revoked or expired
+    ///
+    /// If enabled (default), the cached token is discarded, the authorization flow runs again,
+    /// and the request is retried once. If disabled, it fails with `TokenRevoked` error instead.
+    pub fn with_interactive(mut self, interactive: bool) -> Self {
+      self.core.interactive = interactive;
+      self
+    }
+
+    /// Re-authorize with additional scopes when an API needs a scope the access token lacks
+    ///
+    /// If disabled (default), such an API fails with `MissingScope` error.
+    pub fn with_incremental_authorization(mut self, enabled: bool) -> Self {
+      self.core.incremental_authorization = enabled;
+      self
+    }
+
+    /// Scopes granted for the cached access token, or `None` if no token is cached or they are unknown
+    pub fn granted_scopes(&self) -> Option<&[crate::oauth::consts::OauthScope]> {
+      self
+        .core
+        .access_token
+        .as_ref()
+        .and_then(|token| token.scopes.as_deref())
+    }
+  };
+}
+
+pub(crate) use impl_oauth_options;
@@ -0,0 +1,274 @@
+use std::collections::HashMap;
+
+use chrono::DateTime;
+use percent_encoding::percent_decode_str;
+use reqwest::header::{HeaderMap, DATE, WWW_AUTHENTICATE};
+use reqwest::StatusCode;
+
+use crate::oauth::error::*;
+use crate::oauth::util::trim_ascii;
+
+/// Problems meaning that the access token can no longer be used and a new one is needed
+const TOKEN_PROBLEMS: [&str; 4] = [
+  "token_used",
+  "token_expired",
+  "token_revoked",
+  "token_rejected",
+];
+
+/// Collect parameters of OAuth Problem Reporting from `WWW-Authenticate` header and form-urlencoded body.
+///
+/// Parameters in the header take precedence over the ones in the body.
+pub(crate) fn problem_params(headers: &HeaderMap, body: &[u8]) -> HashMap<String, String> {
+  let mut params: HashMap<String, String> = url::form_urlencoded::parse(trim_ascii(body))
+    .filter(|(key, _)| key.starts_with("oauth_"))
+    .map(|(key, value)| (key.to_string(), value.to_string()))
+    .collect();
+
+  for value in headers.get_all(WWW_AUTHENTICATE) {
+    let value = match value.to_str() {
+      Ok(value) => value.trim(),
+      Err(_) => continue,
+    };
+    let value = match value.get(..6) {
+      Some(scheme) if scheme.eq_ignore_ascii_case("oauth ") => &value[6..],
+      _ => continue,
+    };
+    for (key, value) in auth_params(value) {
+      params.insert(
+        key,
+        percent_decode_str(&value).decode_utf8_lossy().to_string(),
+      );
+    }
+  }
+
+  params
+}
+
+/// Split `key=value` pairs of an `auth-param` list by commas outside of quoted strings
+///
+/// Quotes around values are removed and `\` escapes in them are resolved. Pairs without `=` are skipped.
+fn auth_params(value: &str) -> Vec<(String, String)> {
+  let mut params = vec![];
+  let mut chars = value.chars().peekable();
+
+  loop {
+    let mut key = String::new();
+    let mut has_value = false;
+    for c in chars.by_ref() {
+      if c == '=' {
+        has_value = true;
+        break;
+      }
+      key.push(c);
+    }
+    if !has_value {
+      return params;
+    }
+    // Tokens without `=` before the key are skipped
+    let key = key.rsplit(',').next().unwrap_or_default().trim();
+
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+    let mut value = String::new();
+    if chars.next_if_eq(&'"').is_some() {
+      while let Some(c) = chars.next() {
+        match c {
+          '"' => break,
+          '\\' => value.extend(chars.next()),
+          c => value.push(c),
+        }
+      }
+      // Skip to the next pair
+      chars.by_ref().find(|c| *c == ',');
+    } else {
+      value = chars.by_ref().take_while(|c| *c != ',').collect();
+    }
+
+    if !key.is_empty() {
+      params.push((key.to_string(), value.trim().to_string()));
+    }
+  }
+}
+
+/// Whether `problem` means that the access token is revoked or expired
+pub(crate) fn is_token_problem(problem: &str) -> bool {
+  TOKEN_PROBLEMS.contains(&problem)
+}
+
+/// Whether a response of `status` may carry an OAuth problem report
+pub(crate) fn is_problem_status(status: StatusCode) -> bool {
+  status == StatusCode::BAD_REQUEST || status == StatusCode::UNAUTHORIZED
+}
+
+/// Typed error of the OAuth problem reported by a response, or `None` if it reports no `oauth_problem`
+pub(crate) fn parse_problem(
+  status: StatusCode,
+  headers: &HeaderMap,
+  body: &[u8],
+) -> Option<OauthError> {
+  let mut params = problem_params(headers, body);
+  let problem = params.remove("oauth_problem")?;
+  let advice = params.remove("oauth_problem_advice");
+
+  Some(match problem.as_str() {
+    "signature_invalid" => OauthError::SignatureInvalid { status, advice },
+    "timestamp_refused" => {
+      let acceptable_timestamps = params
+        .get("oauth_acceptable_timestamps")
+        .and_then(|range| parse_timestamp_range(range));
+      OauthError::TimestampRefused {
+        status,
+        advice,
+        acceptable_timestamps,
+        server_time: acceptable_timestamps
+          .map(|(min, max)| min + (max - min) / 2)
+          .or_else(|| date_header(headers)),
+      }
+    }
+    "nonce_used" => OauthError::NonceUsed { status, advice },
+    "consumer_key_unknown" => OauthError::ConsumerKeyUnknown { status, advice },
+    _ if is_token_problem(&problem) => OauthError::TokenRejected {
+      status,
+      problem,
+      advice,
+    },
+    _ => OauthError::OauthProblem {
+      status,
+      problem,
+      advice,
+    },
+  })
+}
+
+/// Error of a failed response, which is `InvalidRequest` with the raw body if no `oauth_problem` is reported
+pub(crate) fn problem_error(status: StatusCode, headers: &HeaderMap, body: &[u8]) -> OauthError {
+  parse_problem(status, headers, body).unwrap_or_else(|| OauthError::InvalidRequest {
+    problem: String::from_utf8_lossy(body).to_string(),
+  })
+}
+
+/// Error of a failed blocking response, reading its body
+pub(crate) fn response_error(res: reqwest::blocking::Response) -> Result<OauthError, OauthError> {
+  let (status, headers) = (res.status(), res.headers().clone());
+  Ok(problem_error(status, &headers, &res.bytes()?))
+}
+
+/// Error of a failed non-blocking response, reading its body
+#[cfg(feature = "async")]
+pub(crate) async fn async_response_error(res: reqwest::Response) -> Result<OauthError, OauthError> {
+  let (status, headers) = (res.status(), res.headers().clone());
+  Ok(problem_error(status, &headers, &res.bytes().await?))
+}
+
+/// Parse `oauth_acceptable_timestamps`, which is formatted as `<min>-<max>`
+fn parse_timestamp_range(range: &str) -> Option<(i64, i64)> {
+  let (min, max) = range.split_once('-')?;
+  Some((min.trim().parse().ok()?, max.trim().parse().ok()?))
+}
+
+/// Time of `Date` header in seconds since the Unix epoch
+pub(crate) fn date_header(headers: &HeaderMap) -> Option<i64> {
+  let date = headers.get(DATE)?.to_str().ok()?;
+  DateTime::parse_from_rfc2822(date)
+    .ok()
+    .map(|date| date.timestamp())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_problem_params() {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+      WWW_AUTHENTICATE,
+      r#"OAuth realm="https://www.hatena.com", oauth_problem="token_rejected""#
+        .parse()
+        .unwrap(),
+    );
+    let params = problem_params(
+      &headers,
+      b"oauth_problem=signature_invalid&oauth_problem_advice=check+the+secret\n",
+    );
+    assert_eq!(params["oauth_problem"], "token_rejected");
+    assert_eq!(params["oauth_problem_advice"], "check the secret");
+    assert!(is_token_problem(&params["oauth_problem"]));
+
+    let params = problem_params(&HeaderMap::new(), b"Unauthorized");
+    assert!(params.is_empty());
+  }
+
+  #[test]
+  fn test_problem_params_quoted_comma() {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+      WWW_AUTHENTICATE,
+      r#"OAuth realm="https://www.hatena.com",stray, oauth_problem="timestamp_refused", oauth_problem_advice="check the clock, then retry \"later\"", oauth_acceptable_timestamps=1000-1600"#
+        .parse()
+        .unwrap(),
+    );
+    let params = problem_params(&headers, b"");
+    assert_eq!(params.len(), 4);
+    assert_eq!(params["realm"], "https://www.hatena.com");
+    assert_eq!(params["oauth_problem"], "timestamp_refused");
+    assert_eq!(
+      params["oauth_problem_advice"],
+      r#"check the clock, then retry "later""#
+    );
+    assert_eq!(params["oauth_acceptable_timestamps"], "1000-1600");
+  }
+
+  #[test]
+  fn test_parse_problem() {
+    let status = StatusCode::UNAUTHORIZED;
+    let headers = HeaderMap::new();
+
+    assert!(matches!(
+      parse_problem(status, &headers, b"oauth_problem=signature_invalid"),
+      Some(OauthError::SignatureInvalid { advice: None, .. })
+    ));
+    assert!(matches!(
+      parse_problem(
+        status,
+        &headers,
+        b"oauth_problem=timestamp_refused&oauth_acceptable_timestamps=1000-1600"
+      ),
+      Some(OauthError::TimestampRefused {
+        acceptable_timestamps: Some((1000, 1600)),
+        server_time: Some(1300),
+        ..
+      })
+    ));
+    let mut date = HeaderMap::new();
+    date.insert(DATE, "Thu, 01 Jan 1970 00:33:20 GMT".parse().unwrap());
+    assert!(matches!(
+      parse_problem(status, &date, b"oauth_problem=timestamp_refused"),
+      Some(OauthError::TimestampRefused {
+        acceptable_timestamps: None,
+        server_time: Some(2000),
+        ..
+      })
+    ));
+    assert!(matches!(
+      parse_problem(status, &headers, b"oauth_problem=nonce_used"),
+      Some(OauthError::NonceUsed { .. })
+    ));
+    assert!(matches!(
+      parse_problem(status, &headers, b"oauth_problem=consumer_key_unknown"),
+      Some(OauthError::ConsumerKeyUnknown { .. })
+    ));
+    assert!(matches!(
+      parse_problem(status, &headers, b"oauth_problem=token_expired"),
+      Some(OauthError::TokenRejected { problem, .. }) if problem == "token_expired"
+    ));
+    assert!(matches!(
+      parse_problem(status, &headers, b"oauth_problem=permission_denied"),
+      Some(OauthError::OauthProblem { problem, .. }) if problem == "permission_denied"
+    ));
+    assert!(matches!(
+      problem_error(StatusCode::BAD_REQUEST, &headers, b"Bad Request"),
+      OauthError::InvalidRequest { problem } if problem == "Bad Request"
+    ));
+  }
+}
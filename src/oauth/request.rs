@@ -0,0 +1,453 @@
+use std::time::Duration;
+
+use reqwest::blocking::Response;
+use reqwest::header::{HeaderMap, AUTHORIZATION, CONTENT_TYPE};
+use reqwest::{Method, StatusCode, Version};
+use url::Url;
+
+use crate::oauth::error::*;
+use crate::oauth::oauth::*;
+use crate::oauth::problem::*;
+use crate::oauth::HatenaOauth;
+
+const FORM_CONTENT_TYPE: &str = "application/x-www-form-urlencoded";
+
+/// Description of an HTTP request to be signed with an access token
+#[derive(Debug, Clone)]
+pub struct HatenaRequest {
+  /// HTTP method
+  pub method: Method,
+  /// URL, which may contain a query string
+  pub url: String,
+  /// Extra headers
+  pub headers: Vec<(String, String)>,
+  /// Query parameters appended to `url`
+  pub query: Vec<(String, String)>,
+  /// Body bytes
+  pub body: Option<Vec<u8>>,
+  /// Timeout of this request
+  pub timeout: Option<Duration>,
+}
+
+impl HatenaRequest {
+  pub fn new(method: Method, url: &str) -> Self {
+    Self {
+      method,
+      url: url.to_string(),
+      headers: vec![],
+      query: vec![],
+      body: None,
+      timeout: None,
+    }
+  }
+
+  /// URL with `query` appended
+  pub fn full_url(&self) -> Result<Url, OauthError> {
+    let mut url = Url::parse(&self.url).map_err(|_| OauthError::InvalidUrl {
+      url: self.url.clone(),
+    })?;
+    if !self.query.is_empty() {
+      url.query_pairs_mut().extend_pairs(&self.query);
+    }
+
+    Ok(url)
+  }
+
+  /// Body to be included in the signature, which exists only if it is `application/x-www-form-urlencoded`
+  pub fn form_body(&self) -> Option<&str> {
+    let is_form = self.headers.iter().any(|(k, v)| {
+      k.eq_ignore_ascii_case(CONTENT_TYPE.as_str())
+        && v.split(';').next().unwrap_or("").trim() == FORM_CONTENT_TYPE
+    });
+    if !is_form {
+      return None;
+    }
+
+    self
+      .body
+      .as_ref()
+      .and_then(|body| std::str::from_utf8(body).ok())
+  }
+
+  /// Sign this request and build a value of `Authorization` header
+  pub(crate) fn authorization(&self, req_token: &RequestToken) -> Result<String, OauthError> {
+    Ok(req_token.to_header_string(
+      self.full_url()?.as_str(),
+      self.method.as_str(),
+      None,
+      self.form_body(),
+    ))
+  }
+
+  /// Sign this request and set `Authorization` header, replacing the previous signature if exists
+  pub(crate) fn sign(&mut self, req_token: &RequestToken) -> Result<(), OauthError> {
+    let authorization = self.authorization(req_token)?;
+    self.replace_header(AUTHORIZATION.as_str(), &authorization);
+    Ok(())
+  }
+
+  /// Build a blocking request
+  pub(crate) fn to_blocking(
+    &self,
+    client: &reqwest::blocking::Client,
+  ) -> Result<reqwest::blocking::RequestBuilder, OauthError> {
+    let mut builder = client.request(self.method.clone(), self.full_url()?);
+    for (key, value) in &self.headers {
+      builder = builder.header(key.as_str(), value.as_str());
+    }
+    if let Some(body) = &self.body {
+      builder = builder.body(body.clone());
+    }
+    if let Some(timeout) = self.timeout {
+      builder = builder.timeout(timeout);
+    }
+
+    Ok(builder)
+  }
+
+  /// Build a non-blocking request
+  #[cfg(feature = "async")]
+  pub(crate) fn to_async(
+    &self,
+    client: &reqwest::Client,
+  ) -> Result<reqwest::RequestBuilder, OauthError> {
+    let mut builder = client.request(self.method.clone(), self.full_url()?);
+    for (key, value) in &self.headers {
+      builder = builder.header(key.as_str(), value.as_str());
+    }
+    if let Some(body) = &self.body {
+      builder = builder.body(body.clone());
+    }
+    if let Some(timeout) = self.timeout {
+      builder = builder.timeout(timeout);
+    }
+
+    Ok(builder)
+  }
+
+  /// Add a header
+  pub fn set_header(&mut self, key: &str, value: &str) {
+    self.headers.push((key.to_string(), value.to_string()));
+  }
+
+  /// Set a header, removing the existing ones of the same name
+  pub fn replace_header(&mut self, key: &str, value: &str) {
+    self.headers.retain(|(k, _)| !k.eq_ignore_ascii_case(key));
+    self.set_header(key, value);
+  }
+
+  pub(crate) fn set_form(&mut self, pairs: &[(&str, &str)]) {
+    let body = url::form_urlencoded::Serializer::new(String::new())
+      .extend_pairs(pairs)
+      .finish();
+    self.replace_header(CONTENT_TYPE.as_str(), FORM_CONTENT_TYPE);
+    self.body = Some(body.into_bytes());
+  }
+}
+
+/// Rebuild a response from the parts of a consumed one, so that it can be returned after its body is read.
+///
+/// Note that `url()` of the rebuilt response is not the original one.
+pub(crate) fn rebuild_response<T: From<http::Response<Vec<u8>>>>(
+  status: StatusCode,
+  version: Version,
+  headers: HeaderMap,
+  body: Vec<u8>,
+) -> T {
+  let mut res = http::Response::new(body);
+  *res.status_mut() = status;
+  *res.version_mut() = version;
+  *res.headers_mut() = headers;
+  T::from(res)
+}
+
+/// OAuth problem reported by a response, which the client should recover from before retrying
+///
+/// It returns `None` if the response reports no problem and should be returned as is,
+/// and fails with the problem if the request has already been retried.
+pub(crate) fn problem_to_recover(
+  status: StatusCode,
+  headers: &HeaderMap,
+  body: &[u8],
+  retried: bool,
+) -> Result<Option<OauthError>, OauthError> {
+  match parse_problem(status, headers, body) {
+    Some(err) if retried => Err(err),
+    problem => Ok(problem),
+  }
+}
+
+/// Builder methods which `SignedRequestBuilder` and `AsyncSignedRequestBuilder` share
+macro_rules! impl_request_options {
+  () => {
+    /// Add a header
+    pub fn header(mut self, key: &str, value: &str) -> Self {
+      self.request.set_header(key, value);
+      self
+    }
+
+    /// Append query parameters to the URL. They are included in the signature.
+    pub fn query(mut self, pairs: &[(&str, &str)]) -> Self {
+      self
+        .request
+        .query
+        .extend(pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())));
+      self
+    }
+
+    /// Set body bytes
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+      self.request.body = Some(body.into());
+      self
+    }
+
+    /// Set `application/x-www-form-urlencoded` body. Its parameters are included in the signature.
+    pub fn form(mut self, pairs: &[(&str, &str)]) -> Self {
+      self.request.set_form(pairs);
+      self
+    }
+
+    /// Set timeout of this request
+    pub fn timeout(mut self, timeout: std::time::Duration) -> Self {
+      self.request.timeout = Some(timeout);
+      self
+    }
+
+    /// If true, it fetches access token even if it is cached
+    pub fn force(mut self, force: bool) -> Self {
+      self.force = force;
+      self
+    }
+
+    /// Description of the request built so far
+    pub fn request(&self) -> &crate::oauth::request::HatenaRequest {
+      &self.request
+    }
+  };
+}
+
+#[cfg(feature = "async")]
+pub(crate) use impl_request_options;
+
+/// Builder of a request signed with the access token of `HatenaOauth`
+///
+/// Created by `HatenaOauth::request`.
+pub struct SignedRequestBuilder<'a> {
+  oauth: &'a mut HatenaOauth,
+  request: HatenaRequest,
+  force: bool,
+}
+
+impl<'a> SignedRequestBuilder<'a> {
+  pub(crate) fn new(oauth: &'a mut HatenaOauth, method: Method, url: &str) -> Self {
+    Self {
+      oauth,
+      request: HatenaRequest::new(method, url),
+      force: false,
+    }
+  }
+
+  impl_request_options!();
+
+  /// Sign and send the request
+  ///
+  /// If access token is not cached, it first fetches access token.
+  /// If the access token is rejected as revoked or expired, it authorizes again and retries once
+  /// (or fails with `TokenRevoked` if `HatenaOauth` is not interactive).
+  /// If the timestamp is refused, it learns the clock offset from the server time and retries once.
+  /// Other OAuth problems reported by the response are returned as typed errors (eg. `SignatureInvalid`).
+  pub fn send(mut self) -> Result<Response, OauthError> {
+    let mut force = self.force;
+    let mut retried = false;
+    loop {
+      let res = self.send_once(force)?;
+      if !is_problem_status(res.status()) {
+        return Ok(res);
+      }
+
+      let (status, version, headers) = (res.status(), res.version(), res.headers().clone());
+      let body = res.bytes()?.to_vec();
+      match problem_to_recover(status, &headers, &body, retried)? {
+        Some(err) => self.oauth.recover(err)?,
+        None => return Ok(rebuild_response(status, version, headers, body)),
+      }
+      force = false;
+      retried = true;
+    }
+  }
+
+  fn send_once(&mut self, force: bool) -> Result<Response, OauthError> {
+    let req_token = self.oauth.access_request_token(force)?;
+    self.request.sign(&req_token)?;
+
+    Ok(self.request.to_blocking(&self.oauth.client)?.send()?)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::endpoints::HatenaEndpoints;
+  use crate::oauth::clock::FixedClock;
+  use crate::oauth::consts::OauthScope;
+  use crate::oauth::store::*;
+  use crate::oauth::token::AccessTokenResponse;
+  use crate::oauth::HatenaConsumerInfo;
+  use crate::test_util::*;
+
+  fn stub_oauth(base: &str) -> HatenaOauth {
+    let consumer_info = HatenaConsumerInfo::new("key", "secret").unwrap();
+    let mut oauth = HatenaOauth::new(
+      vec![OauthScope::ReadPublic],
+      Some(Box::new(|_| Ok("verifier".to_string()))),
+      consumer_info,
+    )
+    .unwrap()
+    .with_browser_launcher(None)
+    .with_endpoints(HatenaEndpoints::from_base_url(base));
+    oauth.core.access_token = Some(AccessTokenResponse {
+      oauth_token: "revoked".into(),
+      oauth_token_secret: "revoked_secret".into(),
+      ..sample_token()
+    });
+    oauth
+  }
+
+  #[test]
+  fn test_form_body_signed_only_for_form() {
+    let mut request = HatenaRequest::new(Method::POST, "https://example.com/post?a=1");
+    request.body = Some(b"<entry />".to_vec());
+    assert!(request.form_body().is_none());
+
+    request.set_form(&[("status", "Hello Ladies + Gentlemen")]);
+    assert_eq!(
+      request.form_body(),
+      Some("status=Hello+Ladies+%2B+Gentlemen")
+    );
+  }
+
+  #[test]
+  fn test_full_url_appends_query() {
+    let mut request = HatenaRequest::new(Method::GET, "https://example.com/list?a=1");
+    request.query.push(("b".into(), "x y".into()));
+    assert_eq!(
+      request.full_url().unwrap().as_str(),
+      "https://example.com/list?a=1&b=x+y"
+    );
+  }
+
+  #[test]
+  fn test_revoked_token_non_interactive() {
+    let (base, server) = spawn_stub_server(vec![response(
+      "401 Unauthorized",
+      &[],
+      "oauth_problem=token_revoked",
+    )]);
+    let store = MemoryTokenStore::new();
+    let mut oauth = stub_oauth(&base)
+      .with_token_store(Box::new(store.clone()))
+      .unwrap()
+      .with_interactive(false);
+    store
+      .save(oauth.core.access_token.as_ref().unwrap())
+      .unwrap();
+
+    let res = oauth.get(&format!("{}/atom/feed", base), false);
+    assert!(matches!(res, Err(OauthError::TokenRevoked { problem }) if problem == "token_revoked"));
+    assert!(oauth.core.access_token.is_none());
+    assert!(store.load().unwrap().is_none());
+    server.join().unwrap();
+  }
+
+  #[test]
+  fn test_revoked_token_reauthorize() {
+    let (base, server) = spawn_stub_server(vec![
+      response(
+        "401 Unauthorized",
+        &[("WWW-Authenticate", "OAuth oauth_problem=\"token_rejected\"")],
+        "",
+      ),
+      response(
+        "200 OK",
+        &[],
+        "oauth_token=req&oauth_token_secret=req_secret&oauth_callback_confirmed=true",
+      ),
+      response(
+        "200 OK",
+        &[],
+        "oauth_token=new&oauth_token_secret=new_secret&url_name=smallkirby&display_name=kirby",
+      ),
+      response("200 OK", &[], "ok"),
+    ]);
+    let mut oauth = stub_oauth(&base);
+
+    let res = oauth.get(&format!("{}/atom/feed", base), false).unwrap();
+    assert_eq!(res.text().unwrap(), "ok");
+    assert_eq!(oauth.core.access_token.as_ref().unwrap().oauth_token, "new");
+
+    let requests = server.join().unwrap();
+    assert_eq!(requests[1].request_line, "POST /oauth/initiate HTTP/1.1");
+    assert!(requests[3]
+      .header("authorization")
+      .unwrap()
+      .contains("oauth_token=\"new\""));
+  }
+
+  #[test]
+  fn test_unauthorized_without_problem_is_returned() {
+    let (base, server) = spawn_stub_server(vec![response("401 Unauthorized", &[], "denied")]);
+    let mut oauth = stub_oauth(&base);
+
+    let res = oauth.get(&format!("{}/atom/feed", base), false).unwrap();
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(res.text().unwrap(), "denied");
+    assert!(oauth.core.access_token.is_some());
+    server.join().unwrap();
+  }
+
+  #[test]
+  fn test_problem_is_typed_error() {
+    let (base, server) = spawn_stub_server(vec![response(
+      "401 Unauthorized",
+      &[],
+      "oauth_problem=signature_invalid&oauth_problem_advice=check+the+secret",
+    )]);
+    let mut oauth = stub_oauth(&base);
+
+    let res = oauth.get(&format!("{}/atom/feed", base), false);
+    assert!(matches!(
+      res,
+      Err(OauthError::SignatureInvalid { status: StatusCode::UNAUTHORIZED, advice: Some(advice) })
+        if advice == "check the secret"
+    ));
+    assert!(oauth.core.access_token.is_some());
+    server.join().unwrap();
+  }
+
+  #[test]
+  fn test_timestamp_refused_adjusts_clock() {
+    let (base, server) = spawn_stub_server(vec![
+      response(
+        "401 Unauthorized",
+        &[],
+        "oauth_problem=timestamp_refused&oauth_acceptable_timestamps=1900-2100",
+      ),
+      response("200 OK", &[], "ok"),
+    ]);
+    let mut oauth = stub_oauth(&base).with_clock(std::sync::Arc::new(FixedClock(1000)));
+
+    let res = oauth.get(&format!("{}/atom/feed", base), false).unwrap();
+    assert_eq!(res.text().unwrap(), "ok");
+    assert_eq!(oauth.clock_offset(), 1000);
+
+    let requests = server.join().unwrap();
+    assert!(requests[0]
+      .header("authorization")
+      .unwrap()
+      .contains("oauth_timestamp=\"1000\""));
+    assert!(requests[1]
+      .header("authorization")
+      .unwrap()
+      .contains("oauth_timestamp=\"2000\""));
+  }
+}
@@ -0,0 +1,160 @@
+use std::fmt;
+use std::sync::Arc;
+
+use ring::hmac::{self, HMAC_SHA1_FOR_LEGACY_USE_ONLY, HMAC_SHA256};
+
+use crate::oauth::util::*;
+use crate::secret::SecretString;
+
+/// Signer of RSA-SHA1, which is RSASSA-PKCS1-v1_5 with SHA-1 (RFC 5849 section 3.4.3)
+///
+/// Implement it with an RSA library (eg. `rsa` or `openssl` crates) holding the private key of the consumer.
+pub trait RsaSha1Signer: Send + Sync {
+  /// Sign `message` (a signature base string) and return the raw signature
+  fn sign(&self, message: &[u8]) -> Vec<u8>;
+}
+
+/// Method to sign requests, which is `oauth_signature_method` (RFC 5849 section 3.4)
+///
+/// Hatena accepts only `HmacSha1`. The others are for other providers and local servers.
+#[derive(Clone, Default)]
+pub enum SignatureMethod {
+  /// HMAC-SHA1 keyed by the consumer secret and token secret
+  #[default]
+  HmacSha1,
+  /// HMAC-SHA256 keyed by the consumer secret and token secret
+  HmacSha256,
+  /// The consumer secret and token secret as they are, which must be sent only over TLS
+  Plaintext,
+  /// RSA-SHA1 with the private key of the consumer. Secrets are not used.
+  RsaSha1(Arc<dyn RsaSha1Signer>),
+}
+
+impl SignatureMethod {
+  /// Value of `oauth_signature_method`
+  pub fn name(&self) -> &'static str {
+    match self {
+      Self::HmacSha1 => "HMAC-SHA1",
+      Self::HmacSha256 => "HMAC-SHA256",
+      Self::Plaintext => "PLAINTEXT",
+      Self::RsaSha1(_) => "RSA-SHA1",
+    }
+  }
+
+  /// Value of `oauth_signature`, before percent-encoded
+  ///
+  /// # Arguments
+  ///
+  /// * `consumer_secret` - Consumer secret
+  /// * `token_secret` - Secret of the request token or access token, which is empty if none
+  /// * `base_string` - Signature base string built by `signature_base_string`, which `Plaintext` ignores
+  pub fn sign(&self, consumer_secret: &str, token_secret: &str, base_string: &str) -> String {
+    match self {
+      Self::HmacSha1 => hmac_sha1_signature(consumer_secret, token_secret, base_string),
+      Self::HmacSha256 => hmac_signature(HMAC_SHA256, consumer_secret, token_secret, base_string),
+      Self::Plaintext => signing_key(consumer_secret, token_secret)
+        .expose_secret()
+        .to_string(),
+      Self::RsaSha1(signer) => base64::encode(signer.sign(base_string.as_bytes())),
+    }
+  }
+}
+
+impl fmt::Debug for SignatureMethod {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "SignatureMethod({})", self.name())
+  }
+}
+
+impl fmt::Display for SignatureMethod {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.name())
+  }
+}
+
+/// Sign `base_string` by HMAC-SHA1 (RFC 5849 section 3.4.2)
+///
+/// # Arguments
+///
+/// * `consumer_secret` - Consumer secret
+/// * `token_secret` - Secret of the request token or access token, which is empty if none
+/// * `base_string` - Signature base string built by `signature_base_string`
+fn hmac_sha1_signature(consumer_secret: &str, token_secret: &str, base_string: &str) -> String {
+  hmac_signature(
+    HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+    consumer_secret,
+    token_secret,
+    base_string,
+  )
+}
+
+fn hmac_signature(
+  algorithm: hmac::Algorithm,
+  consumer_secret: &str,
+  token_secret: &str,
+  base_string: &str,
+) -> String {
+  let key = signing_key(consumer_secret, token_secret);
+  let sign_key = hmac::Key::new(algorithm, key.expose_secret().as_bytes());
+  let signature = hmac::sign(&sign_key, base_string.as_bytes());
+
+  base64::encode(signature.as_ref())
+}
+
+/// Key of HMAC, which is also the signature of PLAINTEXT (RFC 5849 section 3.4.4)
+fn signing_key(consumer_secret: &str, token_secret: &str) -> SecretString {
+  SecretString::new(format!(
+    "{}&{}",
+    encode(consumer_secret),
+    encode(token_secret)
+  ))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::Mutex;
+
+  const BASE_STRING: &str = "GET&http%3A%2F%2Fexample.com%2F&a%3D1";
+
+  /// RSA signer which records messages and returns a fixed signature
+  struct RecordingSigner(Mutex<Vec<Vec<u8>>>);
+
+  impl RsaSha1Signer for RecordingSigner {
+    fn sign(&self, message: &[u8]) -> Vec<u8> {
+      self.0.lock().unwrap().push(message.to_vec());
+      vec![0xde, 0xad, 0xbe, 0xef]
+    }
+  }
+
+  #[test]
+  fn test_signature_methods() {
+    let sign =
+      |method: SignatureMethod| method.sign("kd94hf93k423kf44", "pfkkdhi9sl3r4s00", BASE_STRING);
+
+    assert_eq!(
+      sign(SignatureMethod::HmacSha1),
+      "Ajgu7ZcJRr+1vaa2ErWqCbdSZEM="
+    );
+    assert_eq!(
+      sign(SignatureMethod::HmacSha256),
+      "+oiU1aQcQwRKTDlwW0C0jbNliXe7/b7CLYMxdeCC2zM="
+    );
+    assert_eq!(
+      sign(SignatureMethod::Plaintext),
+      "kd94hf93k423kf44&pfkkdhi9sl3r4s00"
+    );
+    assert_eq!(
+      SignatureMethod::Plaintext.sign("c s", "", BASE_STRING),
+      "c%20s&"
+    );
+
+    let signer = Arc::new(RecordingSigner(Mutex::new(vec![])));
+    assert_eq!(sign(SignatureMethod::RsaSha1(signer.clone())), "3q2+7w==");
+    assert_eq!(*signer.0.lock().unwrap(), vec![BASE_STRING.as_bytes()]);
+    assert_eq!(
+      format!("{:?}", SignatureMethod::RsaSha1(signer)),
+      "SignatureMethod(RSA-SHA1)"
+    );
+  }
+}
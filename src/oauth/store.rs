@@ -0,0 +1,242 @@
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::oauth::error::*;
+use crate::oauth::token::*;
+
+/// Name of the directory under the XDG config dir used by this crate
+pub const CONFIG_DIR_NAME: &str = "hatena-rs";
+/// Name of the file used by `FileTokenStore` by default
+pub const TOKEN_FILE_NAME: &str = "access_token";
+
+/// Persistent storage of an access token
+///
+/// `HatenaOauth` loads a cached token from the store on construction and saves freshly minted tokens to it.
+pub trait TokenStore: Send + Sync {
+  /// Load a stored access token. Returns `None` if nothing is stored.
+  fn load(&self) -> Result<Option<AccessTokenResponse>, OauthError>;
+
+  /// Save an access token, replacing the stored one if exists.
+  fn save(&self, token: &AccessTokenResponse) -> Result<(), OauthError>;
+
+  /// Remove a stored access token.
+  fn clear(&self) -> Result<(), OauthError>;
+}
+
+/// Get the configuration directory of this crate.
+///
+/// It is `$XDG_CONFIG_HOME/hatena-rs` if `XDG_CONFIG_HOME` is set, otherwise `$HOME/.config/hatena-rs`.
+pub fn default_config_dir() -> Option<PathBuf> {
+  let base = match env::var("XDG_CONFIG_HOME") {
+    Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+    _ => PathBuf::from(env::var("HOME").ok().filter(|home| !home.is_empty())?).join(".config"),
+  };
+
+  Some(base.join(CONFIG_DIR_NAME))
+}
+
+/// Token store backed by a file
+///
+/// The token is stored in the same form-urlencoded format as Hatena's access token response.
+pub struct FileTokenStore {
+  path: PathBuf,
+}
+
+impl FileTokenStore {
+  /// Create a token store backed by the file at `path`
+  pub fn new(path: impl Into<PathBuf>) -> Self {
+    Self { path: path.into() }
+  }
+
+  /// Create a token store backed by `access_token` file under the XDG config dir.
+  ///
+  /// It returns `TokenStoreFailure` error if neither `XDG_CONFIG_HOME` nor `HOME` is set.
+  pub fn from_default_path() -> Result<Self, OauthError> {
+    Ok(Self::new(default_file_path(TOKEN_FILE_NAME)?))
+  }
+
+  /// Path to the backing file
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+}
+
+impl TokenStore for FileTokenStore {
+  fn load(&self) -> Result<Option<AccessTokenResponse>, OauthError> {
+    let text = match fs::read_to_string(&self.path) {
+      Ok(text) => text,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+      Err(e) => return Err(e.into()),
+    };
+
+    Ok(Some(AccessTokenResponse::from(text.trim())?))
+  }
+
+  fn save(&self, token: &AccessTokenResponse) -> Result<(), OauthError> {
+    Ok(write_private(
+      &self.path,
+      token.to_form_string().as_bytes(),
+    )?)
+  }
+
+  fn clear(&self) -> Result<(), OauthError> {
+    Ok(remove_if_exists(&self.path)?)
+  }
+}
+
+/// Path to `file_name` under `default_config_dir()`, or `NotFound` error if it is unknown
+pub(crate) fn default_file_path(file_name: &str) -> io::Result<PathBuf> {
+  let dir = default_config_dir().ok_or_else(|| {
+    io::Error::new(
+      io::ErrorKind::NotFound,
+      "neither XDG_CONFIG_HOME nor HOME is set",
+    )
+  })?;
+
+  Ok(dir.join(file_name))
+}
+
+/// Replace the file at `path` with `contents`, readable only by the owner
+///
+/// The contents are written to a temporary file created with mode 0600 in the same directory,
+/// which is then renamed over `path`. So the secret is never readable by others, and a reader sees
+/// either the old or new contents.
+pub(crate) fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+  let dir = match path.parent() {
+    Some(dir) if !dir.as_os_str().is_empty() => dir,
+    _ => Path::new("."),
+  };
+  fs::create_dir_all(dir)?;
+  let file_name = path
+    .file_name()
+    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+  let temp = dir.join(format!(
+    ".{}.{:08x}.tmp",
+    file_name.to_string_lossy(),
+    rand::random::<u32>()
+  ));
+
+  let mut options = fs::OpenOptions::new();
+  options.write(true).create_new(true);
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::OpenOptionsExt;
+    options.mode(0o600);
+  }
+  let result = options.open(&temp).and_then(|mut file| {
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&temp, path)
+  });
+  if result.is_err() {
+    let _ = fs::remove_file(&temp);
+  }
+
+  result
+}
+
+/// Remove the file at `path`, which may not exist
+pub(crate) fn remove_if_exists(path: &Path) -> io::Result<()> {
+  match fs::remove_file(path) {
+    Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+    _ => Ok(()),
+  }
+}
+
+/// Token store which keeps the token only in memory
+///
+/// Clones share the same storage, so a test can keep a handle to inspect what `HatenaOauth` saved.
+#[derive(Clone, Default)]
+pub struct MemoryTokenStore {
+  token: Arc<Mutex<Option<AccessTokenResponse>>>,
+}
+
+impl MemoryTokenStore {
+  /// Create an empty store
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Create a store holding `token`
+  pub fn with_token(token: AccessTokenResponse) -> Self {
+    Self {
+      token: Arc::new(Mutex::new(Some(token))),
+    }
+  }
+}
+
+impl TokenStore for MemoryTokenStore {
+  fn load(&self) -> Result<Option<AccessTokenResponse>, OauthError> {
+    Ok(self.token.lock().unwrap().clone())
+  }
+
+  fn save(&self, token: &AccessTokenResponse) -> Result<(), OauthError> {
+    *self.token.lock().unwrap() = Some(token.clone());
+    Ok(())
+  }
+
+  fn clear(&self) -> Result<(), OauthError> {
+    *self.token.lock().unwrap() = None;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::oauth::consts::OauthScope;
+  use crate::test_util::sample_token;
+
+  #[test]
+  fn test_file_token_store() {
+    let path = env::temp_dir()
+      .join(format!("hatena-rs-test-{}", std::process::id()))
+      .join(TOKEN_FILE_NAME);
+    let store = FileTokenStore::new(&path);
+    assert!(store.load().unwrap().is_none());
+
+    store
+      .save(&AccessTokenResponse {
+        oauth_token_secret: "secret=with/special&chars".into(),
+        display_name: "Small Kirby".into(),
+        scopes: Some(vec![OauthScope::ReadPublic, OauthScope::WritePublic]),
+        ..sample_token()
+      })
+      .unwrap();
+    #[cfg(unix)]
+    {
+      use std::os::unix::fs::PermissionsExt;
+      let mode = fs::metadata(&path).unwrap().permissions().mode();
+      assert_eq!(mode & 0o777, 0o600);
+    }
+    let loaded = store.load().unwrap().unwrap();
+    assert_eq!(
+      loaded.oauth_token_secret.expose_secret(),
+      "secret=with/special&chars"
+    );
+    assert_eq!(loaded.display_name, "Small Kirby");
+    assert_eq!(
+      loaded.scopes,
+      Some(vec![OauthScope::ReadPublic, OauthScope::WritePublic])
+    );
+
+    store.clear().unwrap();
+    assert!(store.load().unwrap().is_none());
+    store.clear().unwrap();
+    fs::remove_dir(path.parent().unwrap()).unwrap();
+  }
+
+  #[test]
+  fn test_memory_token_store() {
+    let store = MemoryTokenStore::new();
+    let handle = store.clone();
+    store.save(&sample_token()).unwrap();
+    assert_eq!(handle.load().unwrap().unwrap().url_name, "smallkirby");
+
+    handle.clear().unwrap();
+    assert!(store.load().unwrap().is_none());
+  }
+}
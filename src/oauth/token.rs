@@ -1,109 +1,231 @@
 use std::collections::HashMap;
+use std::fmt;
 
+use crate::oauth::consts::OauthScope;
 use crate::oauth::error::*;
+use crate::oauth::util::*;
+use crate::secret::SecretString;
 
-use percent_encoding::percent_decode;
+use serde::Deserialize;
+use url::form_urlencoded;
 
-#[derive(Debug)]
+/// Fields of a form-urlencoded response of OAuth endpoints
+///
+/// Values are percent-decoded as `application/x-www-form-urlencoded` (`+` is a space).
+#[derive(Clone, Default)]
+pub struct FormResponse {
+  fields: HashMap<String, String>,
+}
+
+/// Only keys are shown, since values include `oauth_token_secret`
+impl fmt::Debug for FormResponse {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let mut keys: Vec<_> = self.fields.keys().collect();
+    keys.sort();
+    f.debug_struct("FormResponse")
+      .field("keys", &keys)
+      .finish_non_exhaustive()
+  }
+}
+
+impl FormResponse {
+  pub fn parse(response: &str) -> Self {
+    Self {
+      fields: form_urlencoded::parse(response.trim().as_bytes())
+        .into_owned()
+        .collect(),
+    }
+  }
+
+  /// Remove and return a field, or `MissingField` error if absent
+  pub fn take(&mut self, field: &str) -> Result<String, OauthError> {
+    self
+      .fields
+      .remove(field)
+      .ok_or_else(|| OauthError::MissingField {
+        field: field.to_string(),
+      })
+  }
+
+  /// Remove and return a field if present
+  pub fn take_optional(&mut self, field: &str) -> Option<String> {
+    self.fields.remove(field)
+  }
+
+  /// Fields not taken yet
+  pub fn into_extra(self) -> HashMap<String, String> {
+    self.fields
+  }
+}
+
+/// Request token
+#[derive(Debug, Clone)]
 pub struct OauthTokenResponse {
   pub oauth_token: String,
-  pub oauth_token_secret: String,
+  pub oauth_token_secret: SecretString,
+  /// Fields other than the above and `oauth_callback_confirmed`
+  pub extra: HashMap<String, String>,
 }
 
 impl OauthTokenResponse {
+  /// Parse a response of the request token endpoint
+  ///
+  /// It fails with `CallbackNotConfirmed` unless `oauth_callback_confirmed` is `true`, as OAuth 1.0a requires.
   pub fn from(response: &str) -> Result<Self, OauthError> {
-    let mut map: HashMap<&str, String> = HashMap::new();
-    for pair in response.split('&') {
-      let mut parts = pair.split('=');
-      let key = parts.next();
-      let value = parts.next();
-      if key.is_none() || value.is_none() {
-        return Err(OauthError::InvalidResponse {
-          response: response.to_string(),
-        });
-      }
-      map.insert(
-        key.unwrap(),
-        percent_decode(value.unwrap().as_bytes())
-          .decode_utf8_lossy()
-          .to_string(),
-      );
+    let mut form = FormResponse::parse(response);
+    let oauth_token = form.take("oauth_token")?;
+    let oauth_token_secret = form.take("oauth_token_secret")?.into();
+    if form.take("oauth_callback_confirmed")? != "true" {
+      return Err(OauthError::CallbackNotConfirmed);
     }
 
-    let oauth_token = map.get("oauth_token").ok_or(OauthError::InvalidResponse {
-      response: response.to_string(),
-    })?;
-    let oauth_token_secret = map
-      .get("oauth_token_secret")
-      .ok_or(OauthError::InvalidResponse {
-        response: response.to_string(),
-      })?;
-
     Ok(Self {
-      oauth_token: oauth_token.to_string(),
-      oauth_token_secret: oauth_token_secret.to_string(),
+      oauth_token,
+      oauth_token_secret,
+      extra: form.into_extra(),
     })
   }
 }
 
+/// Access token
 #[derive(Debug, Clone)]
 pub struct AccessTokenResponse {
   pub oauth_token: String,
-  pub oauth_token_secret: String,
+  pub oauth_token_secret: SecretString,
   pub url_name: String,
   pub display_name: String,
+  /// Scopes granted for the token. `None` if unknown (eg. given by environment variables).
+  pub scopes: Option<Vec<OauthScope>>,
+  /// Fields other than the above
+  pub extra: HashMap<String, String>,
 }
 
 impl AccessTokenResponse {
+  /// Parse a response of the access token endpoint, or a string made by `to_form_string`
   pub fn from(response: &str) -> Result<Self, OauthError> {
-    let mut map: HashMap<&str, String> = HashMap::new();
-    for pair in response.split('&') {
-      let mut parts = pair.split('=');
-      let key = parts.next();
-      let value = parts.next();
-      if key.is_none() || value.is_none() {
-        return Err(OauthError::InvalidResponse {
-          response: response.to_string(),
-        });
-      }
-      map.insert(
-        key.unwrap(),
-        percent_decode(value.unwrap().as_bytes())
-          .decode_utf8_lossy()
-          .to_string(),
-      );
-    }
-
-    let oauth_token = map
-      .get("oauth_token")
-      .ok_or(OauthError::InvalidResponse {
-        response: response.to_string(),
-      })?
-      .to_string();
-    let oauth_token_secret = map
-      .get("oauth_token_secret")
-      .ok_or(OauthError::InvalidResponse {
-        response: response.to_string(),
-      })?
-      .to_string();
-    let url_name = map
-      .get("url_name")
-      .ok_or(OauthError::InvalidResponse {
-        response: response.to_string(),
-      })?
-      .to_string();
-    let display_name = map
-      .get("display_name")
-      .ok_or(OauthError::InvalidResponse {
-        response: response.to_string(),
-      })?
-      .to_string();
+    let mut form = FormResponse::parse(response);
+    let oauth_token = form.take("oauth_token")?;
+    let oauth_token_secret = form.take("oauth_token_secret")?.into();
+    let url_name = form.take("url_name")?;
+    let display_name = form.take("display_name")?;
+    // Not a part of Hatena response, but saved by `to_form_string`
+    let scopes = form
+      .take_optional("scope")
+      .map(|scope| OauthScope::parse_list(&scope))
+      .transpose()?;
 
     Ok(Self {
-      oauth_token_secret,
       oauth_token,
+      oauth_token_secret,
       url_name,
       display_name,
+      scopes,
+      extra: form.into_extra(),
     })
   }
+
+  /// Serialize into the same form-urlencoded format as the response of Hatena, which `from` can parse.
+  ///
+  /// Granted scopes are appended as `scope` if known, followed by extra fields.
+  pub fn to_form_string(&self) -> String {
+    let mut form = format!(
+      "oauth_token={}&oauth_token_secret={}&url_name={}&display_name={}",
+      encode(&self.oauth_token),
+      encode(self.oauth_token_secret.expose_secret()),
+      encode(&self.url_name),
+      encode(&self.display_name),
+    );
+    if let Some(scopes) = &self.scopes {
+      form.push_str(&format!(
+        "&scope={}",
+        encode(&OauthScope::join_list(scopes))
+      ));
+    }
+    let mut extra = self.extra.iter().collect::<Vec<_>>();
+    extra.sort();
+    for (key, value) in extra {
+      form.push_str(&format!("&{}={}", encode(key), encode(value)));
+    }
+
+    form
+  }
+
+  /// Whether the token has any of `required` scopes. It is true if granted scopes are unknown.
+  pub fn has_any_scope(&self, required: &[OauthScope]) -> bool {
+    self
+      .scopes
+      .as_ref()
+      .map_or(true, |scopes| required.iter().any(|s| scopes.contains(s)))
+  }
+}
+
+/// Profile of the user who granted the access token
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct HatenaUser {
+  /// Hatena ID
+  pub url_name: String,
+  /// Nickname shown on Hatena services
+  pub display_name: String,
+  /// URL of the profile icon
+  pub profile_image_url: String,
+}
+
+impl HatenaUser {
+  /// Parse JSON response of `applications/my.json`
+  pub fn from(response: &str) -> Result<Self, OauthError> {
+    serde_json::from_str(response).map_err(|_| OauthError::InvalidResponse {
+      response: response.to_string(),
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_oauth_token_response() {
+    let token = OauthTokenResponse::from(
+      "oauth_token=req%2Btoken&oauth_token_secret=se=cret+with+space&oauth_callback_confirmed=true&extra=",
+    )
+    .unwrap();
+    assert_eq!(token.oauth_token, "req+token");
+    assert_eq!(
+      token.oauth_token_secret.expose_secret(),
+      "se=cret with space"
+    );
+    assert_eq!(token.extra["extra"], "");
+
+    assert!(matches!(
+      OauthTokenResponse::from("oauth_token=req&oauth_token_secret=secret"),
+      Err(OauthError::MissingField { field }) if field == "oauth_callback_confirmed"
+    ));
+    assert!(matches!(
+      OauthTokenResponse::from(
+        "oauth_token=req&oauth_token_secret=secret&oauth_callback_confirmed=false"
+      ),
+      Err(OauthError::CallbackNotConfirmed)
+    ));
+  }
+
+  #[test]
+  fn test_access_token_response() {
+    let response = "oauth_token=token&oauth_token_secret=secret%3D%3D&url_name=smallkirby&display_name=Small+Kirby&expires_in=3600\n";
+    let token = AccessTokenResponse::from(response).unwrap();
+    assert_eq!(token.oauth_token_secret.expose_secret(), "secret==");
+    assert_eq!(token.display_name, "Small Kirby");
+    assert_eq!(token.extra["expires_in"], "3600");
+
+    let restored = AccessTokenResponse::from(&token.to_form_string()).unwrap();
+    assert_eq!(restored.extra, token.extra);
+
+    let debug = format!("{:?}", FormResponse::parse(response));
+    assert!(debug.contains("oauth_token_secret"));
+    assert!(!debug.contains("secret=="));
+
+    assert!(matches!(
+      AccessTokenResponse::from("oauth_token=token&oauth_token_secret=secret&url_name=smallkirby"),
+      Err(OauthError::MissingField { field }) if field == "display_name"
+    ));
+  }
 }
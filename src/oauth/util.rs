@@ -5,3 +5,16 @@ use percent_encoding::percent_encode;
 pub fn encode(s: &str) -> String {
   percent_encode(s.as_bytes(), STRICT_ENCODE_SET).collect()
 }
+
+/// `bytes` without leading and trailing ASCII whitespace, which is `<[u8]>::trim_ascii` of Rust 1.80
+pub fn trim_ascii(bytes: &[u8]) -> &[u8] {
+  let start = bytes
+    .iter()
+    .position(|b| !b.is_ascii_whitespace())
+    .unwrap_or(bytes.len());
+  let end = bytes
+    .iter()
+    .rposition(|b| !b.is_ascii_whitespace())
+    .map_or(start, |i| i + 1);
+  &bytes[start..end]
+}
@@ -0,0 +1,75 @@
+use std::fmt;
+
+use serde::{Deserialize, Deserializer};
+use zeroize::Zeroize;
+
+/// String holding a credential (eg. consumer secret, token secret, API key)
+///
+/// `Debug` and `Display` print `[REDACTED]` instead of the value, and the memory is zeroized on drop.
+/// Use `expose_secret` only where the value is really needed (eg. signing a request).
+#[derive(Clone, PartialEq, Eq, Default)]
+pub struct SecretString(String);
+
+impl SecretString {
+  pub fn new(secret: String) -> Self {
+    Self(secret)
+  }
+
+  /// Raw value of the secret
+  pub fn expose_secret(&self) -> &str {
+    &self.0
+  }
+}
+
+impl From<String> for SecretString {
+  fn from(secret: String) -> Self {
+    Self::new(secret)
+  }
+}
+
+impl From<&str> for SecretString {
+  fn from(secret: &str) -> Self {
+    Self::new(secret.to_string())
+  }
+}
+
+impl<'de> Deserialize<'de> for SecretString {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    String::deserialize(deserializer).map(Self::new)
+  }
+}
+
+impl fmt::Debug for SecretString {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "SecretString([REDACTED])")
+  }
+}
+
+impl fmt::Display for SecretString {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "[REDACTED]")
+  }
+}
+
+impl Drop for SecretString {
+  fn drop(&mut self) {
+    self.0.zeroize();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::oauth::HatenaConsumerInfo;
+
+  #[test]
+  fn test_redacted() {
+    let secret = SecretString::from("token_secret");
+    assert_eq!(format!("{:?}", secret), "SecretString([REDACTED])");
+    assert_eq!(secret.to_string(), "[REDACTED]");
+    assert_eq!(secret.expose_secret(), "token_secret");
+
+    let consumer_info = HatenaConsumerInfo::new("key", "s3cr3t").unwrap();
+    assert!(!format!("{:?}", consumer_info).contains("s3cr3t"));
+  }
+}
@@ -0,0 +1,5 @@
+pub mod cassette;
+mod http;
+pub mod mock_server;
+
+pub use crate::testing::http::{HttpRequest, HttpResponse, TestServer};
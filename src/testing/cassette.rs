@@ -0,0 +1,624 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use reqwest::header::{HeaderName, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
+use reqwest::redirect::Policy;
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use url::form_urlencoded;
+
+use crate::endpoints::HatenaEndpoints;
+use crate::oauth::util::trim_ascii;
+use crate::oauth::RequestToken;
+use crate::secret::SecretString;
+use crate::testing::http::*;
+
+/// Set to record interactions instead of replaying them in `Cassette::from_env`
+pub const ENV_CASSETTE_RECORD: &str = "HATENA_CASSETTE_RECORD";
+
+/// Value written in place of scrubbed secrets
+pub const SCRUBBED: &str = "SCRUBBED";
+
+/// Headers whose values are always scrubbed
+const SECRET_HEADERS: [&str; 4] = ["authorization", "x-wsse", "cookie", "set-cookie"];
+
+/// Headers which are not recorded, because they depend on the connection
+const SKIPPED_HEADERS: [&str; 5] = [
+  "host",
+  "content-length",
+  "connection",
+  "transfer-encoding",
+  "accept-encoding",
+];
+
+/// Form fields whose values are scrubbed in queries and bodies
+const SECRET_FIELDS: [&str; 3] = ["oauth_token", "oauth_token_secret", "oauth_verifier"];
+
+#[derive(Debug, Error)]
+pub enum CassetteError {
+  #[error("failed to access cassette: {path:?}")]
+  IoFailure {
+    path: PathBuf,
+    #[source]
+    source: io::Error,
+  },
+
+  #[error("failed to parse cassette")]
+  ParseFailure(#[from] serde_json::Error),
+
+  #[error("failed to start cassette server")]
+  ServerFailure(#[source] io::Error),
+
+  #[error("requests not found in cassette: {requests:?}")]
+  Unmatched { requests: Vec<String> },
+
+  #[error("{count} recorded interactions were not replayed")]
+  Unused { count: usize },
+}
+
+/// Whether a cassette talks to Hatena or not
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CassetteMode {
+  /// Forward requests to Hatena and save the interactions
+  Record,
+  /// Answer requests by the saved interactions without network
+  Replay,
+}
+
+/// Pair of a request and its response saved in a cassette
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Interaction {
+  pub request: RecordedRequest,
+  pub response: RecordedResponse,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedRequest {
+  pub method: String,
+  pub path: String,
+  #[serde(default)]
+  pub query: String,
+  #[serde(default)]
+  pub headers: Vec<(String, String)>,
+  #[serde(default)]
+  pub body: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedResponse {
+  pub status: u16,
+  #[serde(default)]
+  pub headers: Vec<(String, String)>,
+  #[serde(default)]
+  pub body: String,
+  /// Whether `body` is encoded in Base64, which is used for non UTF-8 bodies
+  #[serde(default, skip_serializing_if = "is_false")]
+  pub base64: bool,
+}
+
+/// Recording and replaying proxy of Hatena for offline tests
+///
+/// Point clients to `endpoints()`. In `Record` mode, requests are forwarded to Hatena and saved with secrets scrubbed.
+/// OAuth requests are signed again for the upstream URL, so `with_consumer` is needed to record them.
+/// In `Replay` mode, requests are answered by the saved interactions of the same method, path, query and body,
+/// ignoring `oauth_*` parameters, signatures, values of secret fields and multipart bodies.
+pub struct Cassette {
+  path: PathBuf,
+  mode: CassetteMode,
+  server: TestServer,
+  state: Arc<Mutex<CassetteState>>,
+}
+
+struct CassetteState {
+  mode: CassetteMode,
+  interactions: Vec<Interaction>,
+  used: Vec<bool>,
+  unmatched: Vec<String>,
+  /// Literal secrets and their placeholders
+  secrets: Vec<(String, String)>,
+  upstream: Box<dyn Fn(&str) -> String + Send>,
+  consumer: Option<(String, SecretString)>,
+  /// Token secrets keyed by tokens, learned from responses
+  token_secrets: HashMap<String, SecretString>,
+}
+
+impl Cassette {
+  /// Start recording to a cassette at `path`, which is written by `finish`
+  pub fn record(path: impl Into<PathBuf>) -> Result<Self, CassetteError> {
+    Self::start(path.into(), CassetteMode::Record, vec![])
+  }
+
+  /// Start replaying a cassette at `path`
+  pub fn replay(path: impl Into<PathBuf>) -> Result<Self, CassetteError> {
+    let path = path.into();
+    let text = fs::read_to_string(&path).map_err(|source| CassetteError::IoFailure {
+      path: path.clone(),
+      source,
+    })?;
+    let interactions = serde_json::from_str(&text)?;
+
+    Self::start(path, CassetteMode::Replay, interactions)
+  }
+
+  /// Record if `HATENA_CASSETTE_RECORD` is set, otherwise replay
+  pub fn from_env(path: impl Into<PathBuf>) -> Result<Self, CassetteError> {
+    match env::var(ENV_CASSETTE_RECORD) {
+      Ok(value) if !value.is_empty() && value != "0" => Self::record(path),
+      _ => Self::replay(path),
+    }
+  }
+
+  fn start(
+    path: PathBuf,
+    mode: CassetteMode,
+    interactions: Vec<Interaction>,
+  ) -> Result<Self, CassetteError> {
+    let state = Arc::new(Mutex::new(CassetteState {
+      mode,
+      used: vec![false; interactions.len()],
+      interactions,
+      unmatched: vec![],
+      secrets: vec![],
+      upstream: Box::new(hatena_upstream),
+      consumer: None,
+      token_secrets: HashMap::new(),
+    }));
+
+    let handler_state = state.clone();
+    let server = TestServer::spawn(move |request| handler_state.lock().unwrap().handle(request))
+      .map_err(CassetteError::ServerFailure)?;
+
+    Ok(Self {
+      path,
+      mode,
+      server,
+      state,
+    })
+  }
+
+  /// Consumer key and secret to sign OAuth requests again when recording
+  pub fn with_consumer(self, consumer_key: &str, consumer_secret: &str) -> Self {
+    self.state.lock().unwrap().consumer = Some((consumer_key.into(), consumer_secret.into()));
+    self.with_secret(consumer_key, "consumer_key")
+  }
+
+  /// Access token which the client already has, to sign OAuth requests again when recording
+  pub fn with_token(self, oauth_token: &str, oauth_token_secret: &str) -> Self {
+    self
+      .state
+      .lock()
+      .unwrap()
+      .token_secrets
+      .insert(oauth_token.into(), oauth_token_secret.into());
+    self.with_secret(oauth_token, "access_token")
+  }
+
+  /// Replace `secret` with `placeholder` wherever it appears in recorded interactions
+  pub fn with_secret(self, secret: &str, placeholder: &str) -> Self {
+    if !secret.is_empty() {
+      self
+        .state
+        .lock()
+        .unwrap()
+        .secrets
+        .push((secret.into(), placeholder.into()));
+    }
+    self
+  }
+
+  /// Use `upstream`, which maps a path to the base URL of the server, instead of `hatena_upstream`
+  pub fn with_upstream(self, upstream: impl Fn(&str) -> String + Send + 'static) -> Self {
+    self.state.lock().unwrap().upstream = Box::new(upstream);
+    self
+  }
+
+  pub fn mode(&self) -> CassetteMode {
+    self.mode
+  }
+
+  /// Base URL of the proxy
+  pub fn base_url(&self) -> &str {
+    self.server.base_url()
+  }
+
+  /// Endpoints which point to the proxy
+  pub fn endpoints(&self) -> HatenaEndpoints {
+    HatenaEndpoints::from_base_url(self.base_url())
+  }
+
+  /// Stop the proxy
+  ///
+  /// In `Record` mode, it writes the cassette. In `Replay` mode, it fails if any request was not found
+  /// or any interaction was not replayed.
+  pub fn finish(mut self) -> Result<(), CassetteError> {
+    self.server.shutdown();
+    let state = self.state.lock().unwrap();
+
+    match self.mode {
+      CassetteMode::Record => write_cassette(&self.path, &state.interactions),
+      CassetteMode::Replay => {
+        if !state.unmatched.is_empty() {
+          return Err(CassetteError::Unmatched {
+            requests: state.unmatched.clone(),
+          });
+        }
+        match state.used.iter().filter(|used| !**used).count() {
+          0 => Ok(()),
+          count => Err(CassetteError::Unused { count }),
+        }
+      }
+    }
+  }
+}
+
+impl CassetteState {
+  fn handle(&mut self, request: HttpRequest) -> HttpResponse {
+    match self.mode {
+      CassetteMode::Record => self.record(request),
+      CassetteMode::Replay => self.replay(request),
+    }
+  }
+
+  fn replay(&mut self, request: HttpRequest) -> HttpResponse {
+    let key = match_key(
+      &request.method,
+      &request.path,
+      &request.query,
+      request.header(CONTENT_TYPE.as_str()),
+      &self.scrub_form(&String::from_utf8_lossy(&request.body)),
+    );
+    let found = self
+      .interactions
+      .iter()
+      .enumerate()
+      .find(|(i, interaction)| {
+        let recorded = &interaction.request;
+        let content_type = recorded
+          .headers
+          .iter()
+          .find(|(name, _)| name == CONTENT_TYPE.as_str())
+          .map(|(_, value)| value.as_str());
+        !self.used[*i]
+          && match_key(
+            &recorded.method,
+            &recorded.path,
+            &recorded.query,
+            content_type,
+            &recorded.body,
+          ) == key
+      })
+      .map(|(i, _)| i);
+
+    match found {
+      Some(i) => {
+        self.used[i] = true;
+        let recorded = &self.interactions[i].response;
+        let body = if recorded.base64 {
+          base64::decode(&recorded.body).unwrap_or_default()
+        } else {
+          recorded.body.clone().into_bytes()
+        };
+        HttpResponse {
+          status: recorded.status,
+          headers: recorded.headers.clone(),
+          body,
+        }
+      }
+      None => {
+        let target = format!("{} {}", request.method, request.target());
+        self.unmatched.push(target.clone());
+        HttpResponse::new(599, format!("no recorded interaction for {}", target))
+      }
+    }
+  }
+
+  fn record(&mut self, request: HttpRequest) -> HttpResponse {
+    let response = match self.forward(&request) {
+      Ok(response) => response,
+      Err(e) => return HttpResponse::new(502, e.to_string()),
+    };
+    self.learn_token_secret(&response.body);
+
+    let interaction = Interaction {
+      request: RecordedRequest {
+        method: request.method.clone(),
+        path: request.path.clone(),
+        query: self.scrub_form(&request.query),
+        headers: self.scrub_headers(&request.headers),
+        body: self.scrub_form(&String::from_utf8_lossy(&request.body)),
+      },
+      response: match String::from_utf8(response.body.clone()) {
+        Ok(body) => RecordedResponse {
+          status: response.status,
+          headers: self.scrub_headers(&response.headers),
+          body: self.scrub_form(&body),
+          base64: false,
+        },
+        Err(_) => RecordedResponse {
+          status: response.status,
+          headers: self.scrub_headers(&response.headers),
+          body: base64::encode(&response.body),
+          base64: true,
+        },
+      },
+    };
+    self.interactions.push(interaction);
+
+    response
+  }
+
+  /// Send `request` to the upstream, signing it again if it is signed by OAuth
+  fn forward(&self, request: &HttpRequest) -> Result<HttpResponse, reqwest::Error> {
+    let url = format!("{}{}", (self.upstream)(&request.path), request.target());
+    let client = reqwest::blocking::Client::builder()
+      .redirect(Policy::none())
+      .build()?;
+    let method = Method::from_bytes(request.method.as_bytes()).unwrap_or(Method::GET);
+
+    let mut builder = client.request(method, &url);
+    for (name, value) in &request.headers {
+      if SKIPPED_HEADERS.contains(&name.as_str()) || name == AUTHORIZATION.as_str() {
+        continue;
+      }
+      if let (Ok(name), Ok(value)) = (
+        HeaderName::from_bytes(name.as_bytes()),
+        HeaderValue::from_str(value),
+      ) {
+        builder = builder.header(name, value);
+      }
+    }
+    if let Some(authorization) = request.header(AUTHORIZATION.as_str()) {
+      builder = builder.header(AUTHORIZATION, self.resign(request, authorization, &url));
+    }
+    let res = builder.body(request.body.clone()).send()?;
+
+    let status = res.status().as_u16();
+    let headers = res
+      .headers()
+      .iter()
+      .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+      .collect();
+    let body = res.bytes()?.to_vec();
+
+    Ok(HttpResponse {
+      status,
+      headers,
+      body,
+    })
+  }
+
+  /// `Authorization` header signed for `url`, or `authorization` as is if it is not OAuth or no consumer is given
+  fn resign(&self, request: &HttpRequest, authorization: &str, url: &str) -> String {
+    let (consumer_key, consumer_secret) =
+      match (&self.consumer, authorization.strip_prefix("OAuth ")) {
+        (Some(consumer), Some(_)) => consumer,
+        _ => return authorization.to_string(),
+      };
+    let params = oauth_params(authorization);
+    let token = params.get("oauth_token");
+    let token_secret = token.and_then(|token| self.token_secrets.get(token));
+    let signer = RequestToken::new(
+      consumer_key,
+      consumer_secret.expose_secret(),
+      token.map(|token| token.as_str()),
+      token_secret.map(|secret| secret.expose_secret()),
+    );
+
+    // Protocol parameters other than the ones `to_header_string` generates (eg. `oauth_callback`)
+    let extra = params
+      .iter()
+      .filter(|(key, _)| {
+        ![
+          "oauth_consumer_key",
+          "oauth_nonce",
+          "oauth_signature",
+          "oauth_signature_method",
+          "oauth_timestamp",
+          "oauth_token",
+          "oauth_version",
+        ]
+        .contains(&key.as_str())
+      })
+      .map(|(key, value)| (key.as_str(), value.as_str()))
+      .collect::<HashMap<&str, &str>>();
+    let is_form = request
+      .header(CONTENT_TYPE.as_str())
+      .is_some_and(|content_type| content_type.starts_with("application/x-www-form-urlencoded"));
+    let body = String::from_utf8_lossy(&request.body);
+
+    signer.to_header_string(
+      url,
+      &request.method,
+      Some(&extra),
+      if is_form { Some(&body) } else { None },
+    )
+  }
+
+  /// Remember `oauth_token_secret` of a token response to sign the following requests
+  fn learn_token_secret(&mut self, body: &[u8]) {
+    let fields: HashMap<String, String> = form_urlencoded::parse(trim_ascii(body))
+      .into_owned()
+      .collect();
+    if let (Some(token), Some(secret)) =
+      (fields.get("oauth_token"), fields.get("oauth_token_secret"))
+    {
+      self
+        .token_secrets
+        .insert(token.clone(), secret.as_str().into());
+    }
+  }
+
+  fn scrub_headers(&self, headers: &[(String, String)]) -> Vec<(String, String)> {
+    headers
+      .iter()
+      .filter(|(name, _)| !SKIPPED_HEADERS.contains(&name.to_lowercase().as_str()))
+      .map(|(name, value)| {
+        let value = if SECRET_HEADERS.contains(&name.to_lowercase().as_str()) {
+          SCRUBBED.to_string()
+        } else {
+          self.scrub_literals(value)
+        };
+        (name.to_lowercase(), value)
+      })
+      .collect()
+  }
+
+  /// Scrub literal secrets, and values of `SECRET_FIELDS` if `text` is form-urlencoded
+  fn scrub_form(&self, text: &str) -> String {
+    let text = self.scrub_literals(text);
+    text
+      .split('&')
+      .map(|pair| match pair.split_once('=') {
+        Some((key, _)) if SECRET_FIELDS.contains(&key) => format!("{}={}", key, SCRUBBED),
+        _ => pair.to_string(),
+      })
+      .collect::<Vec<_>>()
+      .join("&")
+  }
+
+  fn scrub_literals(&self, text: &str) -> String {
+    let mut text = text.to_string();
+    for (secret, placeholder) in &self.secrets {
+      text = text.replace(secret, placeholder);
+    }
+    text
+  }
+}
+
+/// Base URL of the Hatena server which serves `path`
+pub fn hatena_upstream(path: &str) -> String {
+  if path.starts_with("/oauth/") {
+    "https://www.hatena.com".into()
+  } else if path == "/applications/my.json" {
+    "https://n.hatena.com".into()
+  } else if path.starts_with("/my/") {
+    "https://www.hatena.ne.jp".into()
+  } else {
+    "https://f.hatena.ne.jp".into()
+  }
+}
+
+/// Key to match a request with recorded ones
+///
+/// `oauth_*` query parameters are ignored. `body` is compared as is, after secrets are scrubbed,
+/// except multipart bodies whose boundaries are random.
+fn match_key(
+  method: &str,
+  path: &str,
+  query: &str,
+  content_type: Option<&str>,
+  body: &str,
+) -> (String, String, Vec<(String, String)>, Option<String>) {
+  let mut params = form_urlencoded::parse(query.as_bytes())
+    .into_owned()
+    .filter(|(key, _)| !key.starts_with("oauth_"))
+    .collect::<Vec<_>>();
+  params.sort();
+  let body = match content_type {
+    Some(content_type) if content_type.starts_with("multipart/") => None,
+    _ => Some(body.to_string()),
+  };
+
+  (method.to_uppercase(), path.to_string(), params, body)
+}
+
+fn write_cassette(path: &Path, interactions: &[Interaction]) -> Result<(), CassetteError> {
+  let io_error = |source| CassetteError::IoFailure {
+    path: path.to_path_buf(),
+    source,
+  };
+  if let Some(dir) = path.parent() {
+    fs::create_dir_all(dir).map_err(io_error)?;
+  }
+  fs::write(path, serde_json::to_string_pretty(interactions)? + "\n").map_err(io_error)
+}
+
+fn is_false(value: &bool) -> bool {
+  !value
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_record_and_replay() {
+    let upstream = TestServer::spawn(|request| {
+      assert!(request
+        .header("authorization")
+        .unwrap()
+        .contains("oauth_consumer_key=\"ck_abc123\""));
+      HttpResponse::new(
+        200,
+        "oauth_token=req&oauth_token_secret=req_secret&oauth_callback_confirmed=true",
+      )
+    })
+    .unwrap();
+    let upstream_base = upstream.base_url().to_string();
+    let path = env::temp_dir().join(format!("hatena-rs-cassette-{}.json", std::process::id()));
+
+    let cassette = Cassette::record(&path)
+      .unwrap()
+      .with_consumer("ck_abc123", "consumer_secret")
+      .with_upstream(move |_| upstream_base.clone());
+    let res = reqwest::blocking::Client::new()
+      .post(format!(
+        "{}/oauth/initiate?scope=read_public",
+        cassette.base_url()
+      ))
+      .header(
+        "Authorization",
+        r#"OAuth oauth_callback="oob", oauth_consumer_key="ck_abc123", oauth_signature="local""#,
+      )
+      .send()
+      .unwrap();
+    assert!(res.text().unwrap().contains("req_secret"));
+    cassette.finish().unwrap();
+
+    let recorded = fs::read_to_string(&path).unwrap();
+    assert!(!recorded.contains("req_secret"));
+    assert!(!recorded.contains("ck_abc123"));
+    assert!(recorded.contains("oauth_callback_confirmed=true"));
+
+    let cassette = Cassette::replay(&path).unwrap();
+    let client = reqwest::blocking::Client::new();
+    let res = client
+      .post(format!(
+        "{}/oauth/initiate?scope=read_public",
+        cassette.base_url()
+      ))
+      .body("unexpected")
+      .send()
+      .unwrap();
+    assert_eq!(res.status().as_u16(), 599);
+    let res = client
+      .post(format!(
+        "{}/oauth/initiate?scope=read_public&oauth_nonce=other",
+        cassette.base_url()
+      ))
+      .send()
+      .unwrap();
+    assert_eq!(res.status(), 200);
+    assert_eq!(
+      res.text().unwrap(),
+      "oauth_token=SCRUBBED&oauth_token_secret=SCRUBBED&oauth_callback_confirmed=true"
+    );
+    let res = client
+      .get(format!("{}/atom/feed", cassette.base_url()))
+      .send()
+      .unwrap();
+    assert_eq!(res.status().as_u16(), 599);
+    assert!(matches!(
+      cassette.finish(),
+      Err(CassetteError::Unmatched { requests })
+        if requests == vec!["POST /oauth/initiate?scope=read_public", "GET /atom/feed"]
+    ));
+
+    fs::remove_file(path).unwrap();
+  }
+}
@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use percent_encoding::percent_decode_str;
+use reqwest::StatusCode;
+
+/// Request received by a `TestServer`
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+  pub method: String,
+  /// Path without the query
+  pub path: String,
+  /// Query string without `?`, which is empty if none
+  pub query: String,
+  /// Headers with lowercased names
+  pub headers: Vec<(String, String)>,
+  pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+  pub fn header(&self, name: &str) -> Option<&str> {
+    self
+      .headers
+      .iter()
+      .find(|(key, _)| key.eq_ignore_ascii_case(name))
+      .map(|(_, value)| value.as_str())
+  }
+
+  /// Path followed by the query string, if any
+  pub fn target(&self) -> String {
+    if self.query.is_empty() {
+      self.path.clone()
+    } else {
+      format!("{}?{}", self.path, self.query)
+    }
+  }
+}
+
+/// Response returned by a handler of `TestServer`
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+  pub status: u16,
+  pub headers: Vec<(String, String)>,
+  pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+  pub fn new(status: u16, body: impl Into<Vec<u8>>) -> Self {
+    Self {
+      status,
+      headers: vec![],
+      body: body.into(),
+    }
+  }
+
+  pub fn with_header(mut self, name: &str, value: &str) -> Self {
+    self.headers.push((name.into(), value.into()));
+    self
+  }
+
+  fn write_to(&self, stream: &mut TcpStream) -> io::Result<()> {
+    let reason = StatusCode::from_u16(self.status)
+      .ok()
+      .and_then(|status| status.canonical_reason())
+      .unwrap_or("Unknown");
+    let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason);
+    for (name, value) in &self.headers {
+      if !name.eq_ignore_ascii_case("content-length") && !name.eq_ignore_ascii_case("connection") {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+      }
+    }
+    head.push_str(&format!(
+      "Content-Length: {}\r\nConnection: close\r\n\r\n",
+      self.body.len()
+    ));
+
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(&self.body)?;
+    stream.flush()
+  }
+}
+
+/// HTTP server on 127.0.0.1 which answers every request by a handler, one request per connection
+///
+/// It stops when dropped.
+pub struct TestServer {
+  base_url: String,
+  stopped: Arc<AtomicBool>,
+  handle: Option<JoinHandle<()>>,
+}
+
+impl TestServer {
+  /// Start a server on an ephemeral port
+  ///
+  /// # Arguments
+  ///
+  /// * `handler` - Function which returns a response for each request
+  pub fn spawn(
+    mut handler: impl FnMut(HttpRequest) -> HttpResponse + Send + 'static,
+  ) -> io::Result<Self> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let base_url = format!("http://{}", listener.local_addr()?);
+    let stopped = Arc::new(AtomicBool::new(false));
+
+    let stopped_in_thread = stopped.clone();
+    let handle = thread::spawn(move || {
+      for stream in listener.incoming() {
+        if stopped_in_thread.load(Ordering::SeqCst) {
+          break;
+        }
+        let mut stream = match stream {
+          Ok(stream) => stream,
+          Err(_) => continue,
+        };
+        let response = match read_request(&stream) {
+          Ok(request) => handler(request),
+          Err(e) => HttpResponse::new(400, e.to_string()),
+        };
+        let _ = response.write_to(&mut stream);
+      }
+    });
+
+    Ok(Self {
+      base_url,
+      stopped,
+      handle: Some(handle),
+    })
+  }
+
+  /// Base URL of the server (eg. `http://127.0.0.1:49494`)
+  pub fn base_url(&self) -> &str {
+    &self.base_url
+  }
+
+  /// Stop the server and wait for the handler to finish
+  pub fn shutdown(&mut self) {
+    if let Some(handle) = self.handle.take() {
+      self.stopped.store(true, Ordering::SeqCst);
+      // Wake up `accept`
+      let _ = TcpStream::connect(self.base_url.trim_start_matches("http://"));
+      let _ = handle.join();
+    }
+  }
+}
+
+impl Drop for TestServer {
+  fn drop(&mut self) {
+    self.shutdown();
+  }
+}
+
+fn read_request(stream: &TcpStream) -> io::Result<HttpRequest> {
+  let mut reader = BufReader::new(stream);
+  let mut request_line = String::new();
+  reader.read_line(&mut request_line)?;
+  let mut parts = request_line.split_whitespace();
+  let (method, target) = match (parts.next(), parts.next()) {
+    (Some(method), Some(target)) => (method.to_string(), target.to_string()),
+    _ => {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "malformed request line",
+      ))
+    }
+  };
+
+  let mut headers = vec![];
+  loop {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let line = line.trim_end();
+    if line.is_empty() {
+      break;
+    }
+    if let Some((name, value)) = line.split_once(':') {
+      headers.push((name.trim().to_lowercase(), value.trim().to_string()));
+    }
+  }
+
+  let length = headers
+    .iter()
+    .find(|(name, _)| name == "content-length")
+    .and_then(|(_, value)| value.parse().ok())
+    .unwrap_or(0);
+  let mut body = vec![0; length];
+  reader.read_exact(&mut body)?;
+
+  let (path, query) = match target.split_once('?') {
+    Some((path, query)) => (path.to_string(), query.to_string()),
+    None => (target, String::new()),
+  };
+
+  Ok(HttpRequest {
+    method,
+    path,
+    query,
+    headers,
+    body,
+  })
+}
+
+/// Parameters of `Authorization: OAuth ...` header
+pub(crate) fn oauth_params(authorization: &str) -> HashMap<String, String> {
+  authorization
+    .trim_start_matches("OAuth ")
+    .split(',')
+    .filter_map(|pair| pair.split_once('='))
+    .map(|(key, value)| {
+      let value = value.trim().trim_matches('"');
+      (
+        key.trim().to_string(),
+        percent_decode_str(value).decode_utf8_lossy().to_string(),
+      )
+    })
+    .collect()
+}
@@ -0,0 +1,1049 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use chrono::{NaiveDateTime, TimeZone, Utc};
+use reqwest::header::{CONTENT_TYPE, COOKIE, LOCATION};
+use reqwest::redirect::Policy;
+use scraper::{Html, Selector};
+use url::{form_urlencoded, Url};
+
+use crate::cookie::HatenaCookieSession;
+use crate::endpoints::HatenaEndpoints;
+use crate::oauth::callback::{AuthorizeRequest, GrantPermissionCallback};
+use crate::oauth::clock::*;
+use crate::oauth::consts::{OauthScope, OAUTH_CALLBACK_OOB};
+use crate::oauth::error::OauthError;
+use crate::oauth::signature::SignatureMethod;
+use crate::oauth::token::AccessTokenResponse;
+use crate::oauth::util::trim_ascii;
+use crate::oauth::{signature_base_string, HatenaConsumerInfo};
+use crate::testing::http::*;
+use crate::wsse::{password_digest, WsseCredentials};
+
+pub const MOCK_CONSUMER_KEY: &str = "mock_consumer_key";
+pub const MOCK_CONSUMER_SECRET: &str = "mock_consumer_secret";
+pub const MOCK_USER_NAME: &str = "mockuser";
+pub const MOCK_API_KEY: &str = "mock_api_key";
+pub const MOCK_COOKIE: &str = "mock_rk";
+
+/// Seconds by which `oauth_timestamp` may differ from the server time
+pub const TIMESTAMP_TOLERANCE: i64 = 300;
+
+/// ID of the first photo stored in a mock server, which is incremented for the following ones
+const FIRST_PHOTO_ID: u64 = 20220101000000;
+
+/// Photo stored in a `MockHatena`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MockPhoto {
+  pub id: String,
+  pub title: String,
+  /// Folder given by `dc:subject`, which is empty for the top folder
+  pub folder: String,
+  pub content_type: String,
+  pub data: Vec<u8>,
+}
+
+/// In-process fake of Hatena for end-to-end tests without network
+///
+/// Point clients to `endpoints()`. It serves:
+///
+/// * OAuth `/oauth/initiate`, `/oauth/authorize` and `/oauth/token`, verifying signatures, timestamps and nonces.
+///   Only HMAC-SHA1 is accepted like Hatena, unless `with_signature_methods` is given.
+/// * `/applications/my.json`
+/// * Fotolife AtomPub `/atom/post`, `/atom/edit/{id}` and `/atom/feed`, authenticated by OAuth or WSSE
+/// * Photo list pages `/{user}/{folder}/` and `/my/`, authenticated by the `rk` cookie
+///
+/// Failures are reported as OAuth Problem Reporting like Hatena, so clients see the same errors.
+/// The authorization endpoint grants every request token without asking.
+pub struct MockHatena {
+  server: TestServer,
+  state: Arc<Mutex<MockState>>,
+}
+
+struct MockState {
+  base_url: String,
+  consumer_key: String,
+  consumer_secret: String,
+  url_name: String,
+  display_name: String,
+  api_key: String,
+  cookie: String,
+  clock: Arc<dyn Clock>,
+  /// Methods accepted as `oauth_signature_method`
+  signature_methods: Vec<SignatureMethod>,
+  request_tokens: HashMap<String, PendingToken>,
+  access_tokens: HashMap<String, GrantedToken>,
+  /// Pairs of `oauth_timestamp` and `oauth_nonce` already used
+  nonces: HashSet<(String, String)>,
+  photos: Vec<MockPhoto>,
+  /// Serial number of issued tokens
+  serial: u64,
+  /// Number of photos ever stored, from which photo IDs are generated
+  photo_serial: u64,
+}
+
+/// Request token waiting for authorization or exchange
+struct PendingToken {
+  secret: String,
+  callback: String,
+  scopes: Vec<OauthScope>,
+  verifier: Option<String>,
+}
+
+struct GrantedToken {
+  secret: String,
+  scopes: Vec<OauthScope>,
+}
+
+/// Token which a request must be signed with
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum TokenKind {
+  None,
+  Request,
+  Access,
+}
+
+impl MockHatena {
+  /// Start a mock server on an ephemeral port with `MOCK_*` credentials
+  pub fn start() -> io::Result<Self> {
+    let state = Arc::new(Mutex::new(MockState {
+      base_url: String::new(),
+      consumer_key: MOCK_CONSUMER_KEY.into(),
+      consumer_secret: MOCK_CONSUMER_SECRET.into(),
+      url_name: MOCK_USER_NAME.into(),
+      display_name: MOCK_USER_NAME.into(),
+      api_key: MOCK_API_KEY.into(),
+      cookie: MOCK_COOKIE.into(),
+      clock: Arc::new(SystemClock),
+      signature_methods: vec![SignatureMethod::HmacSha1],
+      request_tokens: HashMap::new(),
+      access_tokens: HashMap::new(),
+      nonces: HashSet::new(),
+      photos: vec![],
+      serial: 0,
+      photo_serial: 0,
+    }));
+
+    let handler_state = state.clone();
+    let server = TestServer::spawn(move |request| handler_state.lock().unwrap().handle(request))?;
+    state.lock().unwrap().base_url = server.base_url().to_string();
+
+    Ok(Self { server, state })
+  }
+
+  /// Accept `consumer_key` and `consumer_secret` instead of `MOCK_CONSUMER_*`
+  pub fn with_consumer(self, consumer_key: &str, consumer_secret: &str) -> Self {
+    {
+      let mut state = self.state.lock().unwrap();
+      state.consumer_key = consumer_key.into();
+      state.consumer_secret = consumer_secret.into();
+    }
+    self
+  }
+
+  /// Authenticated user, instead of `MOCK_USER_NAME`
+  pub fn with_user(self, url_name: &str, display_name: &str) -> Self {
+    {
+      let mut state = self.state.lock().unwrap();
+      state.url_name = url_name.into();
+      state.display_name = display_name.into();
+    }
+    self
+  }
+
+  /// API key accepted by WSSE authentication, instead of `MOCK_API_KEY`
+  pub fn with_api_key(self, api_key: &str) -> Self {
+    self.state.lock().unwrap().api_key = api_key.into();
+    self
+  }
+
+  /// Value of the `rk` cookie of the logged-in session, instead of `MOCK_COOKIE`
+  pub fn with_cookie(self, rk: &str) -> Self {
+    self.state.lock().unwrap().cookie = rk.into();
+    self
+  }
+
+  /// Use `clock` as the server time, which `oauth_timestamp` is checked against
+  pub fn with_clock(self, clock: Arc<dyn Clock>) -> Self {
+    self.state.lock().unwrap().clock = clock;
+    self
+  }
+
+  /// Accept `methods` as `oauth_signature_method`, instead of only HMAC-SHA1
+  ///
+  /// RSA-SHA1 is never accepted, since the server has no public key to verify it.
+  pub fn with_signature_methods(self, methods: Vec<SignatureMethod>) -> Self {
+    self.state.lock().unwrap().signature_methods = methods;
+    self
+  }
+
+  /// Base URL of the server (eg. `http://127.0.0.1:49494`)
+  pub fn base_url(&self) -> &str {
+    self.server.base_url()
+  }
+
+  /// Endpoints which point to the server
+  pub fn endpoints(&self) -> HatenaEndpoints {
+    HatenaEndpoints::from_base_url(self.base_url())
+  }
+
+  /// Consumer info accepted by the server
+  pub fn consumer_info(&self) -> HatenaConsumerInfo {
+    let state = self.state.lock().unwrap();
+    HatenaConsumerInfo::new(&state.consumer_key, &state.consumer_secret).unwrap()
+  }
+
+  /// WSSE credentials of the user accepted by the server
+  pub fn wsse_credentials(&self) -> WsseCredentials {
+    let state = self.state.lock().unwrap();
+    WsseCredentials::new(&state.url_name, &state.api_key)
+  }
+
+  /// Logged-in cookie session of the user, validated against this server
+  pub fn cookie_session(&self) -> HatenaCookieSession {
+    let state = self.state.lock().unwrap();
+    HatenaCookieSession::from_rk(&state.cookie)
+      .with_session_check(&format!("{}/my/", self.base_url()))
+  }
+
+  /// Permission callback which visits the authorization URL and takes the verifier from the page or redirect
+  pub fn grant_permission_callback(&self) -> GrantPermissionCallback {
+    Box::new(visit_authorize_url)
+  }
+
+  /// Issue an access token of `scopes` without the authorization flow
+  pub fn issue_access_token(&self, scopes: &[OauthScope]) -> AccessTokenResponse {
+    let mut state = self.state.lock().unwrap();
+    let (oauth_token, oauth_token_secret) = state.issue_access_token(scopes.to_vec());
+
+    AccessTokenResponse {
+      oauth_token,
+      oauth_token_secret: oauth_token_secret.into(),
+      url_name: state.url_name.clone(),
+      display_name: state.display_name.clone(),
+      scopes: Some(scopes.to_vec()),
+      extra: Default::default(),
+    }
+  }
+
+  /// Revoke an access token, which is rejected with `token_rejected` afterwards
+  pub fn revoke_access_token(&self, oauth_token: &str) {
+    self.state.lock().unwrap().access_tokens.remove(oauth_token);
+  }
+
+  /// Store a photo as if it were posted, and return its ID
+  pub fn add_photo(&self, folder: &str, title: &str, content_type: &str, data: &[u8]) -> String {
+    self
+      .state
+      .lock()
+      .unwrap()
+      .add_photo(folder, title, content_type, data.to_vec())
+  }
+
+  /// Photos stored in the server
+  pub fn photos(&self) -> Vec<MockPhoto> {
+    self.state.lock().unwrap().photos.clone()
+  }
+}
+
+impl MockState {
+  fn handle(&mut self, request: HttpRequest) -> HttpResponse {
+    let user_prefix = format!("/{}/", self.url_name);
+    match (request.method.as_str(), request.path.as_str()) {
+      ("POST", "/oauth/initiate") => self.initiate(&request),
+      ("GET", "/oauth/authorize") => self.authorize(&request),
+      ("POST", "/oauth/token") => self.token(&request),
+      ("GET", "/applications/my.json") => self.user_info(&request),
+      ("POST", "/atom/post") => self.post_photo(&request),
+      ("GET", "/atom/feed") => self.feed(&request),
+      (_, path) if path.starts_with("/atom/edit/") => {
+        let id = path.trim_start_matches("/atom/edit/").to_string();
+        self.edit_photo(&request, &id)
+      }
+      ("GET", "/my/") => self.my_page(&request),
+      // The top page of the user (`/{user}/`) is not served
+      ("GET", path)
+        if path.len() > user_prefix.len()
+          && path.starts_with(&user_prefix)
+          && path.ends_with('/') =>
+      {
+        let folder = path[user_prefix.len()..path.len() - 1].to_string();
+        self.photo_list(&request, &folder)
+      }
+      _ => HttpResponse::new(404, "Not Found"),
+    }
+  }
+
+  fn initiate(&mut self, request: &HttpRequest) -> HttpResponse {
+    let params = match self.verify_oauth(request, TokenKind::None) {
+      Ok(params) => params,
+      Err(res) => return res,
+    };
+    let callback = match params.get("oauth_callback") {
+      Some(callback) => callback.clone(),
+      None => return problem(400, "parameter_absent"),
+    };
+    let scopes = match form_field(&request.body, "scope").map(|s| OauthScope::parse_list(&s)) {
+      Some(Ok(scopes)) => scopes,
+      _ => return problem(400, "parameter_rejected"),
+    };
+
+    let token = self.next_token("request");
+    let secret = format!("{}_secret", token);
+    let body = form_string(&[
+      ("oauth_token", &token),
+      ("oauth_token_secret", &secret),
+      ("oauth_callback_confirmed", "true"),
+    ]);
+    self.request_tokens.insert(
+      token,
+      PendingToken {
+        secret,
+        callback,
+        scopes,
+        verifier: None,
+      },
+    );
+
+    HttpResponse::new(200, body).with_header("Content-Type", "application/x-www-form-urlencoded")
+  }
+
+  fn authorize(&mut self, request: &HttpRequest) -> HttpResponse {
+    let token = form_field(request.query.as_bytes(), "oauth_token").unwrap_or_default();
+    let verifier = self.next_token("verifier");
+    let pending = match self.request_tokens.get_mut(&token) {
+      Some(pending) => pending,
+      None => return HttpResponse::new(400, "Unknown request token"),
+    };
+    pending.verifier = Some(verifier.clone());
+
+    if pending.callback == OAUTH_CALLBACK_OOB {
+      HttpResponse::new(
+        200,
+        format!(
+          r#"<html><body><div id="oauth_verifier">{}</div></body></html>"#,
+          verifier
+        ),
+      )
+      .with_header("Content-Type", "text/html; charset=utf-8")
+    } else {
+      let separator = if pending.callback.contains('?') {
+        '&'
+      } else {
+        '?'
+      };
+      let location = format!(
+        "{}{}{}",
+        pending.callback,
+        separator,
+        form_string(&[("oauth_token", &token), ("oauth_verifier", &verifier)])
+      );
+      HttpResponse::new(302, "").with_header("Location", &location)
+    }
+  }
+
+  fn token(&mut self, request: &HttpRequest) -> HttpResponse {
+    let params = match self.verify_oauth(request, TokenKind::Request) {
+      Ok(params) => params,
+      Err(res) => return res,
+    };
+    let token = &params["oauth_token"];
+    let authorized = self.request_tokens[token].verifier.is_some()
+      && self.request_tokens[token].verifier.as_ref() == params.get("oauth_verifier");
+    if !authorized {
+      return problem(401, "verifier_invalid");
+    }
+
+    let pending = self.request_tokens.remove(token).unwrap();
+    let (oauth_token, oauth_token_secret) = self.issue_access_token(pending.scopes.clone());
+    let body = AccessTokenResponse {
+      oauth_token,
+      oauth_token_secret: oauth_token_secret.into(),
+      url_name: self.url_name.clone(),
+      display_name: self.display_name.clone(),
+      scopes: Some(pending.scopes),
+      extra: Default::default(),
+    }
+    .to_form_string();
+
+    HttpResponse::new(200, body).with_header("Content-Type", "application/x-www-form-urlencoded")
+  }
+
+  fn user_info(&mut self, request: &HttpRequest) -> HttpResponse {
+    if let Err(res) = self.verify_oauth(request, TokenKind::Access) {
+      return res;
+    }
+    let body = serde_json::json!({
+      "url_name": self.url_name,
+      "display_name": self.display_name,
+      "profile_image_url": format!("{}/users/{}/profile.png", self.base_url, self.url_name),
+    });
+
+    HttpResponse::new(200, body.to_string()).with_header("Content-Type", "application/json")
+  }
+
+  fn post_photo(&mut self, request: &HttpRequest) -> HttpResponse {
+    if let Err(res) = self.authenticate_atom(request, true) {
+      return res;
+    }
+    let xml = String::from_utf8_lossy(&request.body);
+    let (attrs, content) = match find_element(&xml, "content") {
+      Some(content) => content,
+      None => return HttpResponse::new(400, "content is required"),
+    };
+    let data = match base64::decode(content.trim()) {
+      Ok(data) => data,
+      Err(_) => return HttpResponse::new(400, "content is not base64"),
+    };
+    let content_type = attribute(attrs, "type").unwrap_or("image/jpeg").to_string();
+    let title = element_text(&xml, "title").unwrap_or_default();
+    let folder = element_text(&xml, "dc:subject").unwrap_or_default();
+
+    let id = self.add_photo(&folder, &title, &content_type, data);
+    let photo = self.photos.iter().find(|photo| photo.id == id).unwrap();
+
+    HttpResponse::new(201, self.entry_xml(photo))
+      .with_header("Content-Type", "application/x.atom+xml")
+      .with_header("Location", &format!("{}/atom/edit/{}", self.base_url, id))
+  }
+
+  fn edit_photo(&mut self, request: &HttpRequest, id: &str) -> HttpResponse {
+    let write = request.method != "GET";
+    if let Err(res) = self.authenticate_atom(request, write) {
+      return res;
+    }
+    let index = match self.photos.iter().position(|photo| photo.id == id) {
+      Some(index) => index,
+      None => return HttpResponse::new(404, "Not Found"),
+    };
+
+    match request.method.as_str() {
+      "GET" => HttpResponse::new(200, self.entry_xml(&self.photos[index]))
+        .with_header("Content-Type", "application/x.atom+xml"),
+      "PUT" => {
+        let xml = String::from_utf8_lossy(&request.body);
+        if let Some(title) = element_text(&xml, "title") {
+          self.photos[index].title = title;
+        }
+        HttpResponse::new(200, "")
+      }
+      "DELETE" => {
+        self.photos.remove(index);
+        HttpResponse::new(200, "")
+      }
+      _ => HttpResponse::new(405, "Method Not Allowed"),
+    }
+  }
+
+  fn feed(&mut self, request: &HttpRequest) -> HttpResponse {
+    if let Err(res) = self.authenticate_atom(request, false) {
+      return res;
+    }
+    let entries = self
+      .photos
+      .iter()
+      .rev()
+      .map(|photo| self.entry_xml(photo))
+      .collect::<String>();
+    let body = format!(
+      r#"<?xml version="1.0" encoding="utf-8"?><feed xmlns="http://purl.org/atom/ns#"><title>{}'s Fotolife</title><link rel="service.post" type="application/x.atom+xml" href="{}/atom/post"/>{}</feed>"#,
+      escape_xml(&self.display_name),
+      self.base_url,
+      entries,
+    );
+
+    HttpResponse::new(200, body).with_header("Content-Type", "application/x.atom+xml")
+  }
+
+  fn my_page(&self, request: &HttpRequest) -> HttpResponse {
+    if let Err(res) = self.authenticate_cookie(request) {
+      return res;
+    }
+    HttpResponse::new(
+      200,
+      format!(
+        r#"<html><body data-user-name="{}"></body></html>"#,
+        escape_xml(&self.url_name)
+      ),
+    )
+    .with_header("Content-Type", "text/html; charset=utf-8")
+  }
+
+  fn photo_list(&self, request: &HttpRequest, folder: &str) -> HttpResponse {
+    if let Err(res) = self.authenticate_cookie(request) {
+      return res;
+    }
+    let photos = self
+      .photos
+      .iter()
+      .filter(|photo| photo.folder == folder)
+      .collect::<Vec<_>>();
+    if photos.is_empty() {
+      return HttpResponse::new(404, "Not Found");
+    }
+    let items = photos
+      .iter()
+      .map(|photo| {
+        format!(
+          r#"<li><a href="/{}/{}"><img class="foto_thumb" src="{}" alt="{}"></a></li>"#,
+          self.url_name,
+          photo.id,
+          self.image_url(photo),
+          escape_xml(&photo.title),
+        )
+      })
+      .collect::<String>();
+
+    HttpResponse::new(
+      200,
+      format!(
+        r#"<html><body><ul class="fotolist">{}</ul></body></html>"#,
+        items
+      ),
+    )
+    .with_header("Content-Type", "text/html; charset=utf-8")
+  }
+
+  /// Verify the OAuth signature of `request`, and return its protocol parameters
+  fn verify_oauth(
+    &mut self,
+    request: &HttpRequest,
+    kind: TokenKind,
+  ) -> Result<HashMap<String, String>, HttpResponse> {
+    let authorization = match request.header("authorization") {
+      Some(authorization) if authorization.starts_with("OAuth ") => authorization,
+      _ => return Err(problem(401, "parameter_absent")),
+    };
+    let mut params = oauth_params(authorization);
+    params.remove("realm");
+    let signature = params
+      .remove("oauth_signature")
+      .ok_or_else(|| problem(401, "parameter_absent"))?;
+
+    let method = params
+      .get("oauth_signature_method")
+      .and_then(|name| {
+        self
+          .signature_methods
+          .iter()
+          .find(|method| method.name() == name && !matches!(method, SignatureMethod::RsaSha1(_)))
+      })
+      .cloned()
+      .ok_or_else(|| problem(400, "signature_method_rejected"))?;
+    if params.get("oauth_consumer_key") != Some(&self.consumer_key) {
+      return Err(problem(401, "consumer_key_unknown"));
+    }
+    let now = self.clock.now();
+    let timestamp = params
+      .get("oauth_timestamp")
+      .and_then(|timestamp| timestamp.parse::<i64>().ok())
+      .ok_or_else(|| problem(400, "parameter_absent"))?;
+    if (timestamp - now).abs() > TIMESTAMP_TOLERANCE {
+      return Err(problem(401, "timestamp_refused").with_header(
+        "WWW-Authenticate",
+        &format!(
+          r#"OAuth oauth_problem="timestamp_refused", oauth_acceptable_timestamps="{}-{}""#,
+          now - TIMESTAMP_TOLERANCE,
+          now + TIMESTAMP_TOLERANCE
+        ),
+      ));
+    }
+    let nonce = (
+      timestamp.to_string(),
+      params.get("oauth_nonce").cloned().unwrap_or_default(),
+    );
+    if self.nonces.contains(&nonce) {
+      return Err(problem(401, "nonce_used"));
+    }
+
+    let token = params.get("oauth_token");
+    let token_secret = match (kind, token) {
+      (TokenKind::None, _) => String::new(),
+      (TokenKind::Request, Some(token)) => match self.request_tokens.get(token) {
+        Some(pending) => pending.secret.clone(),
+        None => return Err(problem(401, "token_rejected")),
+      },
+      (TokenKind::Access, Some(token)) => match self.access_tokens.get(token) {
+        Some(granted) => granted.secret.clone(),
+        None => return Err(problem(401, "token_rejected")),
+      },
+      (_, None) => return Err(problem(401, "parameter_absent")),
+    };
+
+    let mut signed_params = params
+      .iter()
+      .map(|(key, value)| (key.clone(), value.clone()))
+      .collect::<Vec<_>>();
+    signed_params.extend(form_urlencoded::parse(request.query.as_bytes()).into_owned());
+    let is_form = request
+      .header(CONTENT_TYPE.as_str())
+      .is_some_and(|content_type| content_type.starts_with("application/x-www-form-urlencoded"));
+    if is_form {
+      signed_params.extend(form_urlencoded::parse(trim_ascii(&request.body)).into_owned());
+    }
+    let base_string = signature_base_string(
+      &request.method,
+      &format!("{}{}", self.base_url, request.path),
+      &signed_params,
+    );
+    if method.sign(&self.consumer_secret, &token_secret, &base_string) != signature {
+      return Err(problem(401, "signature_invalid"));
+    }
+
+    self.nonces.insert(nonce);
+    Ok(params)
+  }
+
+  /// Authenticate a request to AtomPub by WSSE if `X-WSSE` is given, otherwise by OAuth with scopes
+  fn authenticate_atom(&mut self, request: &HttpRequest, write: bool) -> Result<(), HttpResponse> {
+    if let Some(wsse) = request.header("x-wsse") {
+      return self.verify_wsse(wsse);
+    }
+
+    let params = self.verify_oauth(request, TokenKind::Access)?;
+    let required = if write {
+      [OauthScope::WritePublic, OauthScope::WritePrivate]
+    } else {
+      [OauthScope::ReadPublic, OauthScope::ReadPrivate]
+    };
+    let scopes = &self.access_tokens[&params["oauth_token"]].scopes;
+    if required.iter().any(|scope| scopes.contains(scope)) {
+      Ok(())
+    } else {
+      Err(HttpResponse::new(403, "Insufficient scope"))
+    }
+  }
+
+  fn verify_wsse(&self, wsse: &str) -> Result<(), HttpResponse> {
+    let fields = wsse
+      .trim_start_matches("UsernameToken ")
+      .split(',')
+      .filter_map(|pair| pair.split_once('='))
+      .map(|(key, value)| (key.trim(), value.trim().trim_matches('"')))
+      .collect::<HashMap<_, _>>();
+    let nonce = fields
+      .get("Nonce")
+      .and_then(|nonce| base64::decode(nonce).ok())
+      .unwrap_or_default();
+    let created = fields.get("Created").copied().unwrap_or_default();
+    let digest = password_digest(&nonce, created, &self.api_key);
+
+    if fields.get("Username") == Some(&self.url_name.as_str())
+      && fields.get("PasswordDigest") == Some(&digest.as_str())
+    {
+      Ok(())
+    } else {
+      Err(
+        HttpResponse::new(401, "Unauthorized")
+          .with_header("WWW-Authenticate", r#"WSSE profile="UsernameToken""#),
+      )
+    }
+  }
+
+  /// Redirect to the login page unless the request has the `rk` cookie of the session
+  fn authenticate_cookie(&self, request: &HttpRequest) -> Result<(), HttpResponse> {
+    let logged_in = request.header(COOKIE.as_str()).is_some_and(|cookie| {
+      cookie
+        .split(';')
+        .filter_map(|pair| pair.trim().split_once('='))
+        .any(|(name, value)| name == "rk" && value == self.cookie)
+    });
+
+    if logged_in {
+      Ok(())
+    } else {
+      let location = format!(
+        "{}/login?{}",
+        self.base_url,
+        form_string(&[("location", &format!("{}{}", self.base_url, request.path))])
+      );
+      Err(HttpResponse::new(302, "").with_header(LOCATION.as_str(), &location))
+    }
+  }
+
+  fn next_token(&mut self, prefix: &str) -> String {
+    self.serial += 1;
+    format!("{}_{}", prefix, self.serial)
+  }
+
+  fn issue_access_token(&mut self, scopes: Vec<OauthScope>) -> (String, String) {
+    let token = self.next_token("access");
+    let secret = format!("{}_secret", token);
+    self.access_tokens.insert(
+      token.clone(),
+      GrantedToken {
+        secret: secret.clone(),
+        scopes,
+      },
+    );
+
+    (token, secret)
+  }
+
+  fn add_photo(&mut self, folder: &str, title: &str, content_type: &str, data: Vec<u8>) -> String {
+    let id = (FIRST_PHOTO_ID + self.photo_serial).to_string();
+    self.photo_serial += 1;
+    self.photos.push(MockPhoto {
+      id: id.clone(),
+      title: title.into(),
+      folder: folder.into(),
+      content_type: content_type.into(),
+      data,
+    });
+
+    id
+  }
+
+  fn image_url(&self, photo: &MockPhoto) -> String {
+    let extension = photo
+      .content_type
+      .rsplit('/')
+      .next()
+      .unwrap_or("jpg")
+      .to_string();
+    format!(
+      "{}/images/fotolife/{}/{}.{}",
+      self.base_url, self.url_name, photo.id, extension
+    )
+  }
+
+  fn entry_xml(&self, photo: &MockPhoto) -> String {
+    let issued = NaiveDateTime::parse_from_str(&photo.id, "%Y%m%d%H%M%S")
+      .ok()
+      .map(|issued| Utc.from_utc_datetime(&issued).to_rfc3339())
+      .unwrap_or_default();
+    format!(
+      concat!(
+        r#"<entry xmlns="http://purl.org/atom/ns#">"#,
+        r#"<title>{title}</title>"#,
+        r#"<link rel="service.edit" type="application/x.atom+xml" href="{base}/atom/edit/{id}" title="{title}"/>"#,
+        r#"<issued>{issued}</issued>"#,
+        r#"<author><name>{user}</name></author>"#,
+        r#"<dc:subject xmlns:dc="http://purl.org/dc/elements/1.1/">{folder}</dc:subject>"#,
+        r#"<hatena:imageurl xmlns:hatena="http://www.hatena.ne.jp/info/xmlns#">{image}</hatena:imageurl>"#,
+        r#"</entry>"#,
+      ),
+      title = escape_xml(&photo.title),
+      base = self.base_url,
+      id = photo.id,
+      issued = issued,
+      user = escape_xml(&self.url_name),
+      folder = escape_xml(&photo.folder),
+      image = self.image_url(photo),
+    )
+  }
+}
+
+/// Visit the authorization URL as a browser, and take the verifier from the redirect or the displayed page
+fn visit_authorize_url(request: &AuthorizeRequest) -> Result<String, OauthError> {
+  let client = reqwest::blocking::Client::builder()
+    .no_proxy()
+    .redirect(Policy::none())
+    .build()?;
+  let res = client.get(&request.authorize_url).send()?;
+
+  if res.status().is_redirection() {
+    let location = res
+      .headers()
+      .get(LOCATION)
+      .and_then(|location| location.to_str().ok())
+      .and_then(|location| Url::parse(location).ok());
+    return location
+      .and_then(|location| {
+        location
+          .query_pairs()
+          .find(|(key, _)| key == "oauth_verifier")
+          .map(|(_, value)| value.into_owned())
+      })
+      .ok_or(OauthError::PermissionDeniedUser);
+  }
+
+  let body = res.text()?;
+  let document = Html::parse_document(&body);
+  let selector = Selector::parse("#oauth_verifier").unwrap();
+  document
+    .select(&selector)
+    .next()
+    .map(|element| element.text().collect::<String>().trim().to_string())
+    .ok_or(OauthError::PermissionDeniedUser)
+}
+
+/// Response reporting `oauth_problem` in the body
+fn problem(status: u16, problem: &str) -> HttpResponse {
+  HttpResponse::new(status, format!("oauth_problem={}", problem))
+    .with_header("Content-Type", "application/x-www-form-urlencoded")
+}
+
+fn form_field(form: &[u8], name: &str) -> Option<String> {
+  form_urlencoded::parse(trim_ascii(form))
+    .find(|(key, _)| key == name)
+    .map(|(_, value)| value.into_owned())
+}
+
+fn form_string(fields: &[(&str, &str)]) -> String {
+  form_urlencoded::Serializer::new(String::new())
+    .extend_pairs(fields)
+    .finish()
+}
+
+/// Attributes and inner text of the first element named `name` in `xml`
+fn find_element<'a>(xml: &'a str, name: &str) -> Option<(&'a str, &'a str)> {
+  let open = format!("<{}", name);
+  let mut from = 0;
+  loop {
+    let start = from + xml[from..].find(&open)?;
+    let rest = &xml[start + open.len()..];
+    // Skip elements whose names only start with `name` (eg. `<titles>`)
+    if rest.starts_with(|c: char| c.is_whitespace() || c == '>' || c == '/') {
+      let end = rest.find('>')?;
+      let attrs = &rest[..end];
+      if attrs.ends_with('/') {
+        return Some((attrs.trim_end_matches('/'), ""));
+      }
+      let inner = &rest[end + 1..];
+      let close = inner.find(&format!("</{}>", name))?;
+      return Some((attrs, &inner[..close]));
+    }
+    from = start + open.len();
+  }
+}
+
+fn element_text(xml: &str, name: &str) -> Option<String> {
+  find_element(xml, name).map(|(_, text)| unescape_xml(text.trim()))
+}
+
+fn attribute<'a>(attrs: &'a str, name: &str) -> Option<&'a str> {
+  let key = format!("{}=\"", name);
+  let start = attrs.find(&key)? + key.len();
+  let end = attrs[start..].find('"')?;
+  Some(&attrs[start..start + end])
+}
+
+fn escape_xml(text: &str) -> String {
+  text
+    .replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+}
+
+fn unescape_xml(text: &str) -> String {
+  text
+    .replace("&lt;", "<")
+    .replace("&gt;", ">")
+    .replace("&quot;", "\"")
+    .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::time::Duration;
+
+  use crate::fotolife::Fotolife;
+  use crate::oauth::HatenaOauth;
+  use crate::oauth::RequestToken;
+
+  fn scopes() -> Vec<OauthScope> {
+    vec![OauthScope::ReadPublic, OauthScope::WritePublic]
+  }
+
+  #[test]
+  fn test_oauth_and_fotolife() {
+    let mock = MockHatena::start()
+      .unwrap()
+      .with_user("smallkirby", "kirby");
+    let mut oauth = HatenaOauth::new(
+      scopes(),
+      Some(mock.grant_permission_callback()),
+      mock.consumer_info(),
+    )
+    .unwrap()
+    .with_endpoints(mock.endpoints())
+    .with_browser_launcher(None);
+
+    let token = oauth.get_access_token(true).unwrap();
+    assert_eq!(token.url_name, "smallkirby");
+    assert_eq!(token.scopes, Some(scopes()));
+    assert_eq!(oauth.me(true).unwrap().display_name, "kirby");
+
+    let image = std::env::temp_dir().join(format!("hatena-rs-mock-{}.png", std::process::id()));
+    std::fs::write(&image, b"\x89PNG").unwrap();
+    let mut fotolife = Fotolife::new(oauth);
+    let posted = fotolife.post_image(&image, "test rust", 10).unwrap();
+    std::fs::remove_file(image).unwrap();
+    let photos = mock.photos();
+    assert_eq!(photos[0].id, posted.image_id);
+    assert_eq!(photos[0].title, "test rust");
+    assert_eq!(photos[0].content_type, "image/png");
+    assert_eq!(photos[0].data, b"\x89PNG");
+
+    let listed = mock.add_photo("hatena-rs", "listed", "image/jpeg", b"jpeg");
+    let mut session = mock.cookie_session();
+    assert_eq!(session.validate().unwrap(), "smallkirby");
+    let ids = fotolife
+      .list_images_directory_with("hatena-rs", &session, None)
+      .unwrap();
+    assert_eq!(ids, vec![listed]);
+
+    let expired = HatenaCookieSession::from_rk("expired");
+    assert!(fotolife
+      .list_images_directory_with("hatena-rs", &expired, Some("smallkirby"))
+      .is_err());
+  }
+
+  #[test]
+  fn test_loopback_and_wsse() {
+    let mock = MockHatena::start().unwrap();
+    let mut oauth = HatenaOauth::new(scopes(), None, mock.consumer_info())
+      .unwrap()
+      .with_endpoints(mock.endpoints())
+      .with_browser_launcher(Some(Box::new(|url| {
+        // Follow the redirect to the loopback server as a browser
+        let url = url.to_string();
+        std::thread::spawn(move || {
+          let client = reqwest::blocking::Client::builder()
+            .no_proxy()
+            .build()
+            .unwrap();
+          client.get(url).send().unwrap();
+        });
+        Ok(())
+      })));
+    let token = oauth
+      .get_access_token_loopback(Duration::from_secs(10))
+      .unwrap();
+    assert_eq!(token.url_name, MOCK_USER_NAME);
+
+    let image =
+      std::env::temp_dir().join(format!("hatena-rs-mock-wsse-{}.gif", std::process::id()));
+    std::fs::write(&image, b"GIF89a").unwrap();
+    let mut fotolife = Fotolife::with_wsse(mock.wsse_credentials())
+      .unwrap()
+      .with_endpoints(mock.endpoints());
+    fotolife.post_image(&image, "wsse", 10).unwrap();
+    let mut forged = Fotolife::with_wsse(WsseCredentials::new(MOCK_USER_NAME, "wrong"))
+      .unwrap()
+      .with_endpoints(mock.endpoints());
+    assert!(forged.post_image(&image, "forged", 10).is_err());
+    std::fs::remove_file(image).unwrap();
+    assert_eq!(mock.photos().len(), 1);
+  }
+
+  #[test]
+  fn test_signature_verification() {
+    let mock = MockHatena::start().unwrap();
+    let url = mock.endpoints().oauth_request_token;
+    let client = reqwest::blocking::Client::builder()
+      .no_proxy()
+      .build()
+      .unwrap();
+    let initiate = |signer: &RequestToken| {
+      let params: HashMap<&str, &str> = [("oauth_callback", "oob")].into_iter().collect();
+      let res = client
+        .post(&url)
+        .header(
+          "Authorization",
+          signer.to_header_string(&url, "POST", Some(&params), Some("scope=read_public")),
+        )
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body("scope=read_public")
+        .send()
+        .unwrap();
+      (res.status().as_u16(), res.text().unwrap())
+    };
+
+    let signer = RequestToken::new(MOCK_CONSUMER_KEY, MOCK_CONSUMER_SECRET, None, None);
+    assert_eq!(initiate(&signer).0, 200);
+
+    let wrong_secret = RequestToken::new(MOCK_CONSUMER_KEY, "wrong", None, None);
+    assert_eq!(
+      initiate(&wrong_secret),
+      (401, "oauth_problem=signature_invalid".into())
+    );
+
+    let fixed = signer
+      .with_clock(Arc::new(FixedClock(Utc::now().timestamp())))
+      .with_nonce_source(Arc::new(FixedNonce("nonce".into())));
+    assert_eq!(initiate(&fixed).0, 200);
+    assert_eq!(initiate(&fixed), (401, "oauth_problem=nonce_used".into()));
+
+    let stale = fixed.with_clock(Arc::new(FixedClock(0)));
+    assert_eq!(initiate(&stale).1, "oauth_problem=timestamp_refused");
+
+    let sha256 = RequestToken::new(MOCK_CONSUMER_KEY, MOCK_CONSUMER_SECRET, None, None)
+      .with_signature_method(SignatureMethod::HmacSha256);
+    assert_eq!(
+      initiate(&sha256),
+      (400, "oauth_problem=signature_method_rejected".into())
+    );
+  }
+
+  #[test]
+  fn test_signature_methods() {
+    let mock = MockHatena::start().unwrap().with_signature_methods(vec![
+      SignatureMethod::HmacSha256,
+      SignatureMethod::Plaintext,
+    ]);
+    let url = mock.endpoints().oauth_request_token;
+    let client = reqwest::blocking::Client::builder()
+      .no_proxy()
+      .build()
+      .unwrap();
+    let initiate = |method: SignatureMethod, consumer_secret: &str| {
+      let signer = RequestToken::new(MOCK_CONSUMER_KEY, consumer_secret, None, None)
+        .with_signature_method(method);
+      let params: HashMap<&str, &str> = [("oauth_callback", "oob")].into_iter().collect();
+      client
+        .post(&url)
+        .header(
+          "Authorization",
+          signer.to_header_string(&url, "POST", Some(&params), Some("scope=read_public")),
+        )
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body("scope=read_public")
+        .send()
+        .unwrap()
+        .status()
+        .as_u16()
+    };
+
+    assert_eq!(
+      initiate(SignatureMethod::HmacSha256, MOCK_CONSUMER_SECRET),
+      200
+    );
+    assert_eq!(
+      initiate(SignatureMethod::Plaintext, MOCK_CONSUMER_SECRET),
+      200
+    );
+    assert_eq!(initiate(SignatureMethod::Plaintext, "wrong"), 401);
+    assert_eq!(
+      initiate(SignatureMethod::HmacSha1, MOCK_CONSUMER_SECRET),
+      400
+    );
+  }
+
+  #[test]
+  fn test_user_top_page() {
+    let mock = MockHatena::start().unwrap();
+    mock.add_photo("hatena-rs", "listed", "image/png", b"png");
+    let client = reqwest::blocking::Client::builder()
+      .no_proxy()
+      .build()
+      .unwrap();
+    let get = |path: &str| {
+      client
+        .get(format!("{}{}", mock.base_url(), path))
+        .header("Cookie", format!("rk={}", MOCK_COOKIE))
+        .send()
+        .unwrap()
+        .status()
+        .as_u16()
+    };
+
+    assert_eq!(get(&format!("/{}/", MOCK_USER_NAME)), 404);
+    // The server keeps serving, rather than poisoning its state
+    assert_eq!(get(&format!("/{}/hatena-rs/", MOCK_USER_NAME)), 200);
+  }
+}
@@ -0,0 +1,143 @@
+use std::env;
+use std::sync::Arc;
+
+use chrono::{TimeZone, Utc};
+use ring::digest::{digest, SHA1_FOR_LEGACY_USE_ONLY};
+use thiserror::Error;
+
+use crate::auth::*;
+use crate::oauth::clock::*;
+use crate::oauth::request::HatenaRequest;
+use crate::secret::SecretString;
+
+pub const ENV_HATENA_ID: &str = "HATENA_ID";
+pub const ENV_HATENA_API_KEY: &str = "HATENA_API_KEY";
+
+/// Name of the header carrying a WSSE UsernameToken
+pub const WSSE_HEADER: &str = "X-WSSE";
+
+#[derive(Debug, Error)]
+pub enum WsseError {
+  #[error("HATENA_ID or HATENA_API_KEY is not set")]
+  InsufficientSecret,
+}
+
+/// Credentials for WSSE authentication, which are Hatena ID and API key
+///
+/// API key can be found on the settings page of each service (eg. Hatena Blog).
+#[derive(Clone)]
+pub struct WsseCredentials {
+  /// Hatena ID
+  username: String,
+  /// API key used as a password
+  api_key: SecretString,
+  /// Source of `Created`
+  clock: Arc<dyn Clock>,
+  /// Source of `Nonce`
+  nonce_source: Arc<dyn NonceSource>,
+}
+
+impl WsseCredentials {
+  pub fn new(username: &str, api_key: &str) -> Self {
+    Self {
+      username: username.to_string(),
+      api_key: api_key.into(),
+      clock: Arc::new(SystemClock),
+      nonce_source: Arc::new(RandomNonce),
+    }
+  }
+
+  pub fn from_env() -> Result<Self, WsseError> {
+    let username = env::var(ENV_HATENA_ID).map_err(|_| WsseError::InsufficientSecret)?;
+    let api_key = env::var(ENV_HATENA_API_KEY).map_err(|_| WsseError::InsufficientSecret)?;
+
+    Ok(Self::new(&username, &api_key))
+  }
+
+  /// Use `clock` to generate `Created` instead of the system time
+  pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+    self.clock = clock;
+    self
+  }
+
+  /// Use `nonce_source` to generate `Nonce` instead of random strings
+  pub fn with_nonce_source(mut self, nonce_source: Arc<dyn NonceSource>) -> Self {
+    self.nonce_source = nonce_source;
+    self
+  }
+
+  /// Hatena ID
+  pub fn username(&self) -> &str {
+    &self.username
+  }
+
+  /// Generate a value of `X-WSSE` header with a fresh nonce
+  pub fn header_value(&self) -> String {
+    let nonce = self.nonce_source.nonce();
+    let created = Utc
+      .timestamp_opt(self.clock.now(), 0)
+      .unwrap()
+      .format("%Y-%m-%dT%H:%M:%SZ")
+      .to_string();
+
+    format!(
+      r#"UsernameToken Username="{}", PasswordDigest="{}", Nonce="{}", Created="{}""#,
+      self.username,
+      password_digest(nonce.as_bytes(), &created, self.api_key.expose_secret()),
+      base64::encode(&nonce),
+      created,
+    )
+  }
+}
+
+impl Authenticator for WsseCredentials {
+  /// Set `X-WSSE` header with a fresh nonce
+  fn authenticate(&mut self, request: &mut HatenaRequest) -> Result<(), AuthError> {
+    request.replace_header(WSSE_HEADER, &self.header_value());
+    Ok(())
+  }
+
+  fn username(&mut self) -> Result<Option<String>, AuthError> {
+    Ok(Some(self.username.clone()))
+  }
+}
+
+#[cfg(feature = "async")]
+impl AsyncAuthenticator for WsseCredentials {
+  async fn authenticate(&mut self, request: &mut HatenaRequest) -> Result<(), AuthError> {
+    Authenticator::authenticate(self, request)
+  }
+
+  async fn username(&mut self) -> Result<Option<String>, AuthError> {
+    Authenticator::username(self)
+  }
+}
+
+/// `PasswordDigest` of UsernameToken, which is `Base64(SHA1(nonce + created + password))`
+pub fn password_digest(nonce: &[u8], created: &str, password: &str) -> String {
+  let mut data = nonce.to_vec();
+  data.extend_from_slice(created.as_bytes());
+  data.extend_from_slice(password.as_bytes());
+
+  base64::encode(digest(&SHA1_FOR_LEGACY_USE_ONLY, &data))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_header_value() {
+    // Example of "Atom Authentication" by Mark Pilgrim
+    let credentials = WsseCredentials::new("bob", "taadtaadpstcsm")
+      .with_clock(Arc::new(FixedClock(1071499387)))
+      .with_nonce_source(Arc::new(FixedNonce(
+        "d36e316282959a9ed4c89851497a717f".into(),
+      )));
+
+    assert_eq!(
+      credentials.header_value(),
+      r#"UsernameToken Username="bob", PasswordDigest="quR/EWLAV4xLf9Zqyw4pDmfV9OY=", Nonce="ZDM2ZTMxNjI4Mjk1OWE5ZWQ0Yzg5ODUxNDk3YTcxN2Y=", Created="2003-12-15T14:43:07Z""#
+    );
+  }
+}